@@ -7,8 +7,8 @@ use url::Url;
 
 fn main() {
     let url = Url::parse("ws://echo.websocket.org").unwrap();
-    let mut ws = WebSocket::with_options(url, 13, Some(&["chat", "superchat"][..]), None);
-    ws.connect().unwrap();
+    let ws = WebSocket::with_options(url, 13, Some(&["chat", "superchat"][..]), None);
+    let mut ws = ws.open().map_err(|(_, e)| e).unwrap();
 
     let msg = WSMessage::text("Hello, World!"); //.mask();
 
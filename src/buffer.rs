@@ -0,0 +1,74 @@
+/// Source of buffers for received message payloads.
+///
+/// Buffers come back to the pool via `WebSocket::recycle()`,
+/// so servers handling lots of connections can avoid allocator churn.
+pub trait BufferPool {
+    /// Get empty buffer, its capacity is just a hint,
+    /// `None` if pool is exhausted and won't allocate more
+    fn get(&mut self) -> Option<Vec<u8>>;
+
+    /// Return no longer needed buffer back to the pool
+    fn put(&mut self, buf: Vec<u8>);
+}
+
+/// Default pool, just allocates new buffers on heap and drops returned ones
+#[derive(Debug, Clone, Copy)]
+pub struct HeapPool;
+
+impl BufferPool for HeapPool {
+    #[inline] fn get(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    #[inline] fn put(&mut self, _: Vec<u8>) {
+    }
+}
+
+/// Hands out `count` buffers of `size` bytes capacity allocated beforehand and never allocates more,
+/// buffers grown larger than that are dropped when returned
+#[derive(Debug)]
+pub struct FixedPool {
+    size: usize,
+    count: usize,
+    free: Vec<Vec<u8>>
+}
+
+impl FixedPool {
+    pub fn new(size: usize, count: usize) -> FixedPool {
+        FixedPool {
+            size: size,
+            count: count,
+            free: (0..count).map(|_| Vec::with_capacity(size)).collect()
+        }
+    }
+}
+
+impl BufferPool for FixedPool {
+    fn get(&mut self) -> Option<Vec<u8>> {
+        self.free.pop()
+    }
+
+    fn put(&mut self, mut buf: Vec<u8>) {
+        if self.free.len() < self.count && buf.capacity() <= self.size {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, FixedPool};
+
+    #[test]
+    fn fixed_pool_does_not_allocate() {
+        let mut pool = FixedPool::new(16, 1);
+        let buf = pool.get().unwrap();
+        assert_eq!(buf.capacity(), 16);
+        assert!(pool.get().is_none());
+
+        pool.put(buf);
+        assert!(pool.get().is_some());
+    }
+}
@@ -0,0 +1,166 @@
+//! Threaded mode: connection is driven by a background thread, and messages are exchanged
+//! with it over channels.
+//!
+//! Queue of received messages is bounded: when it's full, the thread stops reading
+//! from the socket until there's room again, so that unread data fills TCP buffers
+//! and server is slowed down by TCP flow control instead of messages piling up in memory.
+//! Pings are not answered meanwhile, as they are not read either.
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc::{channel, sync_channel, Sender, Receiver, SyncSender, TryRecvError, TrySendError, RecvTimeoutError};
+
+use connection::Connection;
+use message::WSMessage;
+use stream::Stream;
+use error::{WSError, WSResult};
+
+// How long the thread waits for incoming data before checking outgoing messages
+const POLL_INTERVAL_MS: u64 = 10;
+
+// How long to wait for server to confirm closing when channel is dropped
+const CLOSE_TIMEOUT_MS: u64 = 1000;
+
+fn stopped() -> WSError {
+    WSError::Io(io::Error::new(io::ErrorKind::NotConnected, "connection thread has stopped"))
+}
+
+/// Connection running in a background thread
+pub struct Channel {
+    outgoing: Sender<WSMessage>,
+    incoming: Receiver<WSResult<WSMessage>>
+}
+
+impl Channel {
+    /// Run connection made by `connect` in a background thread, keeping at most `capacity`
+    /// received messages not yet taken by `recv()`. Connection is made in the thread itself,
+    /// and its error is the first thing `recv()` returns. Dropping channel closes connection
+    pub fn spawn<S, F>(capacity: usize, connect: F) -> Channel where S: Stream, F: FnOnce() -> WSResult<Connection<S>> + Send + 'static {
+        let (outgoing, outgoing_rx) = channel();
+        let (incoming_tx, incoming) = sync_channel(capacity);
+
+        thread::spawn(move || {
+            let conn = match connect() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let _ = incoming_tx.send(Err(e));
+                    return;
+                }
+            };
+            run(conn, outgoing_rx, incoming_tx);
+        });
+
+        Channel { outgoing: outgoing, incoming: incoming }
+    }
+
+    /// Queue message to be sent by the connection thread
+    pub fn send(&self, msg: WSMessage) -> WSResult<()> {
+        self.outgoing.send(msg).map_err(|_| stopped())
+    }
+
+    /// Take next received message, blocking until there is one, connection
+    /// errors are returned once, after all messages received before them
+    pub fn recv(&self) -> WSResult<WSMessage> {
+        self.incoming.recv().map_err(|_| stopped())?
+    }
+
+    /// Take next received message, if it arrives within `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> WSResult<Option<WSMessage>> {
+        match self.incoming.recv_timeout(timeout) {
+            Ok(result) => result.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(stopped())
+        }
+    }
+}
+
+// Connection thread loop: send whatever is queued, then read one message,
+// but only if there's room for it in the incoming queue
+fn run<S: Stream>(mut conn: Connection<S>, outgoing: Receiver<WSMessage>, incoming: SyncSender<WSResult<WSMessage>>) {
+    let poll = Duration::from_millis(POLL_INTERVAL_MS);
+    if let Err(e) = conn.set_read_timeout(Some(poll)) {
+        let _ = incoming.send(Err(WSError::Io(e)));
+        return;
+    }
+
+    let mut received: Option<WSMessage> = None;
+    loop {
+        loop {
+            match outgoing.try_recv() {
+                Ok(msg) => if let Err(e) = conn.send_message(&msg) {
+                    let _ = incoming.send(Err(e));
+                    return;
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = conn.close(Duration::from_millis(CLOSE_TIMEOUT_MS));
+                    return;
+                }
+            }
+        }
+
+        if let Some(msg) = received.take() {
+            match incoming.try_send(Ok(msg)) {
+                Ok(()) => (),
+                Err(TrySendError::Full(Ok(msg))) => {
+                    // Queue is full, leave data in socket till there's room
+                    received = Some(msg);
+                    thread::sleep(poll);
+                    continue;
+                },
+                Err(_) => return
+            }
+        }
+
+        match conn.read_message() {
+            Ok(msg) => received = Some(msg),
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => {
+                let _ = incoming.send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use url::Url;
+
+    use mock;
+    use socket::{WebSocket, Direction};
+    use super::Channel;
+
+    #[test]
+    fn reading_stops_while_queue_is_full() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let chan = Channel::spawn(2, move || {
+            let (stream, server) = mock::pair();
+            for i in 0..10 {
+                server.push(&*mock::frame(0x81, i.to_string().as_bytes()));
+            }
+
+            let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+            ws.set_progress_callback(Some(move |dir, _, _| if let Direction::Received = dir {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }));
+            ws.open_over(stream).map_err(|(_, e)| e)
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(read.load(Ordering::SeqCst), 3);
+
+        for i in 0..10 {
+            assert_eq!(chan.recv().unwrap().as_text().unwrap(), i.to_string());
+        }
+        assert_eq!(read.load(Ordering::SeqCst), 10);
+        assert!(chan.recv_timeout(Duration::from_millis(20)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,61 @@
+//! Source of time for throttling, queued messages time to live, reconnection delays,
+//! idle timeout and other time-dependent logic, so that it can be tested without actually waiting.
+//!
+//! Timeouts enforced by OS on socket reads and writes (handshake and read timeouts)
+//! are not affected, idle peer is still waited for in real time between clock checks.
+
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// Real time
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline] fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline] fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Manually driven time, sleeping advances it instantly,
+/// clones share the same time, so one can be kept to control the other
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    #[inline] fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    #[inline] fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
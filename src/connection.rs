@@ -0,0 +1,293 @@
+//! Connected socket as a separate type: it can only be obtained with successful handshake,
+//! so reading from or sending to unconnected socket is caught at compile time.
+//!
+//! Message I/O is only available here, `WebSocket` itself is the configuration
+//! (and state kept across reconnects) of a connection.
+
+use std::time::Duration;
+use std::net::SocketAddr;
+use std::io::{self, Read};
+use std::fs::File;
+
+use socket::{WebSocket, WSMessages, MessageWriter, RawIo};
+use message::{WSMessage, WSMessageRef, WSSharedMessage, WSStatusCode, WSHeader};
+use stream::{Stream, NetworkStream, TcpInfo};
+use error::{WSError, WSResult};
+use wake::WakeHandle;
+
+pub struct Connection<S = NetworkStream> {
+    ws: WebSocket<S>
+}
+
+impl<S: Stream> WebSocket<S> {
+    /// Connect and turn configured socket into `Connection`,
+    /// socket is given back along with error if connection fails
+    #[allow(clippy::result_large_err)]
+    pub fn open(mut self) -> Result<Connection<S>, (WebSocket<S>, WSError)> {
+        match self.connect() {
+            Ok(()) => Ok(Connection { ws: self }),
+            Err(e) => Err((self, e))
+        }
+    }
+
+    /// Upgrade connection over given transport, see `connect_over()`, and turn socket into `Connection`
+    #[allow(clippy::result_large_err)]
+    pub fn open_over(mut self, stream: S) -> Result<Connection<S>, (WebSocket<S>, WSError)> {
+        match self.connect_over(stream) {
+            Ok(()) => Ok(Connection { ws: self }),
+            Err(e) => Err((self, e))
+        }
+    }
+}
+
+impl<S: Stream> Connection<S> {
+    /// Configuration and state of the underlying socket
+    #[inline] pub fn get_ref(&self) -> &WebSocket<S> {
+        &self.ws
+    }
+
+    #[inline] pub fn id(&self) -> usize {
+        self.ws.id()
+    }
+
+    #[inline] pub fn read_message(&mut self) -> WSResult<WSMessage> {
+        self.ws.read_message()
+    }
+
+    #[inline] pub fn read_frame(&mut self) -> WSResult<WSMessage> {
+        self.ws.read_frame()
+    }
+
+    #[inline] pub fn read_available(&mut self) -> WSResult<Vec<WSMessage>> {
+        self.ws.read_available()
+    }
+
+    #[inline] pub fn read_message_ref(&mut self) -> WSResult<WSMessageRef<'_>> {
+        self.ws.read_message_ref()
+    }
+
+    /// Iterator over incoming messages, append `.defrag()` to get fragmented ones reassembled
+    #[inline] pub fn iter(&mut self) -> WSMessages<'_, S> {
+        self.ws.iter()
+    }
+
+    #[inline] pub fn send_message(&mut self, msg: &WSMessage) -> WSResult<()> {
+        self.ws.send_message(msg)
+    }
+
+    /// Send message skipping compression, e.g. for images or other already compressed data
+    #[inline] pub fn send_uncompressed(&mut self, msg: &WSMessage) -> WSResult<()> {
+        self.ws.send_uncompressed(msg)
+    }
+
+    #[inline] pub fn send_shared(&mut self, msg: &WSSharedMessage) -> WSResult<()> {
+        self.ws.send_shared(msg)
+    }
+
+    #[inline] pub fn send_raw_frame(&mut self, frame: &[u8]) -> WSResult<()> {
+        self.ws.send_raw_frame(frame)
+    }
+
+    #[inline] pub fn send_text(&mut self, text: &str) -> WSResult<()> {
+        self.ws.send_text(text)
+    }
+
+    #[inline] pub fn send_binary(&mut self, data: &[u8]) -> WSResult<()> {
+        self.ws.send_binary(data)
+    }
+
+    #[inline] pub fn send_data(&mut self, header: WSHeader, status: Option<WSStatusCode>, data: &[u8]) -> WSResult<()> {
+        self.ws.send_data(header, status, data)
+    }
+
+    #[inline] pub fn write_frame(&mut self, frame: &WSMessage) -> WSResult<()> {
+        self.ws.write_frame(frame)
+    }
+
+    #[inline] pub fn message_writer(&mut self, header: WSHeader, chunk_size: usize) -> MessageWriter<'_, S> {
+        self.ws.message_writer(header, chunk_size)
+    }
+
+    #[inline] pub fn send_stream<R: Read>(&mut self, header: WSHeader, reader: &mut R, chunk_size: usize) -> WSResult<u64> {
+        self.ws.send_stream(header, reader, chunk_size)
+    }
+
+    #[inline] pub fn raw_io(&mut self) -> RawIo<'_, S> {
+        self.ws.raw_io()
+    }
+
+    #[inline] pub fn flush(&mut self) -> io::Result<()> {
+        self.ws.flush()
+    }
+
+    /// Give received message's buffer back to the pool, see `WebSocket::set_fixed_buffers()`
+    #[inline] pub fn recycle(&mut self, msg: WSMessage) {
+        self.ws.recycle(msg)
+    }
+
+    #[inline] pub fn queue_message(&mut self, msg: WSMessage, priority: u8) -> WSResult<()> {
+        self.ws.queue_message(msg, priority)
+    }
+
+    #[inline] pub fn queue_message_ttl(&mut self, msg: WSMessage, priority: u8, ttl: Duration) -> WSResult<()> {
+        self.ws.queue_message_ttl(msg, priority, ttl)
+    }
+
+    #[inline] pub fn queued(&self) -> usize {
+        self.ws.queued()
+    }
+
+    #[inline] pub fn send_queued(&mut self) -> WSResult<bool> {
+        self.ws.send_queued()
+    }
+
+    #[inline] pub fn flush_queue(&mut self) -> WSResult<()> {
+        self.ws.flush_queue()
+    }
+
+    /// Half-close connection, see `WebSocket::close_send()`
+    #[inline] pub fn close_send(&mut self, status: WSStatusCode, reason: &[u8]) -> WSResult<()> {
+        self.ws.close_send(status, reason)
+    }
+
+    #[inline] pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.ws.set_read_timeout(timeout)
+    }
+
+    #[inline] pub fn read_timeout(&self) -> Option<Duration> {
+        self.ws.read_timeout()
+    }
+
+    #[inline] pub fn set_idle_timeout(&mut self, timeout: Option<Duration>, ping: bool) {
+        self.ws.set_idle_timeout(timeout, ping)
+    }
+
+    #[inline] pub fn wake_handle(&mut self) -> WakeHandle {
+        self.ws.wake_handle()
+    }
+
+    #[inline] pub fn pause(&mut self) {
+        self.ws.pause()
+    }
+
+    #[inline] pub fn resume(&mut self) {
+        self.ws.resume()
+    }
+
+    #[inline] pub fn negotiated_protocol(&self) -> Option<&str> {
+        self.ws.negotiated_protocol()
+    }
+
+    #[inline] pub fn negotiated_extensions(&self) -> &[String] {
+        self.ws.negotiated_extensions()
+    }
+
+    /// Close connection gracefully (see `WebSocket::shutdown()`),
+    /// and get unconnected socket back, e.g. to open it again later
+    pub fn close(mut self, timeout: Duration) -> (WebSocket<S>, WSResult<()>) {
+        let result = self.ws.shutdown(timeout);
+        (self.ws, result)
+    }
+
+    /// Get underlying socket back without closing connection, e.g. to pass it to protocol helpers
+    #[inline] pub fn into_inner(self) -> WebSocket<S> {
+        self.ws
+    }
+}
+
+impl Connection {
+    #[inline] pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.ws.peer_addr()
+    }
+
+    #[inline] pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.ws.local_addr()
+    }
+
+    #[inline] pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        self.ws.tcp_info()
+    }
+
+    #[inline] pub fn send_file(&mut self, header: WSHeader, file: &mut File) -> WSResult<u64> {
+        self.ws.send_file(header, file)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use url::Url;
+
+    use mock;
+    use socket::WebSocket;
+    use message::{WSMessage, WSStatusCode};
+    use error::WSError;
+
+    #[test]
+    fn connection_is_opened_over_transport() {
+        let (stream, server) = mock::pair();
+        let ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        let mut conn = ws.open_over(stream).ok().unwrap();
+
+        server.push(&*mock::frame(0x81, b"hi"));
+        assert_eq!(conn.read_message().unwrap().as_text().unwrap(), "hi");
+        conn.send_text("hey").unwrap();
+        assert_eq!(&server.written()[..2], &[0x81, 0x83]);
+    }
+
+    #[test]
+    fn socket_features_are_usable_after_open() {
+        let (stream, server) = mock::pair();
+        let ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        let mut conn = ws.open_over(stream).ok().unwrap();
+
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(conn.read_timeout(), Some(Duration::from_secs(5)));
+        conn.set_idle_timeout(Some(Duration::from_secs(60)), true);
+        let waker = conn.wake_handle();
+
+        server.push(&*mock::frame(0x81, b"hi"));
+        let msg = conn.read_message().unwrap();
+        conn.recycle(msg);
+
+        waker.wake();
+        match conn.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::Interrupted => (),
+            other => panic!("expected wake up, got {:?}", other)
+        }
+
+        conn.queue_message(WSMessage::text("a").mask(), 0).unwrap();
+        conn.queue_message_ttl(WSMessage::text("b").mask(), 0, Duration::from_secs(60)).unwrap();
+        conn.queue_message(WSMessage::text("c").mask(), 0).unwrap();
+        assert_eq!(conn.queued(), 3);
+        assert!(conn.send_queued().unwrap());
+        conn.flush_queue().unwrap();
+        assert_eq!(conn.queued(), 0);
+        conn.flush().unwrap();
+
+        conn.close_send(WSStatusCode::NoError, b"").unwrap();
+        let written = server.written();
+        // Three masked single byte text frames, 7 bytes each, then masked close frame
+        assert_eq!(written.len(), 3 * 7 + 8);
+        assert_eq!((written[0], written[7], written[14], written[21]), (0x81, 0x81, 0x81, 0x88));
+    }
+
+    #[test]
+    fn fixed_buffers_are_reused_after_recycle() {
+        let (stream, server) = mock::pair();
+        let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        ws.set_fixed_buffers(16, 2);
+        let mut conn = ws.open_over(stream).ok().unwrap();
+
+        for i in 0..5u8 {
+            server.push(&*mock::frame(0x82, &[i; 4]));
+        }
+        for i in 0..5u8 {
+            let msg = conn.read_message().unwrap();
+            assert_eq!(&*msg.data, &[i; 4]);
+            conn.recycle(msg);
+        }
+    }
+}
@@ -0,0 +1,781 @@
+//! Deflate compression extensions on top of system zlib: permessage-deflate (RFC 7692),
+//! and its predecessor x-webkit-deflate-frame, which compresses every data frame on its own,
+//! as negotiated by older Safari era servers.
+
+use std::io;
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::os::raw::{c_int, c_uint};
+
+use libz_sys as z;
+
+use extension::{Extension, Param};
+use message::{WSHeader, WS_RSV1, WS_FIN, WS_OPCODE, WS_OPCONT};
+use error::{WSError, WSResult};
+
+// Data compressed with sync flush ends with this empty stored block,
+// it's stripped before sending and added back before decompression
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Largest LZ77 window, in bits
+const MAX_WINDOW_BITS: u8 = 15;
+
+// zlib refuses 256 bytes window for raw deflate streams
+const MIN_WINDOW_BITS: u8 = 9;
+
+// Compression and memory levels zlib uses by default
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MEM_LEVEL: u8 = 8;
+
+unsafe extern "C" fn zalloc(_opaque: z::voidpf, items: z::uInt, size: z::uInt) -> z::voidpf {
+    libc::calloc(items as usize, size as usize)
+}
+
+unsafe extern "C" fn zfree(_opaque: z::voidpf, address: z::voidpf) {
+    libc::free(address)
+}
+
+// zlib keeps pointer to the stream in its state, so the stream is boxed to never move
+fn new_stream() -> Box<z::z_stream> {
+    Box::new(z::z_stream {
+        next_in: ptr::null_mut(),
+        avail_in: 0,
+        total_in: 0,
+        next_out: ptr::null_mut(),
+        avail_out: 0,
+        total_out: 0,
+        msg: ptr::null_mut(),
+        state: ptr::null_mut(),
+        zalloc: zalloc,
+        zfree: zfree,
+        opaque: ptr::null_mut(),
+        data_type: 0,
+        adler: 0,
+        reserved: 0
+    })
+}
+
+// Run zlib over `input` with sync flush, appending output to `out`,
+// fails with `error` if zlib does, or if `out` grows larger than `limit`
+fn run<F>(strm: &mut z::z_stream, input: &[u8], out: &mut Vec<u8>, limit: Option<usize>, error: &'static str, mut step: F) -> WSResult<()>
+    where F: FnMut(&mut z::z_stream) -> c_int {
+    for chunk in input.chunks(c_uint::MAX as usize) {
+        strm.next_in = chunk.as_ptr() as *mut u8;
+        strm.avail_in = chunk.len() as c_uint;
+
+        loop {
+            out.reserve(cmp::max(strm.avail_in as usize, 64));
+            let spare = cmp::min(out.capacity() - out.len(), c_uint::MAX as usize);
+            // Safe: there are `spare` bytes of reserved capacity after the end of `out`
+            strm.next_out = unsafe { out.as_mut_ptr().add(out.len()) };
+            strm.avail_out = spare as c_uint;
+
+            let ret = step(strm);
+            let written = spare - strm.avail_out as usize;
+            // Safe: zlib has initialized `written` bytes of reserved capacity
+            unsafe { out.set_len(out.len() + written) };
+
+            match ret {
+                z::Z_OK | z::Z_BUF_ERROR | z::Z_STREAM_END => (),
+                _ => {
+                    strm.next_in = ptr::null_mut();
+                    return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidData, error)));
+                }
+            }
+            if let Some(limit) = limit {
+                if out.len() > limit {
+                    strm.next_in = ptr::null_mut();
+                    return Err(WSError::TooLarge { size: out.len() as u64, limit: limit });
+                }
+            }
+
+            // Nothing more to do if output space is left, or if the stream has ended
+            if ret == z::Z_STREAM_END || (strm.avail_in == 0 && strm.avail_out != 0) {
+                break;
+            }
+        }
+    }
+
+    strm.next_in = ptr::null_mut();
+    strm.next_out = ptr::null_mut();
+    Ok(())
+}
+
+/// Raw deflate compressor, keeps its context (LZ77 window) between calls
+struct Deflater {
+    strm: Box<z::z_stream>
+}
+
+// Safe: stream is owned exclusively and never shared
+unsafe impl Send for Deflater {}
+
+impl Deflater {
+    fn new(level: u32, window_bits: u8, mem_level: u8) -> WSResult<Deflater> {
+        let mut strm = new_stream();
+        let ret = unsafe {
+            z::deflateInit2_(&mut *strm, level as c_int, z::Z_DEFLATED, -(window_bits as c_int), mem_level as c_int,
+                             z::Z_DEFAULT_STRATEGY, z::zlibVersion(), mem::size_of::<z::z_stream>() as c_int)
+        };
+        match ret {
+            z::Z_OK => Ok(Deflater { strm: strm }),
+            _ => Err(WSError::InvalidInput("invalid deflate parameters"))
+        }
+    }
+
+    /// Compress `input` appending it to `out`, everything is flushed and ends with `TAIL`
+    fn compress(&mut self, input: &[u8], out: &mut Vec<u8>) -> WSResult<()> {
+        run(&mut *self.strm, input, out, None, "compression error", |strm| unsafe { z::deflate(strm, z::Z_SYNC_FLUSH) })
+    }
+
+    /// Forget context, so that next data is compressed on its own
+    fn reset(&mut self) {
+        unsafe { z::deflateReset(&mut *self.strm) };
+    }
+}
+
+impl Drop for Deflater {
+    fn drop(&mut self) {
+        unsafe { z::deflateEnd(&mut *self.strm) };
+    }
+}
+
+/// Raw deflate decompressor, keeps its context (LZ77 window) between calls
+struct Inflater {
+    strm: Box<z::z_stream>
+}
+
+// Safe: stream is owned exclusively and never shared
+unsafe impl Send for Inflater {}
+
+impl Inflater {
+    /// Decompressor for data compressed with window of at most 2^`window_bits` bytes
+    fn new(window_bits: u8) -> WSResult<Inflater> {
+        let mut strm = new_stream();
+        let ret = unsafe {
+            z::inflateInit2_(&mut *strm, -(window_bits as c_int), z::zlibVersion(), mem::size_of::<z::z_stream>() as c_int)
+        };
+        match ret {
+            z::Z_OK => Ok(Inflater { strm: strm }),
+            _ => Err(WSError::Io(io::Error::other("decompressor initialization error")))
+        }
+    }
+
+    /// Decompress `input` appending it to `out`, which may not grow larger than `limit`
+    fn decompress(&mut self, input: &[u8], out: &mut Vec<u8>, limit: Option<usize>) -> WSResult<()> {
+        let mut ended = false;
+        let result = run(&mut *self.strm, input, out, limit, "invalid compressed data", |strm| {
+            let ret = unsafe { z::inflate(strm, z::Z_SYNC_FLUSH) };
+            ended |= ret == z::Z_STREAM_END;
+            ret
+        });
+
+        // Peer has finished deflate stream, the next data starts a new one
+        if ended {
+            self.reset();
+        }
+        result
+    }
+
+    fn reset(&mut self) {
+        unsafe { z::inflateReset(&mut *self.strm) };
+    }
+}
+
+impl Drop for Inflater {
+    fn drop(&mut self) {
+        unsafe { z::inflateEnd(&mut *self.strm) };
+    }
+}
+
+// Parse window bits parameter value, which is at least `min`
+fn window_bits(value: Option<&str>, min: u8) -> WSResult<u8> {
+    match value.and_then(|v| v.parse::<u8>().ok()) {
+        Some(bits) if (min..=MAX_WINDOW_BITS).contains(&bits) => Ok(bits),
+        _ => Err(invalid_params("unsupported deflate window bits"))
+    }
+}
+
+// Compress data frame payload in place, stripping sync flush tail if it's the last
+// piece of compressed data
+fn compress_in_place(deflater: &mut Deflater, data: &mut Vec<u8>, last: bool) -> WSResult<()> {
+    let input = mem::take(data);
+    deflater.compress(&*input, data)?;
+    if last {
+        if data.ends_with(&TAIL) {
+            let len = data.len() - TAIL.len();
+            data.truncate(len);
+        }
+        // Empty stored block header, if there's nothing left, as the tail alone can't be decompressed
+        if data.is_empty() {
+            data.push(0);
+        }
+    }
+    Ok(())
+}
+
+// Decompress data frame payload in place, keeping its buffer,
+// stripped sync flush tail is added back to the last piece of compressed data
+fn decompress_in_place(inflater: &mut Inflater, data: &mut Vec<u8>, last: bool, limit: Option<usize>) -> WSResult<()> {
+    let mut input = Vec::with_capacity(data.len() + TAIL.len());
+    input.extend_from_slice(&**data);
+    if last {
+        input.extend_from_slice(&TAIL);
+    }
+    data.clear();
+    inflater.decompress(&*input, data, limit)
+}
+
+fn invalid_params(desc: &'static str) -> WSError {
+    WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, desc))
+}
+
+/// x-webkit-deflate-frame extension: every data frame is compressed on its own
+/// and marked with RSV1, compression context is kept between frames unless server
+/// asks for `no_context_takeover`
+pub struct DeflateFrame {
+    level: u32,
+    mem_level: u8,
+    min_size: usize,
+    window_bits: u8,
+    no_context_takeover: bool,
+    deflater: Option<Deflater>,
+    inflater: Option<Inflater>
+}
+
+impl DeflateFrame {
+    pub fn new() -> DeflateFrame {
+        DeflateFrame { level: DEFAULT_LEVEL, mem_level: DEFAULT_MEM_LEVEL, min_size: 0, window_bits: MAX_WINDOW_BITS, no_context_takeover: false, deflater: None, inflater: None }
+    }
+
+    /// Send frames smaller than `size` bytes uncompressed, as compressing them isn't worth it
+    pub fn compress_min_size(mut self, size: usize) -> DeflateFrame {
+        self.min_size = size;
+        self
+    }
+
+    /// Compression level from 0 (no compression) to 9 (best and slowest), default is 6
+    pub fn level(mut self, level: u32) -> DeflateFrame {
+        self.level = cmp::min(level, 9);
+        self
+    }
+
+    /// zlib memory level from 1 (least memory, slower) to 9 (fastest), default is 8
+    pub fn mem_level(mut self, mem_level: u8) -> DeflateFrame {
+        self.mem_level = mem_level.clamp(1, 9);
+        self
+    }
+}
+
+impl Default for DeflateFrame {
+    #[inline] fn default() -> DeflateFrame {
+        DeflateFrame::new()
+    }
+}
+
+impl Extension for DeflateFrame {
+    fn name(&self) -> &str {
+        "x-webkit-deflate-frame"
+    }
+
+    fn offer(&self) -> String {
+        self.name().to_string()
+    }
+
+    fn accept(&mut self, params: &[Param]) -> WSResult<()> {
+        for &(ref name, ref value) in params.iter() {
+            match &**name {
+                "max_window_bits" => self.window_bits = window_bits(value.as_ref().map(|v| &**v), MIN_WINDOW_BITS)?,
+                "no_context_takeover" if value.is_none() => self.no_context_takeover = true,
+                _ => return Err(invalid_params("invalid x-webkit-deflate-frame parameters"))
+            }
+        }
+
+        self.deflater = Some(Deflater::new(self.level, self.window_bits, self.mem_level)?);
+        self.inflater = Some(Inflater::new(MAX_WINDOW_BITS)?);
+        Ok(())
+    }
+
+    fn rsv(&self) -> WSHeader {
+        WS_RSV1
+    }
+
+    fn encode(&mut self, header: &mut WSHeader, data: &mut Vec<u8>, compress: bool) -> WSResult<()> {
+        let deflater = match self.deflater {
+            Some(ref mut deflater) if compress && data.len() >= self.min_size => deflater,
+            _ => return Ok(())
+        };
+
+        compress_in_place(deflater, data, true)?;
+        if self.no_context_takeover {
+            deflater.reset();
+        }
+        header.insert(WS_RSV1);
+        Ok(())
+    }
+
+    fn decode(&mut self, header: &mut WSHeader, data: &mut Vec<u8>, limit: Option<usize>) -> WSResult<()> {
+        // Frames may be sent uncompressed
+        let inflater = match self.inflater {
+            Some(ref mut inflater) if header.contains(WS_RSV1) => inflater,
+            _ => return Ok(())
+        };
+
+        decompress_in_place(inflater, data, true, limit)?;
+        header.remove(WS_RSV1);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = DeflateFrame { level: self.level, mem_level: self.mem_level, min_size: self.min_size, ..DeflateFrame::new() };
+    }
+}
+
+/// permessage-deflate extension (RFC 7692): data messages are compressed as a whole,
+/// their first frames are marked with RSV1. Server may ask to compress with smaller
+/// LZ77 window (256 bytes window is not supported by zlib, though, and fails handshake),
+/// or not to keep compression context between messages
+pub struct PerMessageDeflate {
+    level: u32,
+    mem_level: u8,
+    min_size: usize,
+    // Local limits, server is told about them in the offer
+    max_window_bits: u8,
+    no_context_takeover: bool,
+    server_max_window_bits: Option<u8>,
+    server_no_context_takeover: bool,
+    // Negotiated parameters
+    negotiated: bool,
+    window_bits: u8,
+    server_window_bits: u8,
+    keep_deflater: bool,
+    keep_inflater: bool,
+    deflater: Option<Deflater>,
+    inflater: Option<Inflater>,
+    // Message being sent and received is compressed
+    deflating: bool,
+    inflating: bool
+}
+
+impl PerMessageDeflate {
+    pub fn new() -> PerMessageDeflate {
+        PerMessageDeflate {
+            level: DEFAULT_LEVEL,
+            mem_level: DEFAULT_MEM_LEVEL,
+            min_size: 0,
+            max_window_bits: MAX_WINDOW_BITS,
+            no_context_takeover: false,
+            server_max_window_bits: None,
+            server_no_context_takeover: false,
+            negotiated: false,
+            window_bits: MAX_WINDOW_BITS,
+            server_window_bits: MAX_WINDOW_BITS,
+            keep_deflater: true,
+            keep_inflater: true,
+            deflater: None,
+            inflater: None,
+            deflating: false,
+            inflating: false
+        }
+    }
+
+    /// Compression level from 0 (no compression) to 9 (best and slowest), default is 6
+    pub fn level(mut self, level: u32) -> PerMessageDeflate {
+        self.level = cmp::min(level, 9);
+        self
+    }
+
+    /// zlib memory level from 1 (least memory, slower) to 9 (fastest), default is 8
+    pub fn mem_level(mut self, mem_level: u8) -> PerMessageDeflate {
+        self.mem_level = mem_level.clamp(1, 9);
+        self
+    }
+
+    /// Send messages smaller than `size` bytes uncompressed, as compressing them isn't worth it,
+    /// fragmented messages are always compressed, as their size is not known beforehand
+    pub fn compress_min_size(mut self, size: usize) -> PerMessageDeflate {
+        self.min_size = size;
+        self
+    }
+
+    /// Compress with LZ77 window of at most 2^`bits` bytes (from 9 to 15), even if server allows more
+    pub fn max_window_bits(mut self, bits: u8) -> PerMessageDeflate {
+        self.max_window_bits = bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS);
+        self
+    }
+
+    /// Don't keep compression context between messages, even if server allows it:
+    /// compressor is freed after every message, trading compression ratio for memory
+    pub fn no_context_takeover(mut self) -> PerMessageDeflate {
+        self.no_context_takeover = true;
+        self
+    }
+
+    /// Ask server to compress with LZ77 window of at most 2^`bits` bytes (from 8 to 15),
+    /// so that decompressor needs less memory
+    pub fn server_max_window_bits(mut self, bits: u8) -> PerMessageDeflate {
+        self.server_max_window_bits = Some(bits.clamp(8, MAX_WINDOW_BITS));
+        self
+    }
+
+    /// Ask server not to keep compression context between messages,
+    /// so that decompressor is freed after every message
+    pub fn server_no_context_takeover(mut self) -> PerMessageDeflate {
+        self.server_no_context_takeover = true;
+        self
+    }
+
+    fn deflater(&mut self) -> WSResult<&mut Deflater> {
+        if self.deflater.is_none() {
+            self.deflater = Some(Deflater::new(self.level, self.window_bits, self.mem_level)?);
+        }
+        Ok(self.deflater.as_mut().unwrap())
+    }
+
+    fn inflater(&mut self) -> WSResult<&mut Inflater> {
+        if self.inflater.is_none() {
+            self.inflater = Some(Inflater::new(self.server_window_bits)?);
+        }
+        Ok(self.inflater.as_mut().unwrap())
+    }
+}
+
+impl Default for PerMessageDeflate {
+    #[inline] fn default() -> PerMessageDeflate {
+        PerMessageDeflate::new()
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        "permessage-deflate"
+    }
+
+    fn offer(&self) -> String {
+        let mut offer = self.name().to_string();
+        match self.max_window_bits {
+            MAX_WINDOW_BITS => offer.push_str("; client_max_window_bits"),
+            bits => offer.push_str(&*format!("; client_max_window_bits={}", bits))
+        }
+        if self.no_context_takeover {
+            offer.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            offer.push_str(&*format!("; server_max_window_bits={}", bits));
+        }
+        if self.server_no_context_takeover {
+            offer.push_str("; server_no_context_takeover");
+        }
+        offer
+    }
+
+    fn accept(&mut self, params: &[Param]) -> WSResult<()> {
+        self.window_bits = self.max_window_bits;
+        self.server_window_bits = MAX_WINDOW_BITS;
+        self.keep_deflater = !self.no_context_takeover;
+        self.keep_inflater = true;
+
+        for (i, &(ref name, ref value)) in params.iter().enumerate() {
+            if params[..i].iter().any(|&(ref n, _)| n == name) {
+                return Err(invalid_params("repeated permessage-deflate parameter"));
+            }
+
+            let value = value.as_ref().map(|v| &**v);
+            match (&**name, value) {
+                ("client_max_window_bits", Some(_)) => self.window_bits = cmp::min(self.window_bits, window_bits(value, MIN_WINDOW_BITS)?),
+                ("server_max_window_bits", Some(_)) => match (window_bits(value, 8)?, self.server_max_window_bits) {
+                    (bits, Some(max)) if bits > max => return Err(invalid_params("server window is larger than requested")),
+                    (bits, _) => self.server_window_bits = bits
+                },
+                ("client_no_context_takeover", None) => self.keep_deflater = false,
+                ("server_no_context_takeover", None) => self.keep_inflater = false,
+                _ => return Err(invalid_params("invalid permessage-deflate parameters"))
+            }
+        }
+
+        // Check parameters right away, so that handshake fails if they can't be used
+        self.deflater()?;
+        self.inflater()?;
+        self.negotiated = true;
+        Ok(())
+    }
+
+    fn rsv(&self) -> WSHeader {
+        WS_RSV1
+    }
+
+    fn encode(&mut self, header: &mut WSHeader, data: &mut Vec<u8>, compress: bool) -> WSResult<()> {
+        if !self.negotiated {
+            return Ok(());
+        }
+
+        // Message is compressed as a whole (or not at all), so continuations follow the first frame
+        if *header & WS_OPCODE != WS_OPCONT {
+            self.deflating = compress && !(header.contains(WS_FIN) && data.len() < self.min_size);
+            if self.deflating {
+                header.insert(WS_RSV1);
+            }
+        }
+        if !self.deflating {
+            return Ok(());
+        }
+
+        let last = header.contains(WS_FIN);
+        compress_in_place(self.deflater()?, data, last)?;
+        if last {
+            self.deflating = false;
+            if !self.keep_deflater {
+                self.deflater = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, header: &mut WSHeader, data: &mut Vec<u8>, limit: Option<usize>) -> WSResult<()> {
+        if !self.negotiated {
+            return Ok(());
+        }
+
+        if *header & WS_OPCODE != WS_OPCONT {
+            self.inflating = header.contains(WS_RSV1);
+        } else if header.contains(WS_RSV1) {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidData, "RSV1 is set on continuation frame")));
+        }
+        if !self.inflating {
+            return Ok(());
+        }
+
+        let last = header.contains(WS_FIN);
+        decompress_in_place(self.inflater()?, data, last, limit)?;
+        header.remove(WS_RSV1);
+        if last {
+            self.inflating = false;
+            if !self.keep_inflater {
+                self.inflater = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = PerMessageDeflate {
+            level: self.level,
+            mem_level: self.mem_level,
+            min_size: self.min_size,
+            max_window_bits: self.max_window_bits,
+            no_context_takeover: self.no_context_takeover,
+            server_max_window_bits: self.server_max_window_bits,
+            server_no_context_takeover: self.server_no_context_takeover,
+            ..PerMessageDeflate::new()
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use url::Url;
+
+    use mock;
+    use socket::WebSocket;
+    use error::WSError;
+    use message::{WSMessage, WS_FIN, WS_MASK, WS_OPTEXT, WS_OPCONT};
+    use super::{Deflater, Inflater, DeflateFrame, PerMessageDeflate, compress_in_place, decompress_in_place};
+
+    #[test]
+    fn compressed_data_survives_roundtrip() {
+        let mut deflater = Deflater::new(6, 15, 8).unwrap();
+        let mut inflater = Inflater::new(15).unwrap();
+
+        for text in &[&b"hello, hello, hello"[..], b"", &[7u8; 100000][..], b"hello again"] {
+            let mut data = text.to_vec();
+            compress_in_place(&mut deflater, &mut data, true).unwrap();
+            assert!(data.len() < 1000);
+            decompress_in_place(&mut inflater, &mut data, true, None).unwrap();
+            assert_eq!(&*data, *text);
+        }
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() {
+        let mut deflater = Deflater::new(6, 15, 8).unwrap();
+        let mut data = vec![0u8; 1 << 20];
+        compress_in_place(&mut deflater, &mut data, true).unwrap();
+
+        match decompress_in_place(&mut Inflater::new(15).unwrap(), &mut data, true, Some(4096)) {
+            Err(WSError::TooLarge { limit: 4096, .. }) => (),
+            other => panic!("expected too large error, got {:?}", other)
+        }
+
+        let mut garbage = vec![0xff; 16];
+        match decompress_in_place(&mut Inflater::new(15).unwrap(), &mut garbage, true, None) {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => (),
+            other => panic!("expected invalid data error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn frames_are_compressed_when_negotiated() {
+        let (stream, server) = mock::pair();
+        server.set_extensions("x-webkit-deflate-frame; no_context_takeover");
+        let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        ws.add_extension(DeflateFrame::new());
+        ws.connect_over(stream).unwrap();
+        assert!(server.request().contains("\r\nSec-WebSocket-Extensions: x-webkit-deflate-frame\r\n"));
+
+        ws.send_text("hello, hello, hello").unwrap();
+        let written = server.written();
+        assert_eq!(written[0], 0xc1);
+        let mut payload = mock::payload(&*written);
+        decompress_in_place(&mut Inflater::new(15).unwrap(), &mut payload, true, None).unwrap();
+        assert_eq!(&*payload, b"hello, hello, hello");
+
+        // Uncompressed frames are let through
+        let mut data = b"hi there".to_vec();
+        compress_in_place(&mut Deflater::new(6, 15, 8).unwrap(), &mut data, true).unwrap();
+        server.push(&*mock::frame(0xc1, &*data));
+        server.push(&*mock::frame(0x81, b"plain"));
+
+        let msg = ws.read_message().unwrap();
+        assert_eq!(msg.as_text().unwrap(), "hi there");
+        assert!(!msg.was_compressed());
+        assert_eq!(ws.read_message().unwrap().as_text().unwrap(), "plain");
+    }
+
+    // Socket with permessage-deflate accepted by server with given parameters
+    fn connect(accepted: &str, ext: PerMessageDeflate) -> (WebSocket<mock::MockStream>, mock::MockServer) {
+        let (stream, server) = mock::pair();
+        server.set_extensions(accepted);
+        let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        ws.add_extension(ext);
+        ws.connect_over(stream).unwrap();
+        (ws, server)
+    }
+
+    #[test]
+    fn messages_are_compressed_as_a_whole() {
+        let (mut ws, server) = connect("permessage-deflate; server_max_window_bits=10", PerMessageDeflate::new());
+        assert!(server.request().contains("\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n"));
+
+        ws.send_data(WS_MASK | WS_OPTEXT, None, b"hello, ").unwrap();
+        let first = server.written();
+        ws.send_data(WS_FIN | WS_MASK | WS_OPCONT, None, b"hello").unwrap();
+        let second = server.written()[first.len()..].to_vec();
+        assert_eq!((first[0], second[0]), (0x41, 0x80));
+
+        let mut payload = mock::payload(&*first);
+        payload.extend_from_slice(&*mock::payload(&*second));
+        decompress_in_place(&mut Inflater::new(15).unwrap(), &mut payload, true, None).unwrap();
+        assert_eq!(&*payload, b"hello, hello");
+
+        // Server's message is fragmented at arbitrary point of compressed data
+        let mut data = b"hi there, hi there".to_vec();
+        compress_in_place(&mut Deflater::new(6, 10, 8).unwrap(), &mut data, true).unwrap();
+        server.push(&*mock::frame(0x42, &data[..3]));
+        server.push(&*mock::frame(0x89, b""));
+        server.push(&*mock::frame(0x80, &data[3..]));
+
+        let frames = (0..3).map(|_| ws.read_frame().unwrap()).collect::<Vec<_>>();
+        assert!(frames.iter().all(|f| !f.was_compressed()));
+        assert!(frames[1].is_ping());
+        assert_eq!([&*frames[0].data, &*frames[2].data].concat(), b"hi there, hi there");
+    }
+
+    #[test]
+    fn continuation_with_rsv1_fails_connection() {
+        let (mut ws, server) = connect("permessage-deflate", PerMessageDeflate::new());
+        server.push(&*mock::frame(0x01, b"a"));
+        server.push(&*mock::frame(0xc0, b"b"));
+
+        ws.read_frame().unwrap();
+        match ws.read_frame() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => (),
+            other => panic!("expected protocol error, got {:?}", other)
+        }
+        assert!(server.is_shut_down());
+    }
+
+    #[test]
+    fn invalid_parameters_fail_handshake() {
+        for accepted in &["permessage-deflate; foo", "permessage-deflate; client_max_window_bits=8",
+                          "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+                          "permessage-deflate; client_no_context_takeover=1", "permessage-deflate, permessage-deflate"] {
+            let (stream, server) = mock::pair();
+            server.set_extensions(accepted);
+            let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+            ws.add_extension(PerMessageDeflate::new());
+            assert!(ws.connect_over(stream).is_err(), "{:?} is accepted", accepted);
+        }
+    }
+
+    #[test]
+    fn compression_level_is_used() {
+        let text = "compressible text, ".repeat(100);
+        let mut sizes = Vec::new();
+        for &level in &[0, 9] {
+            let (mut ws, server) = connect("permessage-deflate", PerMessageDeflate::new().level(level).mem_level(1));
+            ws.send_text(&*text).unwrap();
+            sizes.push(server.written().len());
+        }
+        assert!(sizes[0] > text.len());
+        assert!(sizes[1] < text.len() / 10);
+    }
+
+    #[test]
+    fn messages_may_be_sent_uncompressed() {
+        let (mut ws, server) = connect("permessage-deflate", PerMessageDeflate::new());
+        ws.send_uncompressed(&WSMessage::binary(b"\x1f\x8b gzipped").mask()).unwrap();
+        let first = server.written();
+        assert_eq!(first[0], 0x82);
+        assert_eq!(&*mock::payload(&*first), b"\x1f\x8b gzipped");
+
+        ws.send_binary(b"plain").unwrap();
+        assert_eq!(server.written()[first.len()], 0xc2);
+    }
+
+    #[test]
+    fn small_messages_are_sent_uncompressed() {
+        let (mut ws, server) = connect("permessage-deflate", PerMessageDeflate::new().compress_min_size(64));
+        ws.send_text("tiny").unwrap();
+        let tiny = server.written();
+        assert_eq!(tiny[0], 0x81);
+        assert_eq!(&*mock::payload(&*tiny), b"tiny");
+
+        ws.send_text(&*"large ".repeat(20)).unwrap();
+        assert_eq!(server.written()[tiny.len()], 0xc1);
+
+        // Fragment size tells nothing about message size
+        let sent = server.written().len();
+        ws.send_data(WS_MASK | WS_OPTEXT, None, b"tiny").unwrap();
+        assert_eq!(server.written()[sent], 0x41);
+    }
+
+    #[test]
+    fn local_limits_are_offered_and_kept() {
+        let ext = PerMessageDeflate::new().max_window_bits(10).no_context_takeover().server_max_window_bits(9).server_no_context_takeover();
+        let (mut ws, server) = connect("permessage-deflate; client_max_window_bits=12; server_max_window_bits=9; server_no_context_takeover", ext);
+        assert!(server.request().contains("\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=10; client_no_context_takeover; server_max_window_bits=9; server_no_context_takeover\r\n"));
+
+        // Same message is compressed the same way without context takeover
+        ws.send_text("hello, hello").unwrap();
+        let first = server.written();
+        ws.send_text("hello, hello").unwrap();
+        let second = server.written()[first.len()..].to_vec();
+        assert_eq!(mock::payload(&*first), mock::payload(&*second));
+
+        // Server compresses with the window it has agreed to
+        let text = "hello, hello ".repeat(50).into_bytes();
+        let mut data = text.clone();
+        compress_in_place(&mut Deflater::new(6, 9, 8).unwrap(), &mut data, true).unwrap();
+        server.push(&*mock::frame(0xc2, &*data));
+        assert_eq!(&*ws.read_message().unwrap().data, &*text);
+
+        let (stream, server) = mock::pair();
+        server.set_extensions("permessage-deflate; server_max_window_bits=12");
+        let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        ws.add_extension(PerMessageDeflate::new().server_max_window_bits(10));
+        assert!(ws.connect_over(stream).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::collections::BTreeMap;
+use std::str::Utf8Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum WSError {
+    /// Underlying stream I/O error
+    Io(io::Error),
+    /// Stream has ended in the middle of a frame, after `read` bytes
+    /// of `expected` frame size (as far as it was known by the moment) were received
+    ConnectionResetMidFrame { read: usize, expected: usize },
+    /// Server has rejected upgrade request
+    Handshake(HandshakeError),
+    /// Message is expected to be a text one, but it is not
+    NotText,
+    /// Text message payload is not a valid UTF-8
+    Utf8(Utf8Error),
+    /// Frame of `size` bytes exceeds configured size `limit`
+    TooLarge { size: u64, limit: usize },
+    /// Invalid argument or call, e.g. close status code which can't be sent,
+    /// connection is not affected
+    InvalidInput(&'static str)
+}
+
+/// Server response to rejected upgrade request
+#[derive(Debug)]
+pub struct HandshakeError {
+    /// HTTP status code, if response status line was parsable
+    pub status: Option<u16>,
+    /// Response headers, names are lowercased
+    pub headers: BTreeMap<String, String>,
+    /// Response body, truncated to a sane size
+    pub body: Vec<u8>
+}
+
+impl HandshakeError {
+    #[inline] pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| &**v)
+    }
+}
+
+impl WSError {
+    /// Transient failure, e.g. network error or server overload: reconnecting
+    /// (maybe after a delay) is likely to help; read with no data yet and wake up
+    /// are not failures at all
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            WSError::Io(ref e) => !matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData |
+                                                      io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted),
+            WSError::ConnectionResetMidFrame { .. } => true,
+            WSError::Handshake(ref e) => matches!(e.status, Some(408) | Some(429) | Some(502) | Some(503) | Some(504)),
+            _ => false
+        }
+    }
+
+    /// Peer has violated protocol or sent something we can't handle,
+    /// connection can't be used any more
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            WSError::Io(ref e) => matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData),
+            WSError::Utf8(..) | WSError::TooLarge { .. } => true,
+            _ => false
+        }
+    }
+
+    /// Server has rejected upgrade request for good, e.g. wrong path or no authorization,
+    /// retrying with the same request is pointless
+    #[inline] pub fn is_permanent(&self) -> bool {
+        match *self {
+            WSError::Handshake(..) => !self.is_retryable(),
+            _ => false
+        }
+    }
+}
+
+pub type WSResult<T> = Result<T, WSError>;
+
+impl From<io::Error> for WSError {
+    fn from(err: io::Error) -> WSError {
+        WSError::Io(err)
+    }
+}
+
+/// I/O errors are unwrapped, others are wrapped with matching error kind, e.g. for `Write` impls
+impl From<WSError> for io::Error {
+    fn from(err: WSError) -> io::Error {
+        let kind = match err {
+            WSError::Io(err) => return err,
+            WSError::ConnectionResetMidFrame { .. } => io::ErrorKind::UnexpectedEof,
+            WSError::Handshake(..) => io::ErrorKind::ConnectionRefused,
+            WSError::NotText | WSError::Utf8(..) => io::ErrorKind::InvalidData,
+            WSError::TooLarge { .. } | WSError::InvalidInput(..) => io::ErrorKind::InvalidInput
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl From<Utf8Error> for WSError {
+    fn from(err: Utf8Error) -> WSError {
+        WSError::Utf8(err)
+    }
+}
+
+impl Error for WSError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            WSError::Io(ref e) => Some(e),
+            WSError::Utf8(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for WSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WSError::Io(ref e) => e.fmt(f),
+            WSError::ConnectionResetMidFrame { read, expected } =>
+                write!(f, "connection reset in the middle of a frame after {} of {} bytes", read, expected),
+            WSError::Handshake(ref e) => match e.status {
+                Some(status) => write!(f, "handshake rejected by server with status {}", status),
+                None => write!(f, "handshake rejected by server with invalid response status")
+            },
+            WSError::NotText => f.write_str("not a text message"),
+            WSError::Utf8(ref e) => write!(f, "invalid UTF-8 in text message: {}", e),
+            WSError::TooLarge { size, limit } => write!(f, "frame of {} bytes exceeds size limit of {} bytes", size, limit),
+            WSError::InvalidInput(desc) => write!(f, "invalid input: {}", desc)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::WSError;
+
+    #[test]
+    fn local_errors_are_neither_retryable_nor_fatal() {
+        for e in &[WSError::InvalidInput("invalid header"),
+                   WSError::Io(io::Error::new(io::ErrorKind::WouldBlock, "no data")),
+                   WSError::Io(io::Error::new(io::ErrorKind::Interrupted, "woken up"))] {
+            assert!(!e.is_retryable() && !e.is_fatal(), "{:?}", e);
+        }
+        assert!(WSError::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset")).is_retryable());
+        assert_eq!(WSError::InvalidInput("invalid header").to_string(), "invalid input: invalid header");
+    }
+
+    #[test]
+    fn io_error_keeps_original_error() {
+        let err = io::Error::from(WSError::TooLarge { size: 10, limit: 5 });
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match err.get_ref().and_then(|e| e.downcast_ref::<WSError>()) {
+            Some(&WSError::TooLarge { size: 10, limit: 5 }) => (),
+            other => panic!("expected original error, got {:?}", other)
+        }
+        assert_eq!(io::Error::from(WSError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "gone"))).kind(), io::ErrorKind::BrokenPipe);
+    }
+}
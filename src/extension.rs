@@ -0,0 +1,101 @@
+//! Protocol extensions framework: extensions are offered in upgrade request,
+//! configured with parameters server has accepted them with, and then transform
+//! payloads of data frames going both ways, possibly using RSV bits.
+//!
+//! Extensions are added to socket with `WebSocket::add_extension()`, extensions
+//! offered as plain strings are only negotiated, frames are passed as is.
+
+use message::WSHeader;
+use error::WSResult;
+
+/// Extension parameter, e.g. `client_max_window_bits=10`, value is unquoted
+pub type Param = (String, Option<String>);
+
+pub trait Extension {
+    /// Extension token, as it's named in Sec-WebSocket-Extensions header
+    fn name(&self) -> &str;
+
+    /// Offer with parameters for upgrade request
+    fn offer(&self) -> String;
+
+    /// Configure extension with parameters server has accepted it with,
+    /// error fails the handshake, as the extension can't work with them
+    fn accept(&mut self, params: &[Param]) -> WSResult<()>;
+
+    /// RSV bits extension uses, no two negotiated extensions may share them
+    fn rsv(&self) -> WSHeader;
+
+    /// Transform payload of outgoing data frame, setting RSV bits as needed,
+    /// `compress` is false for messages sender has asked not to compress
+    fn encode(&mut self, header: &mut WSHeader, data: &mut Vec<u8>, compress: bool) -> WSResult<()>;
+
+    /// Transform payload of incoming data frame, clearing RSV bits it has handled,
+    /// payload may not grow larger than `limit`
+    fn decode(&mut self, header: &mut WSHeader, data: &mut Vec<u8>, limit: Option<usize>) -> WSResult<()>;
+
+    /// Forget negotiated parameters and state kept between frames, before reconnection
+    fn reset(&mut self);
+}
+
+/// Parse Sec-WebSocket-Extensions header value into extension names with their parameters,
+/// parameter values may be quoted
+pub fn parse(header: &str) -> Vec<(String, Vec<Param>)> {
+    let mut result = Vec::new();
+
+    for ext in split_unquoted(header, ',') {
+        let mut parts = split_unquoted(&*ext, ';').into_iter();
+        let name = match parts.next() {
+            Some(ref name) if !name.trim().is_empty() => name.trim().to_string(),
+            _ => continue
+        };
+
+        let params = parts.filter(|p| !p.trim().is_empty()).map(|p| {
+            let mut kv = p.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim().to_string();
+            let value = kv.next().map(|v| v.trim().trim_matches('"').to_string());
+            (key, value)
+        }).collect();
+
+        result.push((name, params));
+    }
+
+    result
+}
+
+// Split on `sep` outside of quoted strings
+pub(crate) fn split_unquoted(s: &str, sep: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut quoted = false;
+
+    for c in s.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if c == sep && !quoted {
+            parts.push(part);
+            part = String::new();
+        } else {
+            part.push(c);
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn extensions_are_parsed_with_params() {
+        let exts = parse("permessage-deflate; client_max_window_bits=\"10\"; server_no_context_takeover, x-foo; a=\"b,c;d\",, ");
+        assert_eq!(exts.len(), 2);
+        assert_eq!(&*exts[0].0, "permessage-deflate");
+        assert_eq!(exts[0].1, vec![("client_max_window_bits".to_string(), Some("10".to_string())),
+                                   ("server_no_context_takeover".to_string(), None)]);
+        assert_eq!(&*exts[1].0, "x-foo");
+        assert_eq!(exts[1].1, vec![("a".to_string(), Some("b,c;d".to_string()))]);
+    }
+}
@@ -0,0 +1,268 @@
+use std::io::{self, Read};
+
+use message::{WSHeader, WS_FIN, WS_RSV, WS_OPCODE, WS_MASK, WS_LEN, WS_LEN16, WS_LEN64};
+
+/// Wire frame header composer: FIN flag, RSV bits, opcode, mask key and payload length.
+///
+/// Takes care of choosing between 7, 16 and 64 bit length encodings
+/// and appends extended length and masking key after the first two bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeaderBuilder {
+    fin: bool,
+    rsv: u8,
+    opcode: u8,
+    mask: Option<u32>,
+    len: u64
+}
+
+impl FrameHeaderBuilder {
+    pub fn new() -> FrameHeaderBuilder {
+        FrameHeaderBuilder { fin: true, rsv: 0, opcode: 0, mask: None, len: 0 }
+    }
+
+    /// Take FIN, RSV and opcode bits from existing header,
+    /// length and mask bits are ignored.
+    pub fn from_header(header: WSHeader) -> FrameHeaderBuilder {
+        FrameHeaderBuilder {
+            fin: header.contains(WS_FIN),
+            rsv: ((header & WS_RSV).bits() >> 12) as u8,
+            opcode: ((header & WS_OPCODE).bits() >> 8) as u8,
+            mask: None,
+            len: 0
+        }
+    }
+
+    #[inline] pub fn fin(mut self, fin: bool) -> FrameHeaderBuilder {
+        self.fin = fin;
+        self
+    }
+
+    #[inline] pub fn rsv(mut self, rsv: u8) -> FrameHeaderBuilder {
+        self.rsv = rsv & 0x7;
+        self
+    }
+
+    #[inline] pub fn opcode(mut self, opcode: u8) -> FrameHeaderBuilder {
+        self.opcode = opcode & 0xf;
+        self
+    }
+
+    #[inline] pub fn mask(mut self, mask: Option<u32>) -> FrameHeaderBuilder {
+        self.mask = mask;
+        self
+    }
+
+    #[inline] pub fn len(mut self, len: u64) -> FrameHeaderBuilder {
+        self.len = len;
+        self
+    }
+
+    /// First two bytes of the frame as header flags
+    pub fn header(&self) -> WSHeader {
+        let mut hdr = WSHeader::from_bits_truncate(((self.rsv as u16) << 12) | ((self.opcode as u16) << 8));
+
+        if self.fin {
+            hdr.insert(WS_FIN);
+        }
+
+        if self.mask.is_some() {
+            hdr.insert(WS_MASK);
+        }
+
+        if self.len < WS_LEN16.bits() as u64 {
+            hdr | WSHeader::from_bits_truncate(self.len as u16 & WS_LEN.bits())
+        } else if self.len <= u16::MAX as u64 {
+            hdr | WS_LEN16
+        } else {
+            hdr | WS_LEN64
+        }
+    }
+
+    /// Full header as it goes on the wire, including extended length and mask key
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let hdr = self.header();
+        let mut bytes = Vec::with_capacity(14);
+
+        bytes.push((hdr.bits() >> 8) as u8);
+        bytes.push(hdr.bits() as u8);
+
+        let wslen = hdr & WS_LEN;
+        if wslen == WS_LEN16 {
+            bytes.extend_from_slice(&pack_be_u16(self.len as u16));
+        } else if wslen == WS_LEN64 {
+            bytes.extend_from_slice(&pack_be_u64(self.len));
+        }
+
+        if let Some(mask) = self.mask {
+            bytes.extend_from_slice(&pack_be_u32(mask));
+        }
+
+        bytes
+    }
+}
+
+impl Default for FrameHeaderBuilder {
+    #[inline] fn default() -> FrameHeaderBuilder {
+        FrameHeaderBuilder::new()
+    }
+}
+
+/// Total size of a frame starting at the beginning of `buf`,
+/// if there's enough data to decode its header and its length is valid
+pub fn frame_size(buf: &[u8]) -> Option<u64> {
+    if buf.len() < 2 {
+        return None;
+    }
+
+    let hdr = WSHeader::from_bits_truncate(unpack_be(&buf[..2]) as u16);
+    let wslen = hdr & WS_LEN;
+    let ext = if wslen == WS_LEN16 { 2 } else if wslen == WS_LEN64 { 8 } else { 0 };
+    let mask = if hdr.contains(WS_MASK) { 4 } else { 0 };
+
+    if buf.len() < 2 + ext {
+        return None;
+    }
+
+    let len = if ext > 0 { unpack_be(&buf[2..2 + ext]) } else { wslen.bits() as u64 };
+    // The most significant bit of 64-bit length MUST be 0
+    if len >> 63 != 0 {
+        return None;
+    }
+    len.checked_add((2 + ext + mask) as u64)
+}
+
+// Network (big-endian) byte order packing helpers
+
+#[inline] pub fn pack_be_u16(v: u16) -> [u8; 2] {
+    [(v >> 8) as u8, v as u8]
+}
+
+#[inline] pub fn pack_be_u32(v: u32) -> [u8; 4] {
+    [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+#[inline] pub fn pack_be_u64(v: u64) -> [u8; 8] {
+    [(v >> 56) as u8, (v >> 48) as u8, (v >> 40) as u8, (v >> 32) as u8,
+     (v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+/// Unpack up to 8 big-endian bytes into integer
+#[inline] pub fn unpack_be(buf: &[u8]) -> u64 {
+    buf.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+}
+
+/// Read until `buf` is full or stream ends, returns number of bytes read
+pub fn read_upto<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match r.read(&mut buf[pos..])? {
+            0 => break,
+            n => pos += n
+        }
+    }
+    Ok(pos)
+}
+
+/// Read exactly `buf.len()` bytes, failing if stream ends prematurely
+pub fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    if read_upto(r, buf)? < buf.len() {
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of stream"))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn read_be_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    read_full(r, &mut buf)?;
+    Ok(unpack_be(&buf) as u16)
+}
+
+pub fn read_be_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    read_full(r, &mut buf)?;
+    Ok(unpack_be(&buf) as u32)
+}
+
+pub fn read_be_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    read_full(r, &mut buf)?;
+    Ok(unpack_be(&buf))
+}
+
+/// Apply (or remove, it's symmetric) mask to data,
+/// mask bytes are used in network order as they appear on the wire
+pub fn mask_data(data: &[u8], mask: u32) -> Vec<u8> {
+    let key = pack_be_u32(mask);
+    data.iter().enumerate().map(|(i, b)| *b ^ key[i % 4]).collect::<Vec<u8>>()
+}
+
+/// Same as `mask_data()`, but modifies data in place
+pub fn mask_in_place(data: &mut [u8], mask: u32) {
+    let key = pack_be_u32(mask);
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameHeaderBuilder, frame_size, pack_be_u16, pack_be_u32, pack_be_u64, unpack_be, mask_data, mask_in_place};
+    use message::{WS_FIN, WS_MASK, WS_OPTEXT, WS_OPBIN, WS_RSV1, WS_LEN16};
+
+    #[test]
+    fn frame_size_counts_header() {
+        assert_eq!(frame_size(&[0x81]), None);
+        assert_eq!(frame_size(&[0x81, 0x05]), Some(7));
+        assert_eq!(frame_size(&[0x81, 0x85]), Some(11));
+        assert_eq!(frame_size(&[0x82, 0x7e, 0x01]), None);
+        assert_eq!(frame_size(&[0x82, 0x7e, 0x01, 0x00]), Some(260));
+    }
+
+    #[test]
+    fn frame_size_rejects_huge_length() {
+        assert_eq!(frame_size(&[0x82, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), None);
+        assert_eq!(frame_size(&[0x82, 0xff, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), Some(0x7fff_ffff_ffff_ffff + 14));
+    }
+
+    #[test]
+    fn header_builder_chooses_length_encoding() {
+        let builder = FrameHeaderBuilder::new().opcode(1);
+        assert_eq!(builder.len(5).to_bytes(), vec![0x81, 0x05]);
+        assert_eq!(builder.len(125).to_bytes(), vec![0x81, 0x7d]);
+        assert_eq!(builder.len(126).to_bytes(), vec![0x81, 0x7e, 0x00, 0x7e]);
+        assert_eq!(builder.len(0xffff).to_bytes(), vec![0x81, 0x7e, 0xff, 0xff]);
+        assert_eq!(builder.len(0x10000).to_bytes(), vec![0x81, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn header_builder_sets_flags_and_mask() {
+        let builder = FrameHeaderBuilder::new().fin(false).rsv(4).opcode(2).mask(Some(0x01020304)).len(300);
+        assert_eq!(builder.header(), WS_RSV1 | WS_OPBIN | WS_MASK | WS_LEN16);
+        assert_eq!(builder.to_bytes(), vec![0x42, 0xfe, 0x01, 0x2c, 1, 2, 3, 4]);
+
+        let rebuilt = FrameHeaderBuilder::from_header(WS_FIN | WS_OPTEXT | WS_MASK).len(3);
+        assert_eq!(rebuilt.to_bytes(), vec![0x81, 0x03]);
+    }
+
+    #[test]
+    fn integers_are_packed_big_endian() {
+        assert_eq!(pack_be_u16(0x0102), [1, 2]);
+        assert_eq!(pack_be_u32(0x01020304), [1, 2, 3, 4]);
+        assert_eq!(pack_be_u64(0x0102030405060708), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(unpack_be(&[1, 2]), 0x0102);
+        assert_eq!(unpack_be(&pack_be_u64(0xdead_beef_0000_0001)), 0xdead_beef_0000_0001);
+        assert_eq!(unpack_be(&[]), 0);
+    }
+
+    #[test]
+    fn masking_is_symmetric() {
+        let masked = mask_data(b"hello", 0x37fa213d);
+        assert_eq!(masked, vec![0x5f, 0x9f, 0x4d, 0x51, 0x58]);
+
+        let mut data = masked.clone();
+        mask_in_place(&mut data, 0x37fa213d);
+        assert_eq!(&*data, b"hello");
+    }
+}
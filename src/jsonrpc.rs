@@ -0,0 +1,186 @@
+//! JSON-RPC 2.0 client over WebSocket: request ids, response matching,
+//! notifications dispatch and call timeouts.
+
+use std::io;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use rustc_serialize::json::Json;
+
+use socket::WebSocket;
+use message::{WSMessage, Defragmenter, WS_FIN, WS_OPTEXT, WS_MASK};
+use stream::time_left;
+use error::{WSError, WSResult};
+
+/// Error object from JSON-RPC response
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Json>
+}
+
+pub type RpcResult = Result<Json, RpcError>;
+
+fn request(id: Option<u64>, method: &str, params: Json) -> String {
+    let mut obj = BTreeMap::new();
+    obj.insert("jsonrpc".to_string(), Json::String("2.0".to_string()));
+    obj.insert("method".to_string(), Json::String(method.to_string()));
+    obj.insert("params".to_string(), params);
+    if let Some(id) = id {
+        obj.insert("id".to_string(), Json::U64(id));
+    }
+    Json::Object(obj).to_string()
+}
+
+fn response(obj: &Json) -> RpcResult {
+    match obj.find("error") {
+        Some(err) => Err(RpcError {
+            code: err.find("code").and_then(|v| v.as_i64()).unwrap_or(0),
+            message: err.find("message").and_then(|v| v.as_string()).unwrap_or("").to_string(),
+            data: err.find("data").cloned()
+        }),
+        None => Ok(obj.find("result").cloned().unwrap_or(Json::Null))
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub struct JsonRpcClient {
+    ws: WebSocket,
+    next_id: u64,
+    timeout: Option<Duration>,
+    pending: HashSet<u64>,
+    // Responses received while waiting for other ones
+    responses: HashMap<u64, RpcResult>,
+    handler: Option<Box<dyn FnMut(&str, &Json)>>,
+    defrag: Defragmenter
+}
+
+impl JsonRpcClient {
+    pub fn new(ws: WebSocket) -> JsonRpcClient {
+        JsonRpcClient { ws: ws, next_id: 0, timeout: None, pending: HashSet::new(), responses: HashMap::new(), handler: None, defrag: Defragmenter::new() }
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    /// Limit time `call()` waits for response, `None` waits forever
+    #[inline] pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Handle notifications (requests without id) from server, they are dropped if there's no handler
+    pub fn set_notification_handler<F: FnMut(&str, &Json) + 'static>(&mut self, handler: F) {
+        self.handler = Some(Box::new(handler));
+    }
+
+    fn send_text(&mut self, text: &str) -> WSResult<()> {
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, text.as_bytes())
+    }
+
+    /// Send request without waiting for response, returns its id to `wait()` for
+    pub fn request(&mut self, method: &str, params: Json) -> WSResult<u64> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send_text(&*request(Some(id), method, params))?;
+        self.pending.insert(id);
+        Ok(id)
+    }
+
+    /// Send notification, no response is expected
+    pub fn notify(&mut self, method: &str, params: Json) -> WSResult<()> {
+        self.send_text(&*request(None, method, params))
+    }
+
+    /// Call remote method and wait for its response
+    pub fn call(&mut self, method: &str, params: Json) -> WSResult<RpcResult> {
+        let id = self.request(method, params)?;
+        let timeout = self.timeout;
+        self.wait(id, timeout)
+    }
+
+    /// Wait for response to request with given id, dispatching notifications meanwhile,
+    /// if it doesn't come in time, timed out error is returned and late response is dropped.
+    /// Socket's read timeout is restored afterwards
+    pub fn wait(&mut self, id: u64, timeout: Option<Duration>) -> WSResult<RpcResult> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let read_timeout = self.ws.read_timeout();
+
+        let result = self.wait_until(id, deadline);
+        self.pending.remove(&id);
+        if deadline.is_some() {
+            let restored = self.ws.set_read_timeout(read_timeout);
+            if result.is_ok() {
+                restored?;
+            }
+        }
+        result
+    }
+
+    fn wait_until(&mut self, id: u64, deadline: Option<Instant>) -> WSResult<RpcResult> {
+        loop {
+            if let Some(result) = self.responses.remove(&id) {
+                return Ok(result);
+            }
+
+            if deadline.is_some() {
+                let left = time_left(deadline)?;
+                self.ws.set_read_timeout(left)?;
+            }
+
+            match self.ws.read_message() {
+                Ok(msg) => self.dispatch(msg)?,
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                    return Err(WSError::Io(io::Error::new(io::ErrorKind::TimedOut, "JSON-RPC call timed out"))),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// Read and handle one incoming frame: response, notification, ping or a fragment of them
+    pub fn poll(&mut self) -> WSResult<()> {
+        let msg = self.ws.read_message()?;
+        self.dispatch(msg)
+    }
+
+    fn dispatch(&mut self, msg: WSMessage) -> WSResult<()> {
+        let msg = match self.defrag.push(msg)? {
+            Some(msg) => msg,
+            None => return Ok(())
+        };
+
+        if msg.is_ping() {
+            return self.ws.send_message(&WSMessage::pong(&*msg.data).mask());
+        } else if msg.is_close() {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+        } else if !msg.is_text() {
+            // Nothing to do with control and binary messages
+            return Ok(());
+        }
+
+        let items = match msg.as_text()?.parse::<Json>() {
+            Ok(Json::Array(batch)) => batch,
+            Ok(obj @ Json::Object(_)) => vec![obj],
+            _ => return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "invalid JSON-RPC message")))
+        };
+
+        for item in items.iter() {
+            match (item.find("id").and_then(|v| v.as_u64()), item.find("method").and_then(|v| v.as_string())) {
+                (Some(id), None) if self.pending.contains(&id) => {
+                    self.responses.insert(id, response(item));
+                },
+                (None, Some(method)) => if let Some(ref mut handler) = self.handler {
+                    handler(method, item.find("params").unwrap_or(&Json::Null));
+                },
+                // Server to client calls and responses to unknown requests are not supported
+                _ => ()
+            }
+        }
+
+        Ok(())
+    }
+}
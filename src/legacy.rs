@@ -0,0 +1,222 @@
+//! Legacy hixie-76 (a.k.a. hybi-00) protocol draft support:
+//! key1/key2/key3 challenge handshake and sentinel delimited framing.
+//!
+//! There's no masking, fragmentation, ping/pong or status codes in this protocol,
+//! only text messages and closing handshake are supported.
+
+use std::io::{self, Read, Write};
+use rand::Rng;
+use openssl::hash::{hash, MessageDigest};
+
+use frame::{pack_be_u32, read_full};
+use message::{WSMessage, WSHeader, WS_FIN, WS_OPCODE, WS_OPTEXT, WS_OPTERM};
+use error::{WSError, WSResult};
+
+/// Generate `Sec-WebSocket-Key1`/`Sec-WebSocket-Key2` header value
+pub fn generate_key<R: Rng>(r: &mut R) -> String {
+    let spaces = r.gen_range(1u32..13);
+    let number = r.gen_range(0u32..u32::MAX / spaces);
+    let mut key = (number * spaces).to_string().chars().collect::<Vec<char>>();
+
+    // Sprinkle some random non-digit characters...
+    for _ in 0..r.gen_range(1u32..13) {
+        let c = if r.gen() { r.gen_range(0x21u8..0x30) } else { r.gen_range(0x3au8..0x7f) } as char;
+        let pos = r.gen_range(0..key.len() + 1);
+        key.insert(pos, c);
+    }
+
+    // ...and spaces, but never at either end
+    for _ in 0..spaces {
+        let pos = r.gen_range(1..key.len());
+        key.insert(pos, ' ');
+    }
+
+    key.into_iter().collect()
+}
+
+/// Decode key number: all digits divided by number of spaces
+pub fn key_number(key: &str) -> Option<u32> {
+    let digits = key.chars().filter(|c| c.is_ascii_digit()).collect::<String>();
+    let spaces = key.chars().filter(|c| *c == ' ').count() as u64;
+
+    match digits.parse::<u64>() {
+        Ok(n) if spaces > 0 && n % spaces == 0 && n / spaces <= u32::MAX as u64 => Some((n / spaces) as u32),
+        _ => None
+    }
+}
+
+/// Compute 16 bytes challenge response expected in handshake response body
+pub fn challenge_response(key1: &str, key2: &str, key3: &[u8]) -> Option<Vec<u8>> {
+    match (key_number(key1), key_number(key2)) {
+        (Some(n1), Some(n2)) => {
+            let mut data = Vec::with_capacity(16);
+            data.extend_from_slice(&pack_be_u32(n1));
+            data.extend_from_slice(&pack_be_u32(n2));
+            data.extend_from_slice(key3);
+            hash(MessageDigest::md5(), &*data).ok().map(|digest| digest.to_vec())
+        },
+        _ => None
+    }
+}
+
+/// Read next message, frames larger than `max_size` (if given) are rejected,
+/// as are unknown length prefixed frames, which are skipped without keeping them
+pub fn read_message<R: Read>(r: &mut R, max_size: Option<usize>) -> WSResult<WSMessage> {
+    let mut byte = [0u8; 1];
+    loop {
+        read_full(r, &mut byte)?;
+        let frame_type = byte[0];
+
+        if frame_type & 0x80 == 0 {
+            // Text frame: UTF-8 data terminated by 0xff sentinel
+            let mut data = Vec::new();
+            loop {
+                read_full(r, &mut byte)?;
+                if byte[0] == 0xff {
+                    break;
+                }
+                if let Some(limit) = max_size {
+                    if data.len() >= limit {
+                        return Err(WSError::TooLarge { size: data.len() as u64 + 1, limit: limit });
+                    }
+                }
+                data.push(byte[0]);
+            }
+
+            return Ok(WSMessage { header: WS_FIN | WS_OPTEXT, data: data, status: None, received: None });
+        }
+
+        // Length prefixed frame, length is encoded in 7 bit groups, big endian
+        let mut len = 0u64;
+        loop {
+            read_full(r, &mut byte)?;
+            if len >> 57 != 0 {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidData, "invalid frame length")));
+            }
+            len = (len << 7) | (byte[0] & 0x7f) as u64;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        if frame_type == 0xff && len == 0 {
+            return Ok(WSMessage { header: WS_FIN | WS_OPTERM, data: Vec::new(), status: None, received: None });
+        }
+
+        if let Some(limit) = max_size {
+            if len > limit as u64 {
+                return Err(WSError::TooLarge { size: len, limit: limit });
+            }
+        }
+
+        // No other length prefixed frames are defined, so skip them
+        if io::copy(&mut r.take(len), &mut io::sink())? < len {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of stream")));
+        }
+    }
+}
+
+#[inline] pub fn write_message<W: Write>(w: &mut W, msg: &WSMessage) -> io::Result<()> {
+    write_data(w, msg.header, &*msg.data)
+}
+
+pub fn write_data<W: Write>(w: &mut W, header: WSHeader, data: &[u8]) -> io::Result<()> {
+    let opcode = header & WS_OPCODE;
+
+    if opcode == WS_OPTERM {
+        w.write_all(&[0xff, 0x00])?;
+    } else if opcode == WS_OPTEXT && header.contains(WS_FIN) {
+        w.write_all(&[0x00])?;
+        w.write_all(data)?;
+        w.write_all(&[0xff])?;
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "message type is not supported by legacy protocol"));
+    }
+
+    w.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{challenge_response, key_number, read_message, write_data};
+    use message::{WSMessage, WSStatusCode, WS_FIN, WS_OPTEXT, WS_OPBIN};
+    use error::WSError;
+
+    #[test]
+    fn challenge_response_matches_draft_example() {
+        let key1 = "18x 6]8vM;54 *(5:  {   U1]8  z [  8";
+        let key2 = "1_ tx7X d  <  nw  334J702) 7]o}` 0";
+        assert_eq!(key_number(key1), Some(155712099));
+        assert_eq!(key_number(key2), Some(173347027));
+        assert_eq!(challenge_response(key1, key2, b"Tm[K T2u"), Some(b"fQJ,fN/4F4!~K~MH".to_vec()));
+    }
+
+    #[test]
+    fn key_without_spaces_is_invalid() {
+        assert_eq!(key_number("12345"), None);
+    }
+
+    #[test]
+    fn text_frame_round_trip() {
+        let mut wire = Vec::new();
+        write_data(&mut wire, WS_FIN | WS_OPTEXT, "héllo".as_bytes()).unwrap();
+        assert_eq!(wire[0], 0x00);
+        assert_eq!(wire[wire.len() - 1], 0xff);
+
+        let msg = read_message(&mut Cursor::new(wire), None).unwrap();
+        assert!(msg.is_text());
+        assert_eq!(msg.as_text().unwrap(), "héllo");
+    }
+
+    #[test]
+    fn close_frame_round_trip() {
+        let mut wire = Vec::new();
+        write_data(&mut wire, WSMessage::close(WSStatusCode::NoError, b"").header, b"").unwrap();
+        assert_eq!(wire, vec![0xff, 0x00]);
+        assert!(read_message(&mut Cursor::new(wire), None).unwrap().is_close());
+    }
+
+    #[test]
+    fn binary_frames_are_not_supported_for_sending() {
+        assert!(write_data(&mut Vec::new(), WS_FIN | WS_OPBIN, b"data").is_err());
+    }
+
+    #[test]
+    fn many_skipped_frames_are_skipped_in_loop() {
+        let mut wire = Vec::new();
+        for _ in 0..100000 {
+            wire.extend_from_slice(&[0x80, 0x01, 0x55]);
+        }
+        wire.extend_from_slice(b"\x00hi\xff");
+        assert_eq!(read_message(&mut Cursor::new(wire), None).unwrap().data, b"hi");
+    }
+
+    #[test]
+    fn huge_length_prefixed_frame_is_rejected() {
+        let wire = vec![0x80, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        match read_message(&mut Cursor::new(wire), Some(1024)) {
+            Err(WSError::TooLarge { limit: 1024, .. }) => (),
+            other => panic!("expected too large error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn skipped_frame_cut_short_is_error() {
+        let wire = vec![0x80, 0x10, 0x01];
+        assert!(read_message(&mut Cursor::new(wire), None).is_err());
+    }
+
+    #[test]
+    fn long_text_frame_is_rejected() {
+        let mut wire = vec![0x00];
+        wire.extend_from_slice(&[b'a'; 100]);
+        wire.push(0xff);
+        match read_message(&mut Cursor::new(wire), Some(10)) {
+            Err(WSError::TooLarge { limit: 10, .. }) => (),
+            other => panic!("expected too large error, got {:?}", other)
+        }
+    }
+}
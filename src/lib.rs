@@ -1,23 +1,55 @@
-#![feature(rand, old_io, core, collections, hash)]
-
-#![allow(unused_features)]
-#![feature(test)]
+// Written in the style of the era the crate started in:
+// explicit `&*` derefs, `field: field` initializers and `ref` patterns
+#![allow(clippy::explicit_auto_deref, clippy::redundant_field_names, clippy::needless_borrowed_reference)]
 
 extern crate url;
 extern crate openssl;
-extern crate "rustc-serialize" as rustc_serialize;
-extern crate "sha1-hasher" as sha1;
+extern crate rustc_serialize;
+extern crate sha1_smol as sha1;
 extern crate rand;
+extern crate libc;
+extern crate libz_sys;
+extern crate socket2;
 #[macro_use] extern crate bitflags;
 
-#[cfg(test)]
-extern crate test;
-
 pub use socket::WebSocket;
-pub use message::{WSMessage, WSStatusCode};
+pub use message::{WSMessage, WSMessageRef, WSSharedMessage, WSStatusCode, WSTimestamps};
+pub use pool::Pool;
+pub use connection::Connection;
+pub use error::{WSError, WSResult, HandshakeError};
 
+pub mod error;
 pub mod nonce;
 pub mod message;
+pub mod frame;
 pub mod stream;
+pub mod tls;
+pub mod proxy;
+#[cfg(windows)] pub mod pipe;
 pub mod socket;
+pub mod extension;
+pub mod deflate;
+pub mod legacy;
+pub mod clock;
+pub mod throttle;
+pub mod pool;
+pub mod buffer;
+pub mod stomp;
+pub mod socketio;
+pub mod wamp;
+pub mod mqtt;
+pub mod sockjs;
+pub mod jsonrpc;
+pub mod xmpp;
+pub mod relay;
+pub mod swarm;
+pub mod selector;
+pub mod wake;
+pub mod connection;
+pub mod channel;
+pub mod queue;
+pub mod supervisor;
+pub mod standby;
+
+#[cfg(test)] mod mock;
 
@@ -1,51 +1,86 @@
-use std::str::FromStr;
-use std::num::{FromPrimitive, ToPrimitive};
+use std::str::{self, FromStr};
+use std::borrow::Cow;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
 use rustc_serialize::json::{Json, ToJson};
 
+use error::{WSError, WSResult};
+use frame::{FrameHeaderBuilder, pack_be_u16};
+
 bitflags! {
-    #[derive(Debug)] flags WSHeader: u16 {
+    pub struct WSHeader: u16 {
         // Main structure, mask with & to get header parts
-        const WS_FIN     = 0b1000000000000000, // final flag
-        const WS_RSV     = 0b0111000000000000, // reserved
-        const WS_OPCODE  = 0b0000111100000000, // opcode
-        const WS_MASK    = 0b0000000010000000, // mask flag
-        const WS_LEN     = 0b0000000001111111, // length
+        const WS_FIN     = 0b1000000000000000; // final flag
+        const WS_RSV     = 0b0111000000000000; // reserved
+        const WS_OPCODE  = 0b0000111100000000; // opcode
+        const WS_MASK    = 0b0000000010000000; // mask flag
+        const WS_LEN     = 0b0000000001111111; // length
 
         // Opcodes, check for equality after masking with WS_OPCODE
-        const WS_OPCONT  = 0b0000000000000000,
-        const WS_OPTEXT  = 0b0000000100000000,
-        const WS_OPBIN   = 0b0000001000000000,
-        const WS_OPTERM  = 0b0000100000000000,
-        const WS_OPPING  = 0b0000100100000000,
-        const WS_OPPONG  = 0b0000101000000000,
+        const WS_OPCONT  = 0b0000000000000000;
+        const WS_OPTEXT  = 0b0000000100000000;
+        const WS_OPBIN   = 0b0000001000000000;
+        const WS_OPTERM  = 0b0000100000000000;
+        const WS_OPPING  = 0b0000100100000000;
+        const WS_OPPONG  = 0b0000101000000000;
 
         // Bits reserved for extensions, check for equality after &-ing with OP_RSV
-        const WS_RSV1 = 0b0100000000000000,
-        const WS_RSV2 = 0b0010000000000000,
-        const WS_RSV3 = 0b0001000000000000,
+        const WS_RSV1 = 0b0100000000000000;
+        const WS_RSV2 = 0b0010000000000000;
+        const WS_RSV3 = 0b0001000000000000;
 
         // Opcodes reserved for extensions, check for equality after &-ing with OP_OPCODE
-        const WS_OPEXT1 = 0b0000001100000000,
-        const WS_OPEXT2 = 0b0000010000000000,
-        const WS_OPEXT3 = 0b0000010100000000,
-        const WS_OPEXT4 = 0b0000011000000000,
-        const WS_OPEXT5 = 0b0000011100000000,
-
-        const WS_OPCTL1 = 0b0000101100000000,
-        const WS_OPCTL2 = 0b0000110000000000,
-        const WS_OPCTL3 = 0b0000110100000000,
-        const WS_OPCTL4 = 0b0000111000000000,
-        const WS_OPCTL5 = 0b0000111100000000,
+        const WS_OPEXT1 = 0b0000001100000000;
+        const WS_OPEXT2 = 0b0000010000000000;
+        const WS_OPEXT3 = 0b0000010100000000;
+        const WS_OPEXT4 = 0b0000011000000000;
+        const WS_OPEXT5 = 0b0000011100000000;
+
+        const WS_OPCTL1 = 0b0000101100000000;
+        const WS_OPCTL2 = 0b0000110000000000;
+        const WS_OPCTL3 = 0b0000110100000000;
+        const WS_OPCTL4 = 0b0000111000000000;
+        const WS_OPCTL5 = 0b0000111100000000;
 
         // Helper masks
-        const WS_OPCTRL  = 0b0000100000000000, // if matches with &, this is a control code
-        const WS_LEN16   = 0b0000000001111110, // if &WS_LEN equals this, it has 16-bit length
-        const WS_LEN64   = 0b0000000001111111, // if &WS_LEN equals this, it has 32-bit length
+        const WS_OPCTRL  = 0b0000100000000000; // if matches with &, this is a control code
+        const WS_LEN16   = 0b0000000001111110; // if &WS_LEN equals this, it has 16-bit length
+        const WS_LEN64   = 0b0000000001111111; // if &WS_LEN equals this, it has 32-bit length
     }
 }
 
+// Flags are used unqualified all over the place
+pub const WS_FIN: WSHeader = WSHeader::WS_FIN;
+pub const WS_RSV: WSHeader = WSHeader::WS_RSV;
+pub const WS_OPCODE: WSHeader = WSHeader::WS_OPCODE;
+pub const WS_MASK: WSHeader = WSHeader::WS_MASK;
+pub const WS_LEN: WSHeader = WSHeader::WS_LEN;
+pub const WS_OPCONT: WSHeader = WSHeader::WS_OPCONT;
+pub const WS_OPTEXT: WSHeader = WSHeader::WS_OPTEXT;
+pub const WS_OPBIN: WSHeader = WSHeader::WS_OPBIN;
+pub const WS_OPTERM: WSHeader = WSHeader::WS_OPTERM;
+pub const WS_OPPING: WSHeader = WSHeader::WS_OPPING;
+pub const WS_OPPONG: WSHeader = WSHeader::WS_OPPONG;
+pub const WS_RSV1: WSHeader = WSHeader::WS_RSV1;
+pub const WS_RSV2: WSHeader = WSHeader::WS_RSV2;
+pub const WS_RSV3: WSHeader = WSHeader::WS_RSV3;
+pub const WS_OPEXT1: WSHeader = WSHeader::WS_OPEXT1;
+pub const WS_OPEXT2: WSHeader = WSHeader::WS_OPEXT2;
+pub const WS_OPEXT3: WSHeader = WSHeader::WS_OPEXT3;
+pub const WS_OPEXT4: WSHeader = WSHeader::WS_OPEXT4;
+pub const WS_OPEXT5: WSHeader = WSHeader::WS_OPEXT5;
+pub const WS_OPCTL1: WSHeader = WSHeader::WS_OPCTL1;
+pub const WS_OPCTL2: WSHeader = WSHeader::WS_OPCTL2;
+pub const WS_OPCTL3: WSHeader = WSHeader::WS_OPCTL3;
+pub const WS_OPCTL4: WSHeader = WSHeader::WS_OPCTL4;
+pub const WS_OPCTL5: WSHeader = WSHeader::WS_OPCTL5;
+pub const WS_OPCTRL: WSHeader = WSHeader::WS_OPCTRL;
+pub const WS_LEN16: WSHeader = WSHeader::WS_LEN16;
+pub const WS_LEN64: WSHeader = WSHeader::WS_LEN64;
+
 // TODO: use this instead of u16
-#[derive(Copy, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum WSStatusCode {
     NoError, // = 1000,
     GoneAway, // = 1001,
@@ -61,6 +96,8 @@ pub enum WSStatusCode {
     TooLargeData, // = 1009,
     ExtensionMissing, // = 1010,
     ServerError, // = 1011,
+    ServiceRestart, // = 1012,
+    TryAgainLater, // = 1013,
 
     TlsError, // = 1015 // reserved
 
@@ -74,67 +111,99 @@ pub enum WSStatusCode {
     OtherCode(u16)
 }
 
-impl ToPrimitive for WSStatusCode {
-    fn to_u64(&self) -> Option<u64> {
-        match *self {
-            WSStatusCode::NoError => Some(1000u64),
-            WSStatusCode::GoneAway => Some(1001u64),
-            WSStatusCode::ProtocolError => Some(1002u64),
-            WSStatusCode::UnsupportedData => Some(1003u64),
-
-            WSStatusCode::NoCode => Some(1005u64), // reserved
-            WSStatusCode::Aborted => Some(1006u64), // reserved
-
-            WSStatusCode::InvalidData => Some(1007u64),
-            WSStatusCode::ClientError => Some(1008u64),
-            WSStatusCode::TooLargeData => Some(1009u64),
-            WSStatusCode::ExtensionMissing => Some(1010u64),
-            WSStatusCode::ServerError => Some(1011u64),
-
-            WSStatusCode::TlsError => Some(1015u64), // reserved
+impl WSStatusCode {
+    pub fn from_u16(n: u16) -> Option<WSStatusCode> {
+        match n {
+            1000 => Some(WSStatusCode::NoError),
+            1001 => Some(WSStatusCode::GoneAway),
+            1002 => Some(WSStatusCode::ProtocolError),
+            1003 => Some(WSStatusCode::UnsupportedData),
+
+            1005 => Some(WSStatusCode::NoCode), // reserved
+            1006 => Some(WSStatusCode::Aborted), // reserved
+
+            1007 => Some(WSStatusCode::InvalidData),
+            1008 => Some(WSStatusCode::ClientError),
+            1009 => Some(WSStatusCode::TooLargeData),
+            1010 => Some(WSStatusCode::ExtensionMissing),
+            1011 => Some(WSStatusCode::ServerError),
+            1012 => Some(WSStatusCode::ServiceRestart),
+            1013 => Some(WSStatusCode::TryAgainLater),
+
+            1015 => Some(WSStatusCode::TlsError), // reserved
+
+            code if (1000..=2999).contains(&code) => Some(WSStatusCode::ProtocolCode(code)),
+            code if (3000..=3999).contains(&code) => Some(WSStatusCode::ApplicationCode(code)),
+            code if (4000..=4999).contains(&code) => Some(WSStatusCode::OtherCode(code)),
+            _ => None
+        }
+    }
 
-            WSStatusCode::ProtocolCode(code) if 1000 <= code && code <= 2999 => Some(code as u64),
-            WSStatusCode::ApplicationCode(code) if 3000 <= code && code <= 3999 => Some(code as u64),
-            WSStatusCode::OtherCode(code) if 4000 <= code && code <= 4999 => Some(code as u64),
+    /// Numeric code, `None` if code is out of its variant's range
+    pub fn to_u16(&self) -> Option<u16> {
+        match *self {
+            WSStatusCode::NoError => Some(1000),
+            WSStatusCode::GoneAway => Some(1001),
+            WSStatusCode::ProtocolError => Some(1002),
+            WSStatusCode::UnsupportedData => Some(1003),
+
+            WSStatusCode::NoCode => Some(1005), // reserved
+            WSStatusCode::Aborted => Some(1006), // reserved
+
+            WSStatusCode::InvalidData => Some(1007),
+            WSStatusCode::ClientError => Some(1008),
+            WSStatusCode::TooLargeData => Some(1009),
+            WSStatusCode::ExtensionMissing => Some(1010),
+            WSStatusCode::ServerError => Some(1011),
+            WSStatusCode::ServiceRestart => Some(1012),
+            WSStatusCode::TryAgainLater => Some(1013),
+
+            WSStatusCode::TlsError => Some(1015), // reserved
+
+            WSStatusCode::ProtocolCode(code @ 1000..=2999) => Some(code),
+            WSStatusCode::ApplicationCode(code @ 3000..=3999) => Some(code),
+            WSStatusCode::OtherCode(code @ 4000..=4999) => Some(code),
             _ => None
         }
     }
-    fn to_i64(&self) -> Option<i64> {
-        self.to_u64().map(|v| v as i64)
+
+    /// Whether the code may be sent in close frame: reserved codes (1004, 1005, 1006, 1015)
+    /// are only used locally, e.g. to report close without status or abnormal close,
+    /// and unassigned protocol codes (1016-2999) are not allowed on the wire
+    pub fn is_sendable(&self) -> bool {
+        matches!(self.to_u16(), Some(1000..=1003) | Some(1007..=1014) | Some(3000..=4999))
     }
 }
 
-impl FromPrimitive for WSStatusCode {
-    fn from_u64(n: u64) -> Option<WSStatusCode> {
-        match n {
-            1000u64 => Some(WSStatusCode::NoError),
-            1001u64 => Some(WSStatusCode::GoneAway),
-            1002u64 => Some(WSStatusCode::ProtocolError),
-            1003u64 => Some(WSStatusCode::UnsupportedData),
-
-            1005u64 => Some(WSStatusCode::NoCode), // reserved
-            1006u64 => Some(WSStatusCode::Aborted), // reserved
-
-            1007u64 => Some(WSStatusCode::InvalidData),
-            1008u64 => Some(WSStatusCode::ClientError),
-            1009u64 => Some(WSStatusCode::TooLargeData),
-            1010u64 => Some(WSStatusCode::ExtensionMissing),
-            1011u64 => Some(WSStatusCode::ServerError),
-
-            1015u64 => Some(WSStatusCode::TlsError), // reserved
-
-            code if 1000 <= code && code <= 2999 => Some(WSStatusCode::ProtocolCode(code as u16)),
-            code if 3000 <= code && code <= 3999 => Some(WSStatusCode::ApplicationCode(code as u16)),
-            code if 4000 <= code && code <= 4999 => Some(WSStatusCode::OtherCode(code as u16)),
-            _ => None
+/// Max size of close reason, so that close frame fits into control frame payload limit of 125 bytes
+pub const MAX_CLOSE_REASON: usize = 123;
+
+/// Check close frame status and reason are allowed on the wire:
+/// status is sendable, reason is valid UTF-8 of at most `MAX_CLOSE_REASON` bytes,
+/// and there's no reason without status
+pub fn check_close(status: Option<WSStatusCode>, reason: &[u8]) -> WSResult<()> {
+    match status {
+        Some(ref code) if !code.is_sendable() =>
+            Err(WSError::InvalidInput("close status code can't be sent")),
+        None if !reason.is_empty() =>
+            Err(WSError::InvalidInput("close reason without status code")),
+        _ if reason.len() > MAX_CLOSE_REASON =>
+            Err(WSError::InvalidInput("close reason is too long")),
+        _ => {
+            str::from_utf8(reason)?;
+            Ok(())
         }
     }
-    fn from_i64(n: i64) -> Option<WSStatusCode> {
-        if n < 0i64 {
-            None
-        } else {
-            FromPrimitive::from_u64(n as u64)
-        }
+}
+
+/// Numeric value of status code to put into frame, unknown codes can't be sent
+pub fn status_code(status: Option<WSStatusCode>) -> WSResult<Option<u16>> {
+    match status {
+        Some(status) => match status.to_u16() {
+            Some(code) => Ok(Some(code)),
+            None => Err(WSError::InvalidInput("invalid status code"))
+        },
+        None => Ok(None)
     }
 }
 
@@ -147,27 +216,111 @@ impl FromPrimitive for WSStatusCode {
 //     pub status: WSStatusCode,
 //     pub payload: Vec
 // }
+/// When received frame has arrived
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WSTimestamps {
+    /// The moment frame header was read, for long frames it may be much earlier
+    /// than the last byte, for defragmented messages it's the first byte of the first fragment
+    pub first_byte: Instant,
+    /// The moment the last byte of frame payload was read
+    pub last_byte: Instant
+}
+
 #[derive(Debug)]
 pub struct WSMessage {
     pub header: WSHeader,
     pub data: Vec<u8>,
-    pub status: Option<WSStatusCode>
+    pub status: Option<WSStatusCode>,
+    /// Receive time, `None` for messages which were not read from socket
+    pub received: Option<WSTimestamps>
 }
 
 impl WSMessage {
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(&self) -> String {
         String::from_utf8_lossy(&*self.data).into_owned()
     }
 
+    /// Payload of a text message, checked to be valid UTF-8
+    pub fn as_text(&self) -> WSResult<&str> {
+        if !self.is_text() {
+            return Err(WSError::NotText);
+        }
+        Ok(str::from_utf8(&*self.data)?)
+    }
+
+    /// Payload of a text message, with invalid UTF-8 sequences replaced with U+FFFD
+    pub fn as_text_lossy(&self) -> WSResult<Cow<'_, str>> {
+        if !self.is_text() {
+            return Err(WSError::NotText);
+        }
+        Ok(String::from_utf8_lossy(&*self.data))
+    }
+
+    /// Consume text message and get its payload, checked to be valid UTF-8
+    pub fn into_text(self) -> WSResult<String> {
+        if !self.is_text() {
+            return Err(WSError::NotText);
+        }
+        String::from_utf8(self.data).map_err(|e| WSError::Utf8(e.utf8_error()))
+    }
+
+    /// Append payload of `msg` as is, without any checks, see `concat()` for checked version
     pub fn push(&mut self, msg: WSMessage) {
-        self.data.push_all(&*msg.data);
+        self.data.extend_from_slice(&*msg.data);
+        self.received = match (self.received, msg.received) {
+            (Some(first), Some(last)) => Some(WSTimestamps { first_byte: first.first_byte, last_byte: last.last_byte }),
+            (first, last) => first.or(last)
+        };
+    }
+
+    /// Append next fragment to partially reassembled message: `msg` must be a continuation frame
+    /// and this message must not be complete yet, message becomes complete with the final fragment.
+    /// Text is checked to be valid UTF-8 as it grows, a character may be split between fragments,
+    /// and the whole message is checked once it's complete
+    pub fn concat(&mut self, msg: WSMessage) -> WSResult<()> {
+        if self.is_control() || self.is_final() {
+            return Err(WSError::InvalidInput("message is already complete"));
+        }
+        if msg.opcode() != WS_OPCONT {
+            return Err(WSError::InvalidInput("not a continuation frame"));
+        }
+
+        // Start checking from the beginning of the last (maybe incomplete) character
+        let mut start = self.data.len();
+        while start > 0 && self.data.len() - start < 3 && self.data[start - 1] & 0xc0 == 0x80 {
+            start -= 1;
+        }
+        if start > 0 && self.data[start - 1] & 0x80 != 0 {
+            start -= 1;
+        }
+
+        let last = msg.is_final();
+        self.push(msg);
+        if last {
+            self.header.insert(WS_FIN);
+        }
+
+        if self.is_text() {
+            // Appended data only while message grows, as the first fragment might be unchecked
+            if last {
+                str::from_utf8(&*self.data)?;
+            } else if let Err(e) = str::from_utf8(&self.data[start..]) {
+                // Character split at the end is fine, unless it's the last fragment
+                if e.error_len().is_some() {
+                    return Err(WSError::Utf8(e));
+                }
+            }
+        }
+        Ok(())
     }
 
     #[inline] pub fn text(data: &str) -> WSMessage {
         WSMessage {
             header: WS_FIN | WS_OPTEXT,
             data: data.as_bytes().to_vec(),
-            status: None
+            status: None,
+            received: None
         }
     }
 
@@ -175,7 +328,8 @@ impl WSMessage {
         WSMessage {
             header: WS_FIN | WSHeader::from_bits_truncate(((extn & 0x0f) as u16) << 8),
             data: data.to_vec(),
-            status: None
+            status: None,
+            received: None
         }
     }
 
@@ -183,7 +337,8 @@ impl WSMessage {
         WSMessage {
             header: WS_FIN | WS_OPBIN,
             data: data.to_vec(),
-            status: None
+            status: None,
+            received: None
         }
     }
 
@@ -246,15 +401,23 @@ impl WSMessage {
         WSMessage {
             header: WS_FIN | WS_OPTERM,
             data: data.to_vec(),
-            status: Some(status)
+            status: Some(status),
+            received: None
         }
     }
 
+    /// Close message, checked to be valid with `check_close()`
+    pub fn try_close(status: WSStatusCode, reason: &str) -> WSResult<WSMessage> {
+        check_close(Some(status), reason.as_bytes())?;
+        Ok(WSMessage::close(status, reason.as_bytes()))
+    }
+
     #[inline] pub fn ping(data: &[u8]) -> WSMessage {
         WSMessage {
             header: WS_FIN | WS_OPPING,
             data: data.to_vec(),
-            status: None
+            status: None,
+            received: None
         }
     }
 
@@ -262,7 +425,8 @@ impl WSMessage {
         WSMessage {
             header: WS_FIN | WS_OPPONG,
             data: data.to_vec(),
-            status: None
+            status: None,
+            received: None
         }
     }
 
@@ -279,6 +443,12 @@ impl WSMessage {
         (self.header.bits() >> 12) as u8 & 0x7u8 == n
     }
 
+    /// RSV1 bit is set, which means compressed payload with permessage-deflate
+    /// (or x-webkit-deflate-frame) extension, payload is passed as is
+    #[inline] pub fn was_compressed(&self) -> bool {
+        self.header.contains(WS_RSV1)
+    }
+
     #[inline] pub fn is_text(&self) -> bool { self.opcode() == WS_OPTEXT }
     #[inline] pub fn is_binary(&self) -> bool { self.opcode() == WS_OPBIN }
     #[inline] pub fn is_ext(&self, n: u8) -> bool { ((self.opcode().bits() >> 8) as u8) == (n & 0x0f) }
@@ -295,6 +465,113 @@ impl WSMessage {
             pos: 0
         }
     }
+
+    /// Serialize message into complete unmasked (server role) frame, so that it can be encoded
+    /// once and sent to many connections with `Connection::send_raw_frame()`,
+    /// close frames are checked with `check_close()` first
+    pub fn encode_frame(&self) -> WSResult<Vec<u8>> {
+        if self.is_close() {
+            check_close(self.status, &*self.data)?;
+        }
+        let code = status_code(self.status)?;
+
+        let len = self.data.len() + if code.is_none() { 0 } else { 2 };
+        let mut frame = FrameHeaderBuilder::from_header(self.header - WS_MASK).len(len as u64).mask(None).to_bytes();
+        frame.reserve(len);
+
+        if let Some(code) = code {
+            frame.extend_from_slice(&pack_be_u16(code));
+        }
+        frame.extend_from_slice(&*self.data);
+        Ok(frame)
+    }
+
+    /// Turn message into one with shared payload, to be cloned cheaply
+    #[inline] pub fn into_shared(self) -> WSSharedMessage {
+        WSSharedMessage {
+            header: self.header,
+            data: Arc::new(self.data),
+            status: self.status
+        }
+    }
+}
+
+/// Message with reference counted payload: clones share the same data,
+/// so sending one message to many connections doesn't copy it per recipient
+#[derive(Debug, Clone)]
+pub struct WSSharedMessage {
+    pub header: WSHeader,
+    pub data: Arc<Vec<u8>>,
+    pub status: Option<WSStatusCode>
+}
+
+impl WSSharedMessage {
+    /// Get owned message back, payload is copied only if it's still shared
+    pub fn into_message(self) -> WSMessage {
+        WSMessage {
+            header: self.header,
+            data: match Arc::try_unwrap(self.data) {
+                Ok(data) => data,
+                Err(data) => (*data).clone()
+            },
+            status: self.status,
+            received: None
+        }
+    }
+
+    /// Payload of a text message, checked to be valid UTF-8
+    pub fn as_text(&self) -> WSResult<&str> {
+        if !self.is_text() {
+            return Err(WSError::NotText);
+        }
+        Ok(str::from_utf8(&**self.data)?)
+    }
+
+    #[inline] pub fn opcode(&self) -> WSHeader { self.header & WS_OPCODE }
+    #[inline] pub fn is_final(&self) -> bool { self.header.contains(WS_FIN) }
+    #[inline] pub fn is_control(&self) -> bool { self.header.contains(WS_OPCTRL) }
+    #[inline] pub fn is_text(&self) -> bool { self.opcode() == WS_OPTEXT }
+    #[inline] pub fn is_binary(&self) -> bool { self.opcode() == WS_OPBIN }
+    #[inline] pub fn is_close(&self) -> bool { self.opcode() == WS_OPTERM }
+}
+
+/// Borrowed counterpart of `WSMessage`, payload may point into socket's read buffer
+#[derive(Debug)]
+pub struct WSMessageRef<'a> {
+    pub header: WSHeader,
+    pub data: Cow<'a, [u8]>,
+    pub status: Option<WSStatusCode>,
+    pub received: Option<WSTimestamps>
+}
+
+impl<'a> WSMessageRef<'a> {
+    pub fn into_owned(self) -> WSMessage {
+        WSMessage {
+            header: self.header,
+            data: self.data.into_owned(),
+            status: self.status,
+            received: self.received
+        }
+    }
+
+    /// Payload of a text message, checked to be valid UTF-8
+    pub fn as_text(&self) -> WSResult<&str> {
+        if !self.is_text() {
+            return Err(WSError::NotText);
+        }
+        Ok(str::from_utf8(&*self.data)?)
+    }
+
+    #[inline] pub fn opcode(&self) -> WSHeader { self.header & WS_OPCODE }
+    #[inline] pub fn was_compressed(&self) -> bool { self.header.contains(WS_RSV1) }
+    #[inline] pub fn is_final(&self) -> bool { self.header.contains(WS_FIN) }
+    #[inline] pub fn is_control(&self) -> bool { self.header.contains(WS_OPCTRL) }
+    #[inline] pub fn is_text(&self) -> bool { self.opcode() == WS_OPTEXT }
+    #[inline] pub fn is_binary(&self) -> bool { self.opcode() == WS_OPBIN }
+    #[inline] pub fn is_ping(&self) -> bool { self.opcode() == WS_OPPING }
+    #[inline] pub fn is_pong(&self) -> bool { self.opcode() == WS_OPPONG }
+    #[inline] pub fn is_close(&self) -> bool { self.opcode() == WS_OPTERM }
+    #[inline] pub fn is_cont(&self) -> bool { self.opcode() == WS_OPCONT }
 }
 
 pub struct WSFragmentedMessage {
@@ -314,14 +591,16 @@ impl Iterator for WSFragmentedMessage {
             Some(WSMessage {
                 header: self.original.header | WS_FIN,
                 status: self.original.status,
-                data: self.original.data[self.pos..].to_vec()
+                data: self.original.data[self.pos..].to_vec(),
+                received: self.original.received
             })
         } else if self.pos == 0 { // first
             let maxsize = self.maxsize - if self.original.status.is_none() { 0 } else { 2 };
             let result = Some(WSMessage {
                 header: self.original.header - WS_FIN,
                 status: self.original.status,
-                data: self.original.data[..maxsize].to_vec()
+                data: self.original.data[..maxsize].to_vec(),
+                received: self.original.received
             });
             self.original.header.remove(WS_FIN | WS_OPCODE);
             self.original.status = None;
@@ -335,19 +614,63 @@ impl Iterator for WSFragmentedMessage {
             Some(WSMessage {
                 header: self.original.header,
                 status: None,
-                data: self.original.data[pos..pos+self.maxsize].to_vec()
+                data: self.original.data[pos..pos+self.maxsize].to_vec(),
+                received: self.original.received
             })
         }
     }
 }
 
+/// Reassembles data messages from fragments read one frame at a time,
+/// keeps incomplete message between reads, so they may time out meanwhile
+pub struct Defragmenter {
+    partial: Option<WSMessage>
+}
+
+impl Defragmenter {
+    pub fn new() -> Defragmenter {
+        Defragmenter { partial: None }
+    }
+
+    /// Add frame, returns message once it's complete, control frames are returned right away
+    pub fn push(&mut self, msg: WSMessage) -> WSResult<Option<WSMessage>> {
+        if msg.is_control() {
+            return Ok(Some(msg));
+        }
+
+        match self.partial.take() {
+            None if msg.is_cont() => Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidData, "unexpected continuation frame"))),
+            None if msg.is_final() => Ok(Some(msg)),
+            None => {
+                self.partial = Some(msg);
+                Ok(None)
+            },
+            Some(mut partial) => {
+                partial.concat(msg)?;
+                if partial.is_final() {
+                    Ok(Some(partial))
+                } else {
+                    self.partial = Some(partial);
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+impl Default for Defragmenter {
+    #[inline] fn default() -> Defragmenter {
+        Defragmenter::new()
+    }
+}
+
 impl ToJson for WSMessage {
     fn to_json(&self) -> Json {
         self.to_string().parse::<Json>().unwrap()
     }
 }
 
-#[derive(Copy)]
+#[derive(Clone, Copy)]
 pub struct WSMessageParseError;
 
 impl FromStr for WSMessage {
@@ -356,3 +679,91 @@ impl FromStr for WSMessage {
         Ok(WSMessage::text(s))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{WSMessage, WSStatusCode, Defragmenter, check_close, MAX_CLOSE_REASON, WS_FIN, WS_OPTEXT, WS_OPCONT};
+    use error::WSError;
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let mut defrag = Defragmenter::new();
+        assert!(defrag.push(WSMessage::text("Hel").first()).unwrap().is_none());
+        assert!(defrag.push(WSMessage::ping(b"")).unwrap().unwrap().is_ping());
+        assert!(defrag.push(WSMessage::text("lo").more()).unwrap().is_none());
+
+        let msg = defrag.push(WSMessage::text("!").last()).unwrap().unwrap();
+        assert!(msg.is_text() && msg.is_final());
+        assert_eq!(msg.as_text().unwrap(), "Hello!");
+
+        assert_eq!(defrag.push(WSMessage::text("whole")).unwrap().unwrap().as_text().unwrap(), "whole");
+    }
+
+    #[test]
+    fn stray_continuation_is_rejected() {
+        assert!(Defragmenter::new().push(WSMessage::text("lo").last()).is_err());
+
+        let mut defrag = Defragmenter::new();
+        defrag.push(WSMessage::text("Hel").first()).unwrap();
+        assert!(defrag.push(WSMessage::text("again")).is_err());
+    }
+
+    #[test]
+    fn encoded_frame_is_unmasked() {
+        assert_eq!(WSMessage::text("hi").mask().encode_frame().unwrap(), b"\x81\x02hi");
+        assert_eq!(WSMessage::close(WSStatusCode::NoError, b"").encode_frame().unwrap(), b"\x88\x02\x03\xe8");
+    }
+
+    #[test]
+    fn invalid_status_is_not_encoded() {
+        assert!(WSMessage::close(WSStatusCode::OtherCode(5), b"").encode_frame().is_err());
+        assert!(WSMessage::close(WSStatusCode::Aborted, b"").encode_frame().is_err());
+
+        let mut msg = WSMessage::text("hi");
+        msg.status = Some(WSStatusCode::ApplicationCode(1));
+        assert!(msg.encode_frame().is_err());
+    }
+
+    #[test]
+    fn unassigned_protocol_codes_are_not_sendable() {
+        assert!(WSStatusCode::from_u16(1014).unwrap().is_sendable());
+        assert!(!WSStatusCode::from_u16(1016).unwrap().is_sendable());
+        assert!(!WSStatusCode::from_u16(2999).unwrap().is_sendable());
+        assert!(WSStatusCode::from_u16(3000).unwrap().is_sendable());
+        assert!(!WSStatusCode::NoCode.is_sendable());
+    }
+
+    #[test]
+    fn invalid_first_fragment_is_caught_on_concat() {
+        let mut msg = WSMessage { header: WS_OPTEXT, data: b"\xffab".to_vec(), status: None, received: None };
+        assert!(msg.concat(WSMessage { header: WS_OPCONT, data: b"cd".to_vec(), status: None, received: None }).is_ok());
+        match msg.concat(WSMessage { header: WS_FIN | WS_OPCONT, data: b"ef".to_vec(), status: None, received: None }) {
+            Err(WSError::Utf8(..)) => (),
+            other => panic!("expected invalid UTF-8, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn split_character_is_reassembled() {
+        let mut msg = WSMessage { header: WS_OPTEXT, data: b"a\xc3".to_vec(), status: None, received: None };
+        msg.concat(WSMessage { header: WS_OPCONT, data: b"\xa9".to_vec(), status: None, received: None }).unwrap();
+        msg.concat(WSMessage { header: WS_FIN | WS_OPCONT, data: b"b".to_vec(), status: None, received: None }).unwrap();
+        assert_eq!(msg.as_text().unwrap(), "a\u{e9}b");
+    }
+
+    #[test]
+    fn close_frames_are_checked() {
+        assert!(check_close(None, b"").is_ok());
+        assert!(check_close(Some(WSStatusCode::GoneAway), b"bye").is_ok());
+        assert!(check_close(Some(WSStatusCode::NoError), &[b'x'; MAX_CLOSE_REASON]).is_ok());
+
+        assert!(check_close(Some(WSStatusCode::NoError), &[b'x'; MAX_CLOSE_REASON + 1]).is_err());
+        assert!(check_close(None, b"bye").is_err());
+        assert!(check_close(Some(WSStatusCode::Aborted), b"").is_err());
+        match check_close(Some(WSStatusCode::NoError), b"\xff") {
+            Err(WSError::Utf8(..)) => (),
+            other => panic!("expected invalid UTF-8, got {:?}", other)
+        }
+    }
+}
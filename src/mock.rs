@@ -0,0 +1,215 @@
+//! In-memory transport for tests: data server sends is scripted as a sequence
+//! of chunks and timeouts, upgrade request is answered automatically.
+//!
+//! Not every test needs every helper.
+#![allow(dead_code)]
+
+use std::io::{self, Read, Write};
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::cell::RefCell;
+use url::Url;
+
+use socket::WebSocket;
+use error::WSResult;
+use stream::Stream;
+use nonce::compute_accept;
+
+enum Chunk {
+    Data(Vec<u8>),
+    Timeout,
+    Eof
+}
+
+#[derive(Default)]
+struct State {
+    input: VecDeque<Chunk>,
+    request: Vec<u8>,
+    response: Option<Vec<u8>>,
+    extensions: Option<String>,
+    upgraded: bool,
+    written: Vec<u8>,
+    shut_down: bool,
+    read_timeouts: Vec<Option<Duration>>,
+    write_timeouts: Vec<Option<Duration>>
+}
+
+pub struct MockStream(Rc<RefCell<State>>);
+
+/// Server end of `MockStream`
+#[derive(Clone)]
+pub struct MockServer(Rc<RefCell<State>>);
+
+impl MockServer {
+    /// Data to be read by client, each chunk is returned by a separate read at most
+    pub fn push(&self, data: &[u8]) {
+        self.0.borrow_mut().input.push_back(Chunk::Data(data.to_vec()));
+    }
+
+    /// Make one read fail with timed out error
+    pub fn push_timeout(&self) {
+        self.0.borrow_mut().input.push_back(Chunk::Timeout);
+    }
+
+    pub fn push_eof(&self) {
+        self.0.borrow_mut().input.push_back(Chunk::Eof);
+    }
+
+    /// Everything client has written after upgrade request
+    pub fn written(&self) -> Vec<u8> {
+        self.0.borrow().written.clone()
+    }
+
+    /// Accept extensions in upgrade response with given header value
+    pub fn set_extensions(&self, extensions: &str) {
+        self.0.borrow_mut().extensions = Some(extensions.to_string());
+    }
+
+    /// Upgrade request client has sent
+    pub fn request(&self) -> String {
+        String::from_utf8_lossy(&*self.0.borrow().request).into_owned()
+    }
+
+    /// Read timeouts set by client so far, in order
+    pub fn read_timeouts(&self) -> Vec<Option<Duration>> {
+        self.0.borrow().read_timeouts.clone()
+    }
+
+    /// Whether client has shut the connection down
+    pub fn is_shut_down(&self) -> bool {
+        self.0.borrow().shut_down
+    }
+
+    pub fn write_timeouts(&self) -> Vec<Option<Duration>> {
+        self.0.borrow().write_timeouts.clone()
+    }
+}
+
+/// Unmasked server frame with given first header byte (FIN, RSV and opcode)
+pub fn frame(head: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![head];
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        frame.push(126);
+        frame.extend_from_slice(&[(payload.len() >> 8) as u8, payload.len() as u8]);
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Unmasked payload of masked client frame at the start of `data`, which is shorter than 126 bytes
+pub fn payload(data: &[u8]) -> Vec<u8> {
+    let len = (data[1] & 0x7f) as usize;
+    data[6..6 + len].iter().enumerate().map(|(i, b)| b ^ data[2 + i % 4]).collect()
+}
+
+/// Mock transport, not connected yet, and the server end of it
+pub fn pair() -> (MockStream, MockServer) {
+    let state = Rc::new(RefCell::new(State::default()));
+    (MockStream(state.clone()), MockServer(state))
+}
+
+/// Socket upgraded over mock transport, and the server end of it
+pub fn connect() -> (WebSocket<MockStream>, MockServer) {
+    let (stream, server) = pair();
+    let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+    ws.connect_over(stream).unwrap();
+    (ws, server)
+}
+
+/// Try to upgrade over mock transport, server answers with given response
+pub fn handshake(response: &[u8]) -> WSResult<()> {
+    let state = Rc::new(RefCell::new(State::default()));
+    state.borrow_mut().response = Some(response.to_vec());
+    let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+    ws.connect_over(MockStream(state))
+}
+
+impl State {
+    // Answer upgrade request once it's complete
+    fn upgrade(&mut self) {
+        let request = String::from_utf8_lossy(&*self.request).into_owned();
+        if !request.contains("\r\n\r\n") {
+            return;
+        }
+
+        if let Some(response) = self.response.take() {
+            // Server closes connection after the response
+            self.input.push_front(Chunk::Eof);
+            self.input.push_front(Chunk::Data(response));
+            return;
+        }
+
+        let key = request.lines()
+            .find(|line| line.to_ascii_lowercase().starts_with("sec-websocket-key:"))
+            .map(|line| line[18..].trim().to_string())
+            .unwrap_or_default();
+        let extensions = match self.extensions {
+            Some(ref extensions) => format!("Sec-WebSocket-Extensions: {}\r\n", extensions),
+            None => String::new()
+        };
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+                               compute_accept(&*key), extensions);
+
+        self.input.push_front(Chunk::Data(response.into_bytes()));
+        self.upgraded = true;
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        match state.input.pop_front() {
+            Some(Chunk::Data(mut data)) => {
+                let n = ::std::cmp::min(data.len(), buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                if n < data.len() {
+                    state.input.push_front(Chunk::Data(data.split_off(n)));
+                }
+                Ok(n)
+            },
+            Some(Chunk::Eof) => Ok(0),
+            Some(Chunk::Timeout) | None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"))
+        }
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        if state.upgraded {
+            state.written.extend_from_slice(buf);
+        } else {
+            state.request.extend_from_slice(buf);
+            state.upgrade();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MockStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.borrow_mut().read_timeouts.push(timeout);
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.borrow_mut().write_timeouts.push(timeout);
+        Ok(())
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        self.0.borrow_mut().shut_down = true;
+        Ok(())
+    }
+}
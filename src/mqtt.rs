@@ -0,0 +1,158 @@
+//! Binary transport for MQTT clients over WebSocket.
+//!
+//! Offer `mqtt` subprotocol when creating WebSocket, connect it and wrap into `MqttTransport`,
+//! which can be given to any MQTT codec expecting `Read + Write` byte stream.
+//! Only binary frames are allowed by MQTT spec, text frames are rejected.
+
+use std::io::{self, Read, Write};
+use std::cmp;
+
+use socket::WebSocket;
+use message::{WSMessage, WS_FIN, WS_OPBIN, WS_MASK};
+use frame::read_full;
+use error::WSError;
+
+/// Length of MQTT control packet at the start of `buf` (fixed header included),
+/// `None` if there's not enough data to know it yet
+pub fn packet_len(buf: &[u8]) -> Option<usize> {
+    let mut len = 0usize;
+    for i in 0..4 {
+        match buf.get(i + 1) {
+            Some(&byte) => {
+                len |= ((byte & 0x7f) as usize) << (7 * i);
+                if byte & 0x80 == 0 {
+                    return Some(len + i + 2);
+                }
+            },
+            None => return None
+        }
+    }
+    None
+}
+
+/// Read one complete MQTT control packet from byte stream
+pub fn read_packet<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut packet = vec![0u8; 2];
+    read_full(r, &mut *packet)?;
+
+    while packet_len(&*packet).is_none() {
+        if packet.len() >= 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "malformed MQTT remaining length"));
+        }
+        let mut byte = [0u8; 1];
+        read_full(r, &mut byte)?;
+        packet.push(byte[0]);
+    }
+
+    let header_len = packet.len();
+    let len = packet_len(&*packet).unwrap();
+    packet.resize(len, 0);
+    read_full(r, &mut packet[header_len..])?;
+    Ok(packet)
+}
+
+fn to_io_error(err: WSError) -> io::Error {
+    match err {
+        WSError::Io(err) => err,
+        _ => io::Error::new(io::ErrorKind::InvalidInput, "websocket protocol error")
+    }
+}
+
+pub struct MqttTransport {
+    ws: WebSocket,
+    rbuf: Vec<u8>,
+    rpos: usize,
+    wbuf: Vec<u8>
+}
+
+impl MqttTransport {
+    pub fn new(ws: WebSocket) -> MqttTransport {
+        MqttTransport { ws: ws, rbuf: Vec::new(), rpos: 0, wbuf: Vec::new() }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &WebSocket {
+        &self.ws
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    /// Read next binary message into read buffer, answering pings on the way,
+    /// returns false on close
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            let msg = self.ws.read_message().map_err(to_io_error)?;
+
+            if msg.is_ping() {
+                self.ws.send_message(&WSMessage::pong(&*msg.data).mask()).map_err(to_io_error)?;
+            } else if msg.is_close() {
+                return Ok(false);
+            } else if msg.is_text() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "text frames are not allowed for MQTT"));
+            } else if !msg.is_control() && !msg.data.is_empty() {
+                self.rbuf = msg.data;
+                self.rpos = 0;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Send complete MQTT control packet as single binary message
+    pub fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPBIN, None, packet).map_err(to_io_error)
+    }
+
+    /// Read one complete MQTT control packet, whatever way it's split into messages
+    #[inline] pub fn recv_packet(&mut self) -> io::Result<Vec<u8>> {
+        read_packet(self)
+    }
+
+    /// Pump one MQTT packet from codec's stream into WebSocket, returns packet length
+    pub fn pump_from<R: Read>(&mut self, r: &mut R) -> io::Result<usize> {
+        let packet = read_packet(r)?;
+        self.send_packet(&*packet)?;
+        Ok(packet.len())
+    }
+
+    /// Pump one MQTT packet from WebSocket into codec's stream, returns packet length
+    pub fn pump_to<W: Write>(&mut self, w: &mut W) -> io::Result<usize> {
+        let packet = self.recv_packet()?;
+        w.write_all(&*packet)?;
+        w.flush()?;
+        Ok(packet.len())
+    }
+}
+
+impl Read for MqttTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rpos >= self.rbuf.len() && !self.fill()? {
+            return Ok(0);
+        }
+
+        let n = cmp::min(buf.len(), self.rbuf.len() - self.rpos);
+        buf[..n].copy_from_slice(&self.rbuf[self.rpos..self.rpos + n]);
+        self.rpos += n;
+        Ok(n)
+    }
+}
+
+impl Write for MqttTransport {
+    /// Data is buffered until `flush()`, then sent as one binary message
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wbuf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.wbuf.is_empty() {
+            let data = ::std::mem::take(&mut self.wbuf);
+            self.send_packet(&*data)?;
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,62 @@
-use rand::{self, Rng};
+use rand::{self, RngCore};
 use std::ops::Deref;
 use rustc_serialize::base64::{self, ToBase64};
 use sha1::Sha1;
-use std::io::Write;
 
-static WEBSOCKET_GUID: &'static [u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+static WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
 #[derive(Debug, PartialEq)]
 pub struct Nonce(String);
 
 impl Nonce {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Nonce {
         Nonce::generate(&mut rand::thread_rng())
     }
 
-    fn generate<R: Rng>(r: &mut R) -> Nonce {
-        let mut nonce = [0u8; 10];
-        r.fill_bytes(nonce.as_mut_slice());
+    fn generate<R: RngCore>(r: &mut R) -> Nonce {
+        let mut nonce = [0u8; 16];
+        r.fill_bytes(&mut nonce[..]);
         Nonce(nonce.to_base64(base64::STANDARD))
     }
 
     pub fn encode(self) -> Nonce {
-        let mut sha1 = Sha1::new();
-        sha1.write(self.0.as_bytes()).unwrap();
-        sha1.write(WEBSOCKET_GUID).unwrap();
-        Nonce(sha1.finish().to_base64(base64::STANDARD))
+        Nonce(compute_accept(&*self.0))
     }
 }
 
+/// Derive `Sec-WebSocket-Accept` header value from `Sec-WebSocket-Key`
+/// as described in RFC6455, section 4.2.2: base64 encoded SHA-1 of the key
+/// concatenated with magic GUID.
+pub fn compute_accept(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID);
+    sha1.digest().bytes().to_base64(base64::STANDARD)
+}
+
 impl Deref for Nonce {
     type Target = str;
-    fn deref<'a>(&'a self) -> &'a str {
+    fn deref(&self) -> &str {
         let Nonce(ref val) = *self;
         &**val
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Nonce, compute_accept};
+
+    #[test]
+    fn accept_matches_rfc_example() {
+        assert_eq!(compute_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn nonce_is_16_bytes_base64_encoded() {
+        let nonce = Nonce::new();
+        assert_eq!(nonce.len(), 24);
+        assert!(nonce.ends_with("=="));
+    }
+}
@@ -0,0 +1,45 @@
+//! Windows named pipe transport, for local services exposing WebSocket endpoint over a pipe.
+//!
+//! Pipe is opened with `PipeStream::open()` and passed to `WebSocket::connect_over()`,
+//! socket URL is only used for upgrade request, e.g. `ws://localhost/`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, self};
+
+use stream::Stream;
+
+pub struct PipeStream {
+    pipe: File
+}
+
+impl PipeStream {
+    /// Open pipe by its full path (`\\.\pipe\name`) or just by name
+    pub fn open(name: &str) -> io::Result<PipeStream> {
+        let path = if name.starts_with(r"\\") { name.to_string() } else { format!(r"\\.\pipe\{}", name) };
+        let pipe = OpenOptions::new().read(true).write(true).open(&*path)?;
+        Ok(PipeStream { pipe: pipe })
+    }
+
+    #[inline] pub fn get_ref(&self) -> &File {
+        &self.pipe
+    }
+}
+
+impl Read for PipeStream {
+    #[inline] fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.pipe.read(buf)
+    }
+}
+
+impl Write for PipeStream {
+    #[inline] fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pipe.write(buf)
+    }
+
+    #[inline] fn flush(&mut self) -> io::Result<()> {
+        self.pipe.flush()
+    }
+}
+
+// Pipes are local, so blocking reads and writes without timeouts are fine
+impl Stream for PipeStream {}
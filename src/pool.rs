@@ -0,0 +1,168 @@
+use std::io;
+use std::mem;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use socket::WebSocket;
+use connection::Connection;
+use message::WSMessage;
+use stream::time_left;
+use error::{WSError, WSResult};
+
+/// Keeps up to N connections alive, spread over one or several URLs.
+///
+/// Dead connections (failed to connect, failed to send or to pass health check)
+/// are dropped and replaced on the next `fill()` or `check()`.
+pub struct Pool {
+    factory: Box<dyn FnMut(usize) -> WebSocket + 'static>,
+    conns: Vec<Option<Connection>>,
+    next: usize,
+    // Data messages received while waiting for pongs, by connection slot
+    received: Vec<(usize, WSMessage)>
+}
+
+impl Pool {
+    /// Pool of `size` connections, i-th connection goes to `urls[i % urls.len()]`,
+    /// pool is empty if there are no URLs
+    pub fn new(urls: &[Url], size: usize) -> Pool {
+        let urls = urls.to_vec();
+        let size = if urls.is_empty() { 0 } else { size };
+        Pool::with_factory(size, move |i| WebSocket::new(urls[i % urls.len()].clone()))
+    }
+
+    /// Pool of `size` connections configured by `factory` called with connection slot number
+    pub fn with_factory<F>(size: usize, factory: F) -> Pool where F: FnMut(usize) -> WebSocket + 'static {
+        Pool {
+            factory: Box::new(factory),
+            conns: (0..size).map(|_| None).collect(),
+            next: 0,
+            received: Vec::new()
+        }
+    }
+
+    #[inline] pub fn size(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Number of currently alive connections
+    pub fn alive(&self) -> usize {
+        self.conns.iter().filter(|c| c.is_some()).count()
+    }
+
+    /// Open connections in all empty slots, returns number of alive connections
+    pub fn fill(&mut self) -> usize {
+        for i in 0..self.conns.len() {
+            if self.conns[i].is_none() {
+                self.conns[i] = (self.factory)(i).open().ok();
+            }
+        }
+        self.alive()
+    }
+
+    /// Ping all connections and wait up to `timeout` for each one to answer, drop those
+    /// which fail or stay silent (e.g. half-open ones), then refill the pool.
+    ///
+    /// Pings are answered and data messages received meanwhile are kept for `take_received()`.
+    pub fn check(&mut self, timeout: Duration) -> usize {
+        for i in 0..self.conns.len() {
+            let alive = match self.conns[i] {
+                Some(ref mut ws) => {
+                    let mut received = Vec::new();
+                    let alive = Pool::ping(ws, timeout, &mut received).is_ok();
+                    self.received.extend(received.into_iter().map(|msg| (i, msg)));
+                    alive
+                },
+                None => continue
+            };
+            if !alive {
+                self.conns[i] = None;
+            }
+        }
+        self.fill()
+    }
+
+    // Ping connection and wait for pong, read timeout set by user is restored afterwards
+    fn ping(ws: &mut Connection, timeout: Duration, received: &mut Vec<WSMessage>) -> WSResult<()> {
+        let read_timeout = ws.read_timeout();
+        let result = Pool::wait_pong(ws, Instant::now() + timeout, received);
+        ws.set_read_timeout(read_timeout)?;
+        result
+    }
+
+    fn wait_pong(ws: &mut Connection, deadline: Instant, received: &mut Vec<WSMessage>) -> WSResult<()> {
+        ws.send_message(&WSMessage::ping(b"pool").mask())?;
+
+        loop {
+            ws.set_read_timeout(time_left(Some(deadline))?)?;
+            let msg = ws.read_message()?;
+
+            if msg.is_pong() {
+                return Ok(());
+            } else if msg.is_ping() {
+                ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+            } else if msg.is_close() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+            } else {
+                received.push(msg);
+            }
+        }
+    }
+
+    /// Data messages received by `check()`, along with slot numbers of connections they came from
+    pub fn take_received(&mut self) -> Vec<(usize, WSMessage)> {
+        mem::take(&mut self.received)
+    }
+
+    /// Get next alive connection in round robin manner
+    pub fn get(&mut self) -> Option<&mut Connection> {
+        let size = self.conns.len();
+        match (0..size).map(|i| (self.next + i) % size).find(|&i| self.conns[i].is_some()) {
+            Some(i) => {
+                self.next = (i + 1) % size;
+                self.conns[i].as_mut()
+            },
+            None => None
+        }
+    }
+
+    /// Send message via next alive connection, dropping connections
+    /// which fail to send and retrying with others
+    pub fn send(&mut self, msg: &WSMessage) -> WSResult<()> {
+        let size = self.conns.len();
+        for _ in 0..size {
+            let i = self.next;
+            self.next = (i + 1) % size;
+
+            let result = match self.conns[i] {
+                Some(ref mut ws) => ws.send_message(msg),
+                None => continue
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(_) => self.conns[i] = None
+            }
+        }
+
+        Err(WSError::Io(io::Error::new(io::ErrorKind::NotConnected, "no alive connections in pool")))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Pool;
+    use message::WSMessage;
+
+    #[test]
+    fn pool_without_urls_is_empty() {
+        let mut pool = Pool::new(&[], 2);
+        assert_eq!(pool.size(), 0);
+        assert_eq!(pool.fill(), 0);
+        assert_eq!(pool.check(Duration::from_millis(10)), 0);
+        assert!(pool.get().is_none());
+        assert!(pool.send(&WSMessage::text("hello").mask()).is_err());
+    }
+}
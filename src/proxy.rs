@@ -0,0 +1,251 @@
+//! HTTP proxy support: connection to server is tunnelled through proxy with CONNECT request,
+//! TLS and WebSocket handshake are then done over the tunnel as usual.
+//!
+//! If proxy asks for credentials with 407 status, request is repeated once with them
+//! over a new connection, as proxies usually close it after the challenge.
+//! Basic and Digest (MD5, SHA-256 and their -sess variants) schemes are supported.
+
+use std::io::{Read, Write, self};
+use std::collections::BTreeMap;
+use openssl::hash::{hash, MessageDigest};
+use rustc_serialize::base64::{self, ToBase64};
+use rustc_serialize::hex::ToHex;
+use rand::{thread_rng, RngCore};
+
+use extension::split_unquoted;
+
+// Proxy response head larger than this is not parsed
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    address: String,
+    credentials: Option<(String, String)>
+}
+
+// Authentication scheme with its parameters, as given in Proxy-Authenticate header
+type Challenge = (String, BTreeMap<String, String>);
+
+impl Proxy {
+    /// Proxy listening on `address` ("host:port")
+    pub fn new(address: &str) -> Proxy {
+        Proxy {
+            address: address.to_string(),
+            credentials: None
+        }
+    }
+
+    /// Credentials to answer proxy authentication challenge with
+    pub fn credentials(mut self, user: &str, password: &str) -> Proxy {
+        self.credentials = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    #[inline] pub fn address(&self) -> &str {
+        &*self.address
+    }
+
+    /// Open tunnel to `target` ("host:port") over streams to proxy made by `connect`,
+    /// the second one is made only if proxy asks for credentials
+    pub fn open<S, F>(&self, target: &str, mut connect: F) -> io::Result<S> where S: Read + Write, F: FnMut() -> io::Result<S> {
+        let mut stream = connect()?;
+        let challenges = match tunnel(&mut stream, target, None)? {
+            None => return Ok(stream),
+            Some(challenges) => challenges
+        };
+
+        let (user, password) = match self.credentials {
+            Some((ref user, ref password)) => (user, password),
+            None => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "proxy authentication required"))
+        };
+        let auth = authorization(&challenges, user, password, target)?;
+
+        let mut stream = connect()?;
+        match tunnel(&mut stream, target, Some(&*auth))? {
+            None => Ok(stream),
+            Some(_) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "proxy authentication failed"))
+        }
+    }
+}
+
+// Send CONNECT request and read response, returns authentication challenges if proxy asks for them
+fn tunnel<S: Read + Write>(stream: &mut S, target: &str, auth: Option<&str>) -> io::Result<Option<Vec<Challenge>>> {
+    let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(auth) = auth {
+        req.push_str(&*format!("Proxy-Authorization: {}\r\n", auth));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes())?;
+    stream.flush()?;
+
+    let (status, challenges) = read_response(stream)?;
+    match status {
+        200..=299 => Ok(None),
+        407 => Ok(Some(challenges)),
+        _ => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "proxy refused to open tunnel"))
+    }
+}
+
+// Read response head byte by byte, as tunnelled data follows it right away
+fn read_response<S: Read>(stream: &mut S) -> io::Result<(u16, Vec<Challenge>)> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "proxy response is too large"));
+        }
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "proxy closed connection"));
+        }
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&*head);
+    let mut lines = head.split("\r\n");
+    let status = match lines.next().and_then(|line| line.split(' ').nth(1)).and_then(|s| s.parse::<u16>().ok()) {
+        Some(status) => status,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid proxy response status"))
+    };
+
+    let challenges = lines.filter_map(|line| {
+        let mut kv = line.splitn(2, ':');
+        match (kv.next(), kv.next()) {
+            (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("proxy-authenticate") => Some(parse_challenge(value.trim())),
+            _ => None
+        }
+    }).collect();
+
+    Ok((status, challenges))
+}
+
+// Split "Scheme key=value, key="quoted value"" into lowercased scheme and parameters
+fn parse_challenge(value: &str) -> Challenge {
+    let mut parts = value.splitn(2, ' ');
+    let scheme = parts.next().unwrap_or("").to_ascii_lowercase();
+    let params = split_unquoted(parts.next().unwrap_or(""), ',').into_iter().filter_map(|p| {
+        let mut kv = p.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => Some((key.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string())),
+            _ => None
+        }
+    }).collect();
+    (scheme, params)
+}
+
+// Proxy-Authorization value answering the strongest challenge we support
+fn authorization(challenges: &[Challenge], user: &str, password: &str, target: &str) -> io::Result<String> {
+    if let Some(&(_, ref params)) = challenges.iter().find(|&&(ref scheme, _)| scheme == "digest") {
+        let mut cnonce = [0u8; 8];
+        thread_rng().fill_bytes(&mut cnonce);
+        return digest(params, user, password, "CONNECT", target, &*cnonce.to_hex());
+    }
+
+    if challenges.iter().any(|&(ref scheme, _)| scheme == "basic") {
+        return Ok(format!("Basic {}", format!("{}:{}", user, password).as_bytes().to_base64(base64::STANDARD)));
+    }
+
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, "unsupported proxy authentication scheme"))
+}
+
+// Digest response as described in RFC 7616, request counter is always 1, as nonce is used once
+fn digest(params: &BTreeMap<String, String>, user: &str, password: &str, method: &str, uri: &str, cnonce: &str) -> io::Result<String> {
+    let unsupported = || io::Error::new(io::ErrorKind::PermissionDenied, "unsupported digest algorithm");
+    let algorithm = params.get("algorithm").map(|a| &**a).unwrap_or("MD5");
+    let (md, sess) = match &*algorithm.to_ascii_uppercase() {
+        "MD5" => (MessageDigest::md5(), false),
+        "MD5-SESS" => (MessageDigest::md5(), true),
+        "SHA-256" => (MessageDigest::sha256(), false),
+        "SHA-256-SESS" => (MessageDigest::sha256(), true),
+        _ => return Err(unsupported())
+    };
+    let h = |data: String| hash(md, data.as_bytes()).map(|d| d.to_hex()).map_err(|_| unsupported());
+
+    let empty = String::new();
+    let realm = params.get("realm").unwrap_or(&empty);
+    let nonce = params.get("nonce").unwrap_or(&empty);
+    let qop = params.get("qop").map(|q| q.split(',').any(|q| q.trim() == "auth"));
+
+    let mut ha1 = h(format!("{}:{}:{}", user, realm, password))?;
+    if sess {
+        ha1 = h(format!("{}:{}:{}", ha1, nonce, cnonce))?;
+    }
+    let ha2 = h(format!("{}:{}", method, uri))?;
+
+    let mut auth = format!("Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}", user, realm, nonce, uri, algorithm);
+    match qop {
+        Some(true) => {
+            let response = h(format!("{}:{}:00000001:{}:auth:{}", ha1, nonce, cnonce, ha2))?;
+            auth.push_str(&*format!(", qop=auth, nc=00000001, cnonce=\"{}\", response=\"{}\"", cnonce, response));
+        },
+        Some(false) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "unsupported digest qop")),
+        None => {
+            let response = h(format!("{}:{}:{}", ha1, nonce, ha2))?;
+            auth.push_str(&*format!(", response=\"{}\"", response));
+        }
+    }
+    if let Some(opaque) = params.get("opaque") {
+        auth.push_str(&*format!(", opaque=\"{}\"", opaque));
+    }
+
+    Ok(auth)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::{Proxy, parse_challenge, digest};
+
+    // Read request head, returning its lines
+    fn read_request(stream: &TcpStream) -> Vec<String> {
+        let mut reader = BufReader::new(stream);
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" || line.is_empty() {
+                return lines;
+            }
+            lines.push(line.trim_end().to_string());
+        }
+    }
+
+    #[test]
+    fn digest_response_matches_rfc() {
+        let (_, params) = parse_challenge("Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"");
+        let auth = digest(&params, "Mufasa", "Circle Of Life", "GET", "/dir/index.html", "0a4f113b").unwrap();
+        assert!(auth.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        assert!(auth.contains("opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""));
+        assert!(auth.contains("nc=00000001"));
+    }
+
+    #[test]
+    fn tunnel_is_retried_with_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let req = read_request(&stream);
+            assert_eq!(&*req[0], "CONNECT example.com:443 HTTP/1.1");
+            assert!(!req.iter().any(|l| l.starts_with("Proxy-Authorization")));
+            stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nConnection: close\r\n\r\n").unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let req = read_request(&stream);
+            assert!(req.iter().any(|l| l == "Proxy-Authorization: Basic dXNlcjpwYXNz"));
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello").unwrap();
+        });
+
+        let proxy = Proxy::new(&*addr.to_string()).credentials("user", "pass");
+        let mut stream = proxy.open("example.com:443", || TcpStream::connect(addr)).unwrap();
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+        assert_eq!(&*data, "hello");
+        server.join().unwrap();
+    }
+}
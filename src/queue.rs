@@ -0,0 +1,253 @@
+//! Outgoing frames queue with priorities.
+//!
+//! Control frames always go first, data frames go in order of priority (higher first),
+//! then in order they were queued. Large messages may be split into fragments when queued,
+//! so that control frames can be sent in between them, but fragments of different data
+//! messages are never interleaved, as it's not allowed by protocol.
+//!
+//! Messages may have time to live, they are dropped if they are not sent in time.
+//!
+//! Queue may be limited in number of data messages, with overflow handled as `Overflow` says.
+
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+
+use message::WSMessage;
+use clock::{Clock, SystemClock};
+
+/// What to do with a data message pushed into full queue
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overflow {
+    /// Give the message back, socket sends queued frames until there's room for it
+    Block,
+    /// Give the message back, socket reports error
+    Error,
+    /// Drop the oldest queued message (but never partially sent one) to make room
+    DropOldest,
+    /// Drop the new message
+    DropNewest
+}
+
+struct Outgoing {
+    priority: u8,
+    // Id of the message this frame belongs to, shared by all its fragments
+    message: u64,
+    expires: Option<Instant>,
+    frame: WSMessage
+}
+
+pub struct OutgoingQueue {
+    frames: Vec<Outgoing>,
+    next_message: u64,
+    fragment_size: Option<usize>,
+    // Fragmented message, which is partially sent
+    in_progress: Option<u64>,
+    on_expired: Option<Box<dyn FnMut(WSMessage)>>,
+    clock: Arc<dyn Clock>,
+    limit: Option<usize>,
+    overflow: Overflow
+}
+
+impl OutgoingQueue {
+    pub fn new() -> OutgoingQueue {
+        OutgoingQueue {
+            frames: Vec::new(),
+            next_message: 0,
+            fragment_size: None,
+            in_progress: None,
+            on_expired: None,
+            clock: Arc::new(SystemClock),
+            limit: None,
+            overflow: Overflow::Block
+        }
+    }
+
+    /// Split data messages larger than `size` into fragments when they are queued
+    #[inline] pub fn set_fragment_size(&mut self, size: Option<usize>) {
+        self.fragment_size = size;
+    }
+
+    /// Clock messages time to live is measured with
+    #[inline] pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    #[inline] pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    #[inline] pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Limit number of queued data messages (control frames are not limited),
+    /// `overflow` says what to do when the limit is reached
+    pub fn set_limit(&mut self, limit: Option<usize>, overflow: Overflow) {
+        self.limit = limit;
+        self.overflow = overflow;
+    }
+
+    #[inline] pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    /// Number of queued data messages, partially sent one included
+    pub fn messages(&self) -> usize {
+        self.frames.iter().filter(|f| !f.frame.is_control() && f.frame.is_final()).count()
+    }
+
+    #[inline] pub fn is_full(&self) -> bool {
+        self.limit.map(|limit| self.messages() >= limit).unwrap_or(false)
+    }
+
+    /// Call `callback` with every frame dropped because its time to live has passed
+    /// or because the queue has overflowed
+    pub fn set_expired_callback<F: FnMut(WSMessage) + 'static>(&mut self, callback: Option<F>) {
+        self.on_expired = callback.map(|f| Box::new(f) as Box<dyn FnMut(WSMessage)>);
+    }
+
+    fn push_frame(&mut self, frame: WSMessage, priority: u8, message: u64, expires: Option<Instant>) {
+        self.frames.push(Outgoing { priority: priority, message: message, expires: expires, frame: frame });
+    }
+
+    #[inline] pub fn push(&mut self, msg: WSMessage, priority: u8) -> Result<(), WSMessage> {
+        self.push_ttl(msg, priority, None)
+    }
+
+    /// Queue message, which is dropped if it's not sent within `ttl`,
+    /// message is given back if queue is full and overflow policy doesn't let to drop anything
+    pub fn push_ttl(&mut self, msg: WSMessage, priority: u8, ttl: Option<Duration>) -> Result<(), WSMessage> {
+        if !msg.is_control() && self.is_full() {
+            self.drop_expired();
+        }
+
+        if !msg.is_control() && self.is_full() {
+            match self.overflow {
+                Overflow::Block | Overflow::Error => return Err(msg),
+                Overflow::DropNewest => {
+                    self.dropped(msg);
+                    return Ok(());
+                },
+                Overflow::DropOldest => if !self.drop_oldest() {
+                    return Err(msg);
+                }
+            }
+        }
+
+        let message = self.next_message;
+        self.next_message += 1;
+        let expires = ttl.map(|ttl| self.clock.now() + ttl);
+
+        match self.fragment_size {
+            Some(size) if !msg.is_control() && msg.data.len() > size => {
+                for fragment in msg.split(size) {
+                    self.push_frame(fragment, priority, message, expires);
+                }
+            },
+            _ => self.push_frame(msg, priority, message, expires)
+        }
+        Ok(())
+    }
+
+    fn dropped(&mut self, frame: WSMessage) {
+        if let Some(ref mut callback) = self.on_expired {
+            callback(frame);
+        }
+    }
+
+    // Drop all frames of the oldest data message, which is not partially sent
+    fn drop_oldest(&mut self) -> bool {
+        let in_progress = self.in_progress;
+        let message = match self.frames.iter().find(|f| !f.frame.is_control() && Some(f.message) != in_progress) {
+            Some(f) => f.message,
+            None => return false
+        };
+
+        let mut i = 0;
+        while i < self.frames.len() {
+            if self.frames[i].message == message {
+                let frame = self.frames.remove(i).frame;
+                self.dropped(frame);
+            } else {
+                i += 1;
+            }
+        }
+        true
+    }
+
+    // Drop frames waiting for too long, but never the rest of partially sent message,
+    // as the peer would never get the end of it
+    fn drop_expired(&mut self) {
+        let now = self.clock.now();
+        let in_progress = self.in_progress;
+
+        let mut i = 0;
+        while i < self.frames.len() {
+            let expired = {
+                let f = &self.frames[i];
+                f.expires.map(|t| t <= now).unwrap_or(false) && Some(f.message) != in_progress
+            };
+
+            if expired {
+                let frame = self.frames.remove(i).frame;
+                self.dropped(frame);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // Index of the frame to go next
+    fn next(&self) -> Option<usize> {
+        let frames = &self.frames;
+
+        // Frames are kept in order they were queued, so the first match is the oldest one
+        if let Some(i) = frames.iter().position(|f| f.frame.is_control()) {
+            return Some(i);
+        }
+
+        if let Some(message) = self.in_progress {
+            if let Some(i) = frames.iter().position(|f| f.message == message) {
+                return Some(i);
+            }
+        }
+
+        let mut best: Option<usize> = None;
+        for (i, f) in frames.iter().enumerate() {
+            if best.map(|b| f.priority > frames[b].priority).unwrap_or(true) {
+                best = Some(i);
+            }
+        }
+        best
+    }
+
+    /// Take the next frame to be sent
+    pub fn pop(&mut self) -> Option<WSMessage> {
+        self.drop_expired();
+        self.next().map(|i| {
+            let out = self.frames.remove(i);
+            if !out.frame.is_control() {
+                self.in_progress = if out.frame.is_final() { None } else { Some(out.message) };
+            }
+            out.frame
+        })
+    }
+
+    /// Take the oldest queued control frame, if any, leaving data frames in place
+    pub fn pop_control(&mut self) -> Option<WSMessage> {
+        self.drop_expired();
+        self.frames.iter().position(|f| f.frame.is_control()).map(|i| self.frames.remove(i).frame)
+    }
+
+    /// Drop all queued frames
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.in_progress = None;
+    }
+}
+
+impl Default for OutgoingQueue {
+    #[inline] fn default() -> OutgoingQueue {
+        OutgoingQueue::new()
+    }
+}
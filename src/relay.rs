@@ -0,0 +1,168 @@
+//! Pumping frames between two connections, the core of WebSocket proxies,
+//! or between connection and plain reader and writer, the core of CLI tools.
+
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::time::Duration;
+use std::thread;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+use connection::Connection;
+use stream::Stream;
+use message::{WSMessage, WSStatusCode, WS_MASK, WS_OPCONT};
+use error::{WSError, WSResult};
+
+// How long to wait for a frame on one side before checking the other one
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Forward one frame from `from` to `to` if it arrives within poll interval,
+/// returns false once close frame is forwarded
+fn pump<S: Stream>(from: &mut Connection<S>, to: &mut Connection<S>) -> WSResult<bool> {
+    let msg = match from.read_message() {
+        Ok(msg) => msg,
+        Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(true),
+        Err(e) => return Err(e)
+    };
+
+    // Pings and pongs are hop-by-hop, answer them here
+    if msg.is_ping() {
+        from.send_message(&WSMessage::pong(&*msg.data).mask())?;
+        return Ok(true);
+    } else if msg.is_pong() {
+        return Ok(true);
+    }
+
+    // Frames are passed as is, fragmentation included, but masked again with our own key
+    to.send_data(msg.header | WS_MASK, msg.status, &*msg.data)?;
+
+    if msg.is_close() {
+        // Let closing side finish its closing handshake
+        from.send_message(&WSMessage::close(msg.status.unwrap_or(WSStatusCode::NoError), b"").mask())?;
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Relay frames between two connections in both directions until either of them closes,
+/// close frame is propagated to the other side. Read timeouts are restored afterwards
+pub fn relay<S: Stream>(a: &mut Connection<S>, b: &mut Connection<S>) -> WSResult<()> {
+    let (a_timeout, b_timeout) = (a.read_timeout(), b.read_timeout());
+    let interval = Some(Duration::from_millis(POLL_INTERVAL_MS));
+    a.set_read_timeout(interval)?;
+    b.set_read_timeout(interval)?;
+
+    let mut result = Ok(true);
+    while let Ok(true) = result {
+        result = pump(a, b).and_then(|open| if open { pump(b, a) } else { Ok(false) });
+    }
+
+    let _ = a.set_read_timeout(a_timeout);
+    let _ = b.set_read_timeout(b_timeout);
+    result.map(|_| ())
+}
+
+// Send lines read so far, returns false once input is over and close is sent
+fn pump_input<S: Stream>(ws: &mut Connection<S>, lines: &Receiver<io::Result<String>>) -> WSResult<bool> {
+    loop {
+        match lines.try_recv() {
+            Ok(line) => ws.send_message(&WSMessage::text(&*line?).mask())?,
+            Err(TryRecvError::Empty) => return Ok(true),
+            Err(TryRecvError::Disconnected) => {
+                ws.close_send(WSStatusCode::NoError, b"")?;
+                return Ok(false);
+            }
+        }
+    }
+}
+
+fn pump_lines<S: Stream, W: Write>(ws: &mut Connection<S>, lines: Receiver<io::Result<String>>, output: &mut W) -> WSResult<()> {
+    let mut input_open = true;
+    // Whether message being received is a text one, to end it with newline
+    let mut text = false;
+
+    loop {
+        if input_open {
+            input_open = pump_input(ws, &lines)?;
+        }
+
+        let msg = match ws.read_message() {
+            Ok(msg) => msg,
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e)
+        };
+
+        if msg.is_ping() {
+            // Nothing can be sent after our close, peer will get it soon enough
+            if input_open {
+                ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+            }
+        } else if msg.is_close() {
+            if input_open {
+                let _ = ws.send_message(&WSMessage::close(msg.status.unwrap_or(WSStatusCode::NoError), b"").mask());
+            }
+            return Ok(());
+        } else if !msg.is_pong() {
+            if msg.opcode() != WS_OPCONT {
+                text = msg.is_text();
+            }
+            output.write_all(&*msg.data)?;
+            if text && msg.is_final() {
+                output.write_all(b"\n")?;
+            }
+            output.flush()?;
+        }
+    }
+}
+
+/// Send lines of `input` as text messages and write payloads of incoming messages to `output`
+/// (text ones followed by newline) until connection is closed. When input is over,
+/// connection is closed, and incoming messages are written out until peer's close arrives.
+///
+/// Input is read in a separate thread, which is left blocked reading, if connection is closed
+/// by peer before input is over. Read timeout is restored afterwards.
+pub fn pipe<S, R, W>(ws: &mut Connection<S>, input: R, output: &mut W) -> WSResult<()> where S: Stream, R: Read + Send + 'static, W: Write {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for line in BufReader::new(input).lines() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let read_timeout = ws.read_timeout();
+    ws.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+    let result = pump_lines(ws, rx, output);
+    let _ = ws.set_read_timeout(read_timeout);
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use mock;
+    use socket::WebSocket;
+    use super::relay;
+
+    #[test]
+    fn frames_are_relayed_until_close() {
+        let (a_stream, a_server) = mock::pair();
+        let (b_stream, b_server) = mock::pair();
+        let url = Url::parse("ws://localhost/").unwrap();
+        let mut a = WebSocket::with_transport(url.clone(), 13, None, None).open_over(a_stream).ok().unwrap();
+        let mut b = WebSocket::with_transport(url, 13, None, None).open_over(b_stream).ok().unwrap();
+
+        a_server.push(&*mock::frame(0x81, b"hi"));
+        a_server.push(&*mock::frame(0x88, b"\x03\xe8"));
+        relay(&mut a, &mut b).unwrap();
+
+        // Text and close frames forwarded to b, masked, and close answered on a
+        let to_b = b_server.written();
+        assert_eq!((to_b[0], to_b[1]), (0x81, 0x82));
+        assert_eq!((to_b[8], to_b[9]), (0x88, 0x82));
+        assert_eq!(&a_server.written()[..2], &[0x88, 0x82]);
+        assert_eq!(a.read_timeout(), None);
+    }
+}
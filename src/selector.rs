@@ -0,0 +1,200 @@
+//! Consuming many connections in a single thread.
+//!
+//! Connections are polled in turn with short read timeouts, so that one idle connection
+//! doesn't block the others. Read timeouts sockets had are restored when they are removed.
+
+use std::io;
+use std::time::Duration;
+
+use socket::WebSocket;
+use stream::{Stream, NetworkStream};
+use message::{WSMessage, WSStatusCode};
+use error::{WSError, WSResult};
+
+// How long to wait for a frame on one connection before checking the next one
+const POLL_INTERVAL_MS: u64 = 1;
+
+pub enum WSEvent<S = NetworkStream> {
+    Message(WSMessage),
+    /// Connection is closed by peer (its close frame is answered) and removed from selector
+    Closed(WebSocket<S>),
+    /// Connection failed and is removed from selector
+    Error(WebSocket<S>, WSError)
+}
+
+pub struct Selector<S = NetworkStream> {
+    // Sockets along with read timeouts they had before they were added
+    sockets: Vec<Option<(WebSocket<S>, Option<Duration>)>>,
+    next: usize
+}
+
+impl Selector {
+    pub fn new() -> Selector {
+        Selector::with_transport()
+    }
+}
+
+impl<S: Stream> Selector<S> {
+    /// Selector of sockets running over custom transport
+    pub fn with_transport() -> Selector<S> {
+        Selector { sockets: Vec::new(), next: 0 }
+    }
+
+    /// Add connected socket, returns index its events are reported with
+    pub fn add(&mut self, mut ws: WebSocket<S>) -> io::Result<usize> {
+        let read_timeout = ws.read_timeout();
+        ws.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+
+        match self.sockets.iter().position(|s| s.is_none()) {
+            Some(index) => {
+                self.sockets[index] = Some((ws, read_timeout));
+                Ok(index)
+            },
+            None => {
+                self.sockets.push(Some((ws, read_timeout)));
+                Ok(self.sockets.len() - 1)
+            }
+        }
+    }
+
+    /// Take socket out of selector, index may be reused by following `add()`
+    pub fn remove(&mut self, index: usize) -> Option<WebSocket<S>> {
+        let slot = self.sockets.get_mut(index).and_then(|s| s.take());
+        slot.map(|(mut ws, read_timeout)| {
+            let _ = ws.set_read_timeout(read_timeout);
+            ws
+        })
+    }
+
+    #[inline] pub fn get_mut(&mut self, index: usize) -> Option<&mut WebSocket<S>> {
+        self.sockets.get_mut(index).and_then(|s| s.as_mut()).map(|s| &mut s.0)
+    }
+
+    /// Send message to socket with given index
+    pub fn send_message(&mut self, index: usize, msg: &WSMessage) -> WSResult<()> {
+        match self.get_mut(index) {
+            Some(ws) => ws.send_message(msg),
+            None => Err(WSError::Io(io::Error::new(io::ErrorKind::NotFound, "no socket with given index")))
+        }
+    }
+
+    /// Number of sockets in selector
+    pub fn len(&self) -> usize {
+        self.sockets.iter().filter(|s| s.is_some()).count()
+    }
+
+    #[inline] pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait for next event on any of sockets, pings are answered on the way,
+    /// paused sockets are skipped, and if all of them are paused, `WouldBlock` error is returned
+    pub fn select(&mut self) -> WSResult<(usize, WSEvent<S>)> {
+        if self.is_empty() {
+            return Err(WSError::InvalidInput("no sockets to select from"));
+        }
+
+        loop {
+            if self.sockets.iter().flatten().all(|&(ref ws, _)| ws.is_paused()) {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::WouldBlock, "all sockets are paused")));
+            }
+
+            let index = self.next;
+            self.next = (self.next + 1) % self.sockets.len();
+
+            let result = match self.sockets[index] {
+                Some((ref mut ws, _)) if !ws.is_paused() => ws.read_message(),
+                _ => continue
+            };
+
+            match result {
+                Ok(msg) => {
+                    if msg.is_ping() {
+                        if let Some((ref mut ws, _)) = self.sockets[index] {
+                            let _ = ws.send_message(&WSMessage::pong(&*msg.data).mask());
+                        }
+                    } else if msg.is_close() {
+                        if let Some((ref mut ws, _)) = self.sockets[index] {
+                            let _ = ws.send_message(&WSMessage::close(msg.status.unwrap_or(WSStatusCode::NoError), b"").mask());
+                        }
+                        return Ok((index, WSEvent::Closed(self.remove(index).unwrap())));
+                    } else {
+                        return Ok((index, WSEvent::Message(msg)));
+                    }
+                },
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => return Ok((index, WSEvent::Error(self.remove(index).unwrap(), e)))
+            }
+        }
+    }
+}
+
+impl Default for Selector {
+    #[inline] fn default() -> Selector {
+        Selector::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use mock;
+    use error::WSError;
+    use super::{Selector, WSEvent};
+
+    #[test]
+    fn read_timeout_is_restored_on_remove() {
+        let (mut ws, server) = mock::connect();
+        ws.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut selector = Selector::with_transport();
+        let index = selector.add(ws).unwrap();
+        let ws = selector.remove(index).unwrap();
+
+        assert_eq!(ws.read_timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(server.read_timeouts().last(), Some(&Some(Duration::from_secs(5))));
+    }
+
+    #[test]
+    fn messages_are_selected_from_any_socket() {
+        let (first, _) = mock::connect();
+        let (second, server) = mock::connect();
+        server.push(&*mock::frame(0x81, b"hi"));
+
+        let mut selector = Selector::with_transport();
+        selector.add(first).unwrap();
+        let index = selector.add(second).unwrap();
+
+        match selector.select().unwrap() {
+            (i, WSEvent::Message(ref msg)) if i == index => assert_eq!(msg.as_text().unwrap(), "hi"),
+            _ => panic!("expected message from the second socket")
+        }
+    }
+
+    #[test]
+    fn paused_sockets_are_skipped() {
+        let (mut first, first_server) = mock::connect();
+        let (second, second_server) = mock::connect();
+        first.pause();
+        first_server.push(&*mock::frame(0x81, b"later"));
+        second_server.push(&*mock::frame(0x81, b"now"));
+
+        let mut selector = Selector::with_transport();
+        selector.add(first).unwrap();
+        let index = selector.add(second).unwrap();
+
+        match selector.select().unwrap() {
+            (i, WSEvent::Message(ref msg)) if i == index => assert_eq!(msg.as_text().unwrap(), "now"),
+            _ => panic!("expected message from the second socket")
+        }
+
+        selector.get_mut(index).unwrap().pause();
+        match selector.select() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+            _ => panic!("expected all sockets to be paused")
+        }
+    }
+}
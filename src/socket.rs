@@ -1,299 +1,1987 @@
-use std::io::{Read, Write, BufRead, BufStream, self};
+use std::io::{Read, Write, BufRead, Seek, SeekFrom, self};
+use std::fs::File;
 use std::mem;
+use std::cmp;
+use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::u16;
-use std::num::{Int, FromPrimitive, ToPrimitive};
-use std::slice::SliceConcatExt;
-use url::Url;
-use rand::{thread_rng, Rng};
+use std::time::{Duration, Instant};
+use std::net::{SocketAddr, Shutdown};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+use url::{Url, Position};
+use rand::{thread_rng, Rng, RngCore};
 
 use nonce::Nonce;
-use message::{WSMessage, WSHeader, WS_MASK, WS_LEN, WS_LEN16, WS_LEN64, WS_OPTERM};
-use stream::NetworkStream;
+use message::{check_close, status_code, WSMessage, WSMessageRef, WSSharedMessage, WSTimestamps, WSStatusCode, WSHeader, WS_FIN, WS_RSV, WS_OPCTRL, WS_OPTEXT, WS_OPBIN, WS_MASK, WS_OPCODE, WS_LEN, WS_LEN16, WS_LEN64, WS_OPTERM};
+use frame::{FrameHeaderBuilder, frame_size, pack_be_u16, unpack_be, read_full, read_upto, mask_in_place};
+use stream::{Stream, NetworkStream, BufferedStream, TcpOptions, TcpInfo, time_left};
+use legacy;
+use throttle::Throttle;
+use clock::{Clock, SystemClock};
+use error::{WSError, WSResult, HandshakeError};
+use buffer::{BufferPool, HeapPool, FixedPool};
+use tls::TlsConfig;
+use proxy::Proxy;
+use wake::WakeHandle;
+use queue::{OutgoingQueue, Overflow};
+use extension::{self, Extension};
 
 
+#[allow(clippy::type_complexity)]
 pub struct WebSocket<S = NetworkStream> {
-    stream: Option<BufStream<S>>,
-    pub url: Url,
+    id: usize,
+    stream: Option<BufferedStream<S>>,
+    endpoint: Endpoint,
     hostname: String,
+    endpoints: Vec<Endpoint>,
+    active_endpoint: Option<usize>,
     use_ssl: bool,
     version: u32,
     extensions: Option<Vec<String>>,
-    protocols: Option<Vec<String>>
+    // Extensions transforming frames, and indices of negotiated ones in server's order
+    codecs: Vec<Box<dyn Extension>>,
+    active_codecs: Vec<usize>,
+    // Data frames being sent may be compressed by extensions
+    compress: bool,
+    protocols: Option<Vec<String>>,
+    version_fallback: bool,
+    offered_versions: Option<Vec<u32>>,
+    legacy: bool,
+    throttle: Option<Throttle>,
+    clock: Arc<dyn Clock>,
+    restart_delay: Option<Duration>,
+    max_restart_delay: Duration,
+    handshake_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    idle_ping: bool,
+    timeouts: (Option<Duration>, Option<Duration>),
+    read_timeout: Option<Duration>,
+    last_read: Instant,
+    idle_pinged: bool,
+    partial: Option<PartialFrame>,
+    closing: bool,
+    // Close frame is sent, RFC 6455 allows only one
+    close_sent: bool,
+    drained: Option<usize>,
+    drain_limit: Option<usize>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    buffers: Box<dyn BufferPool>,
+    auto_flush: bool,
+    negotiated_protocol: Option<String>,
+    negotiated_extensions: Vec<String>,
+    tls: TlsConfig,
+    proxy: Option<Proxy>,
+    tcp: TcpOptions,
+    wake: WakeHandle,
+    wake_enabled: bool,
+    progress: Option<Box<dyn FnMut(Direction, u64, Option<u64>)>>,
+    sent_progress: u64,
+    recv_progress: u64,
+    outgoing: OutgoingQueue,
+    max_frame_size: Option<usize>,
+    path: Option<String>,
+    paused: bool,
+    headers: Vec<(String, String)>,
+    request_hook: Option<Box<dyn FnMut(&mut Vec<u8>)>>,
+    response_hook: Option<Box<dyn FnMut(Option<u16>, &BTreeMap<String, String>, bool) -> bool>>
+}
+
+/// Direction of data transfer reported to progress callback
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received
+}
+
+// Max size of frame header: 2 bytes, 64-bit length and mask
+const MAX_HEAD: usize = 14;
+
+// Frame being received: header and payload bytes read so far, it's kept
+// when read is interrupted by timeout or wake up, and the next read resumes it
+struct PartialFrame {
+    head: [u8; MAX_HEAD],
+    head_read: usize,
+    data: Vec<u8>,
+    data_read: usize,
+    reported: usize,
+    first_byte: Option<Instant>
+}
+
+impl PartialFrame {
+    fn new() -> PartialFrame {
+        PartialFrame { head: [0u8; MAX_HEAD], head_read: 0, data: Vec::new(), data_read: 0, reported: 0, first_byte: None }
+    }
+}
+
+// Errors after which partially read frame can be read further
+#[inline] fn is_resumable(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted)
+}
+
+// Max size of rejected handshake response body to keep
+const MAX_REJECT_BODY: usize = 64 * 1024;
+
+// Size of chunks payload is read and written in when progress is reported
+const PROGRESS_CHUNK: usize = 64 * 1024;
+
+// Files which can't be sent directly are read by chunks of this size
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Most bytes passed to a single sendfile() call
+#[cfg(target_os = "linux")]
+const SENDFILE_CHUNK: u64 = 1024 * 1024;
+
+// Longest reconnection delay server may ask for by default, in seconds
+const DEFAULT_MAX_RESTART_DELAY: u64 = 300;
+
+// How often blocked read checks if it's woken up
+const WAKE_POLL_MS: u64 = 50;
+
+// Sent unless overridden, as some servers reject clients without one
+const DEFAULT_USER_AGENT: &str = concat!("bare-websocket/", env!("CARGO_PKG_VERSION"));
+
+// Source of unique connection ids
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Protocol versions we can fall back to
+static SUPPORTED_VERSIONS: &[u32] = &[13, 8];
+
+#[inline] fn default_port(use_ssl: bool) -> u16 {
+    if use_ssl { 443 } else { 80 }
+}
+
+// IPv6 literals are put in brackets, so that port can be appended
+fn bracket_host(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+/// Server to connect to: either URL, or host, port and request path taken as they are,
+/// without URL parsing, escaping and normalization
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Url(Url),
+    Target { host: String, port: u16, path: String, secure: bool }
+}
+
+impl Endpoint {
+    #[inline] pub fn is_secure(&self) -> bool {
+        match *self {
+            Endpoint::Url(ref url) => url.scheme() == "wss",
+            Endpoint::Target { secure, .. } => secure
+        }
+    }
+
+    // Host, IPv6 literal in brackets, and port
+    fn host_port(&self) -> (String, u16) {
+        match *self {
+            Endpoint::Url(ref url) => (bracket_host(url.host_str().unwrap_or("")), url.port().unwrap_or(default_port(self.is_secure()))),
+            Endpoint::Target { ref host, port, .. } => (bracket_host(host), port)
+        }
+    }
+
+    // Address to connect to
+    fn address(&self) -> String {
+        let (host, port) = self.host_port();
+        format!("{}:{}", host, port)
+    }
+
+    // Value of Host header: port is only given if it's not the default one
+    fn host_header(&self) -> String {
+        match self.host_port() {
+            (host, port) if port == default_port(self.is_secure()) => host,
+            (host, port) => format!("{}:{}", host, port)
+        }
+    }
+
+    // Request path with query
+    fn path(&self) -> &str {
+        match *self {
+            Endpoint::Url(ref url) => &url[Position::BeforePath..Position::AfterQuery],
+            Endpoint::Target { ref path, .. } => &**path
+        }
+    }
+
+    // Default Origin header: the endpoint itself
+    fn origin(&self) -> String {
+        match *self {
+            Endpoint::Url(ref url) => url[..Position::AfterQuery].to_string(),
+            Endpoint::Target { ref path, secure, .. } => format!("{}://{}{}", if secure { "wss" } else { "ws" }, self.host_header(), path)
+        }
+    }
+}
+
+// Headers written by handshake itself, which must not be overridden
+fn is_reserved_header(name: &str) -> bool {
+    ["host", "upgrade", "connection"].iter().any(|h| name.eq_ignore_ascii_case(h))
+        || name.len() >= 14 && name[..14].eq_ignore_ascii_case("sec-websocket-")
 }
 
 impl WebSocket {
-    pub fn with_options(url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket {
-        let use_ssl = &*url.scheme == "wss";
+    #[inline] pub fn with_options(url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket {
+        WebSocket::with_transport(url, version, protocols, extensions)
+    }
+
+    #[inline] pub fn new(url: Url) -> WebSocket {
+        WebSocket::with_options(url, 1, None, None)
+    }
+
+    /// Socket for given host, port and request path, which are used as is, `Url` is not involved:
+    /// host is neither normalized nor IDNA encoded, path is not escaped. IPv6 address may be given
+    /// with or without brackets. Host and path must not contain whitespace or control characters
+    pub fn with_target(host: &str, port: u16, path: &str, secure: bool) -> WSResult<WebSocket> {
+        if host.is_empty() || host.bytes().any(|b| b <= b' ' || b == 0x7f || b == b'/') {
+            return Err(WSError::InvalidInput("invalid host"));
+        }
+        if path.bytes().any(|b| b <= b' ' || b == 0x7f) {
+            return Err(WSError::InvalidInput("invalid path"));
+        }
+
+        let path = if path.is_empty() { "/" } else { path };
+        let endpoint = Endpoint::Target { host: host.to_string(), port: port, path: path.to_string(), secure: secure };
+        Ok(WebSocket::with_endpoint(endpoint, 1, None, None))
+    }
+
+    /// Connect to given host and port with request path sent as is, see `with_target()`
+    pub fn connect_to(host: &str, port: u16, path: &str, secure: bool) -> WSResult<WebSocket> {
+        let mut ws = WebSocket::with_target(host, port, path, secure)?;
+        ws.connect()?;
+        Ok(ws)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.stream {
+            Some(ref s) => s.get_ref().peer_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.stream {
+            Some(ref s) => s.get_ref().local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
+        }
+    }
+
+    /// Kernel statistics of TCP connection (RTT, retransmits, congestion window), Linux only
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        match self.stream {
+            Some(ref s) => s.get_ref().tcp_info(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
+        }
+    }
+
+    /// Send file contents from its current position to the end as a single message, returns
+    /// number of bytes sent. Unmasked frames over plain TCP are sent with `sendfile()` on Linux,
+    /// without copying file through userspace, otherwise file is sent with `send_stream()`
+    pub(crate) fn send_file(&mut self, header: WSHeader, file: &mut File) -> WSResult<u64> {
+        if header.contains(WS_OPCTRL) {
+            return Err(WSError::InvalidInput("control frames can't be sent from file"));
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let start = file.stream_position()?;
+            let len = file.metadata()?.len().saturating_sub(start);
+            if let Some(fd) = self.direct_fd(header, len) {
+                return self.sendfile(fd, header, file, start, len);
+            }
+        }
+
+        self.send_stream(header, file, FILE_CHUNK_SIZE)
+    }
+
+    // Socket file can be sent to directly: plain TCP, and frame goes out as is in one piece
+    #[cfg(target_os = "linux")]
+    fn direct_fd(&self, header: WSHeader, len: u64) -> Option<RawFd> {
+        if header.contains(WS_MASK) || self.legacy || !self.active_codecs.is_empty() || self.progress.is_some()
+            || self.max_frame_size.is_some_and(|limit| len > limit as u64) {
+            return None;
+        }
+
+        match self.stream {
+            Some(ref s) => match *s.get_ref() {
+                NetworkStream::Tcp(ref tcp) => Some(tcp.as_raw_fd()),
+                NetworkStream::Ssl(_) => None
+            },
+            None => None
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sendfile(&mut self, fd: RawFd, header: WSHeader, file: &mut File, start: u64, len: u64) -> WSResult<u64> {
+        if self.closing {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "connection is shutting down")));
+        }
+
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.wait(len);
+        }
+
+        // Buffered data must go out before file contents
+        let frame = FrameHeaderBuilder::from_header(header | WS_FIN).len(len);
+        self.io().write_all(&*frame.to_bytes())?;
+        self.io().flush()?;
+
+        let end = start + len;
+        let mut offset = start as libc::off_t;
+        while (offset as u64) < end {
+            let count = cmp::min(end - offset as u64, SENDFILE_CHUNK) as usize;
+            let sent = unsafe { libc::sendfile(fd, file.as_raw_fd(), &mut offset, count) };
+            if sent < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(WSError::Io(e));
+            }
+            if sent == 0 {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "file is truncated while being sent")));
+            }
+        }
+
+        file.seek(SeekFrom::Start(end))?;
+        Ok(len)
+    }
+}
+
+impl<S: Stream> WebSocket<S> {
+    /// Socket running over custom transport, it's either connected by hostname with `Stream::connect()`,
+    /// or given ready to `connect_over()`
+    #[inline] pub fn with_transport(url: Url, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket<S> {
+        WebSocket::with_endpoint(Endpoint::Url(url), version, protocols, extensions)
+    }
+
+    fn with_endpoint(endpoint: Endpoint, version: u32, protocols: Option<&[&str]>, extensions: Option<&[&str]>) -> WebSocket<S> {
+        WebSocket {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            stream: None,
+            hostname: endpoint.address(),
+            endpoints: vec![endpoint.clone()],
+            active_endpoint: None,
+            use_ssl: endpoint.is_secure(),
+            endpoint: endpoint,
+            version: version,
+            extensions: extensions.map(|v| v.iter().map(|v| v.to_string()).collect()),
+            codecs: Vec::new(),
+            active_codecs: Vec::new(),
+            compress: true,
+            protocols: protocols.map(|v| v.iter().map(|v| v.to_string()).collect()),
+            version_fallback: false,
+            offered_versions: None,
+            legacy: false,
+            throttle: None,
+            clock: Arc::new(SystemClock),
+            restart_delay: None,
+            max_restart_delay: Duration::from_secs(DEFAULT_MAX_RESTART_DELAY),
+            handshake_timeout: None,
+            idle_timeout: None,
+            idle_ping: false,
+            timeouts: (None, None),
+            read_timeout: None,
+            last_read: Instant::now(),
+            idle_pinged: false,
+            partial: None,
+            closing: false,
+            close_sent: false,
+            drained: None,
+            drain_limit: None,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            buffers: Box::new(HeapPool),
+            auto_flush: true,
+            negotiated_protocol: None,
+            negotiated_extensions: Vec::new(),
+            tls: TlsConfig::new(),
+            proxy: None,
+            tcp: TcpOptions::new(),
+            wake: WakeHandle::new(),
+            wake_enabled: false,
+            progress: None,
+            sent_progress: 0,
+            recv_progress: 0,
+            outgoing: OutgoingQueue::new(),
+            max_frame_size: None,
+            path: None,
+            paused: false,
+            headers: vec![("User-Agent".to_string(), DEFAULT_USER_AGENT.to_string())],
+            request_hook: None,
+            response_hook: None
+        }
+    }
+
+    /// Id unique among all sockets created by this process, it stays the same on reconnection,
+    /// so it can be used to tell connections apart in logs
+    #[inline] pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn try_connect(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        let stream = {
+            let tls = if self.use_ssl { Some(&self.tls) } else { None };
+            (<S as Stream>::connect_with(&*self.hostname, self.proxy.as_ref(), &self.tcp, tls, time_left(deadline)?))?
+        };
+        self.wake.set_stream(stream.try_clone_tcp().ok());
+        self.stream = Some(BufferedStream::new(stream));
+        Ok(())
+    }
+
+    // Limit both reads and writes by time left till deadline
+    fn set_deadline(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        let left = time_left(deadline)?;
+        self.set_stream_timeout(left, left)
+    }
+
+    // Timeouts in effect are remembered, so that they can be restored after temporary changes
+    fn set_stream_timeout(&mut self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        match self.stream {
+            Some(ref s) => {
+                s.get_ref().set_read_timeout(read)?;
+                s.get_ref().set_write_timeout(write)?;
+                self.timeouts = (read, write);
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
+        }
+    }
+
+    fn request_path(&self) -> String {
+        match self.path {
+            Some(ref path) => path.clone(),
+            None => match self.endpoint.path() {
+                "" => "/".to_string(),
+                path => path.to_string()
+            }
+        }
+    }
+
+    fn write_request(&mut self, nonce: &str) -> io::Result<()> {
+        let mut req = Vec::new();
+
+        write!(&mut req, "GET {} HTTP/1.1\r\n", self.request_path())?;
+        write!(&mut req, "Host: {}\r\n", self.endpoint.host_header())?;
+        if self.header("origin").is_none() {
+            write!(&mut req, "Origin: {}\r\n", self.endpoint.origin())?;
+        }
+        write!(&mut req, "Sec-WebSocket-Key: {}\r\n", nonce)?;
+
+        req.extend_from_slice(b"Upgrade: websocket\r\n");
+        req.extend_from_slice(b"Connection: Upgrade\r\n");
+        write!(&mut req, "Sec-WebSocket-Version: {}\r\n", self.version)?;
+        if let Some(ref protos) = self.protocols {
+            write!(&mut req, "Sec-WebSocket-Protocol: {}\r\n", protos.join(", "))?;
+        }
+        let mut exts = self.extensions.clone().unwrap_or_default();
+        exts.extend(self.codecs.iter().map(|ext| ext.offer()));
+        if !exts.is_empty() {
+            write!(&mut req, "Sec-WebSocket-Extensions: {}\r\n", exts.join(", "))?;
+        }
+        for &(ref name, ref value) in self.headers.iter() {
+            write!(&mut req, "{}: {}\r\n", name, value)?;
+        }
+        req.extend_from_slice(b"\r\n");
+
+        if let Some(ref mut hook) = self.request_hook {
+            hook(&mut req);
+        }
+
+        let s = match self.stream { Some(ref mut s) => s, None => return Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected")) };
+        s.write_all(&*req)?;
+        s.flush()
+    }
+
+    fn read_headers(&mut self) -> io::Result<(Option<u16>, BTreeMap<String, String>)> {
+        let spaces: &[_] = &[' ', '\t', '\r', '\n'];
+        let s = match self.stream { Some(ref mut s) => s, None => return Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected")) };
+
+        let mut line = String::new();
+        s.read_line(&mut line)?;
+        if line.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing response status"));
+        }
+
+        let status = line.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok());
+
+        // Header names are case insensitive, so keep them lowercased
+        let mut headers = BTreeMap::new();
+        loop {
+            line.clear();
+            s.read_line(&mut line)?;
+
+            let header = line.trim_matches(spaces);
+            if header.is_empty() {
+                break;
+            }
+
+            let mut parts = header.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                headers.insert(name.trim_matches(spaces).to_ascii_lowercase(), value.trim_matches(spaces).to_string());
+            }
+        }
+
+        Ok((status, headers))
+    }
+
+    // Read what's left of the response to rejected upgrade request
+    fn read_body(&mut self, headers: &BTreeMap<String, String>) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+
+        if let Some(len) = headers.get("content-length").and_then(|v| v.parse::<usize>().ok()) {
+            body = vec![0u8; cmp::min(len, MAX_REJECT_BODY)];
+            read_full(&mut self.io(), &mut *body)?;
+
+        } else if headers.get("transfer-encoding").map(|v| v.to_ascii_lowercase().contains("chunked")).unwrap_or(false) {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                self.io().read_line(&mut line)?;
+                let size = match usize::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16) {
+                    Ok(size) => size,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid chunk size"))
+                };
+
+                // Last chunk, skip trailers
+                if size == 0 {
+                    loop {
+                        line.clear();
+                        self.io().read_line(&mut line)?;
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+
+                // Keep what fits into the limit, discard the rest
+                let keep = cmp::min(size, MAX_REJECT_BODY - body.len());
+                let start = body.len();
+                body.resize(start + keep, 0);
+                read_full(&mut self.io(), &mut body[start..])?;
+
+                let skip = (size - keep) as u64;
+                if io::copy(&mut (&mut self.io()).take(skip), &mut io::sink())? < skip {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk"));
+                }
+
+                // Chunk data is followed by CRLF
+                line.clear();
+                self.io().read_line(&mut line)?;
+            }
+        }
+
+        Ok(body)
+    }
+
+    fn rejected(&mut self, status: Option<u16>, headers: BTreeMap<String, String>) -> WSError {
+        match self.read_body(&headers) {
+            Ok(body) => WSError::Handshake(HandshakeError { status: status, headers: headers, body: body }),
+            Err(e) => WSError::Io(e)
+        }
+    }
+
+    // Check upgrade response, error is `None` if server has rejected upgrade,
+    // or description of what's wrong with the response otherwise
+    fn check_response(&self, nonce: &str, status: Option<u16>, headers: &BTreeMap<String, String>) -> Result<(), Option<&'static str>> {
+        if status != Some(101) {
+            return Err(None);
+        }
+
+        match headers.get("sec-websocket-accept") {
+            Some(r) if nonce == *r => (),
+            _ => return Err(Some("missing Sec-WebSocket-Accept header in response"))
+        }
+
+        // Server MUST choose one of protocols we've offered
+        if let Some(proto) = headers.get("sec-websocket-protocol").map(|v| v.trim().to_string()) {
+            if !self.protocols.as_ref().map(|protos| protos.contains(&proto)).unwrap_or(false) {
+                return Err(Some("server has chosen protocol we haven't offered"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_response(&mut self, nonce: &str) -> WSResult<()> {
+        let (status, headers) = self.read_headers()?;
+
+        if status == Some(426) {
+            // Server doesn't speak our protocol version and (hopefully) tells us which ones it does
+            self.offered_versions = headers.get("sec-websocket-version").map(|v| v.split(',').filter_map(|v| v.trim().parse::<u32>().ok()).collect());
+        }
+
+        let check = self.check_response(nonce, status, &headers);
+        let accepted = match self.response_hook {
+            Some(ref mut hook) => hook(status, &headers, check.is_ok()),
+            None => check.is_ok()
+        };
+
+        if !accepted {
+            return match check {
+                Err(Some(desc)) => Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, desc))),
+                _ => Err(self.rejected(status, headers))
+            };
+        }
+
+        self.negotiated_protocol = headers.get("sec-websocket-protocol").map(|v| v.trim().to_string());
+        self.negotiated_extensions = headers.get("sec-websocket-extensions")
+            .map(|v| v.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect())
+            .unwrap_or_else(Vec::new);
+
+        self.accept_extensions(headers.get("sec-websocket-extensions").map(|v| &**v))
+    }
+
+    // Configure offered extensions server has accepted with parameters it has given
+    fn accept_extensions(&mut self, header: Option<&str>) -> WSResult<()> {
+        let mut rsv = WSHeader::empty();
+        for (name, params) in header.map(extension::parse).unwrap_or_default() {
+            let i = match self.codecs.iter().position(|ext| ext.name() == &*name) {
+                Some(i) => i,
+                None => continue
+            };
+            if self.active_codecs.contains(&i) || rsv.intersects(self.codecs[i].rsv()) {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "conflicting extensions are accepted")));
+            }
+
+            self.codecs[i].accept(&*params)?;
+            rsv |= self.codecs[i].rsv();
+            self.active_codecs.push(i);
+        }
+        Ok(())
+    }
+
+    fn legacy_handshake(&mut self, deadline: Option<Instant>) -> WSResult<()> {
+        let mut rng = thread_rng();
+        let key1 = legacy::generate_key(&mut rng);
+        let key2 = legacy::generate_key(&mut rng);
+        let mut key3 = [0u8; 8];
+        rng.fill_bytes(&mut key3);
+        let path = self.request_path();
+
+        {
+            let s = match self.stream { Some(ref mut s) => s, None => return Err(WSError::Io(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))) };
+
+            write!(s, "GET {} HTTP/1.1\r\n", path)?;
+            s.write_all(b"Upgrade: WebSocket\r\n")?;
+            s.write_all(b"Connection: Upgrade\r\n")?;
+            write!(s, "Host: {}\r\n", self.endpoint.host_header())?;
+            if !self.headers.iter().any(|&(ref n, _)| n.eq_ignore_ascii_case("origin")) {
+                write!(s, "Origin: {}\r\n", self.endpoint.origin())?;
+            }
+            write!(s, "Sec-WebSocket-Key1: {}\r\n", key1)?;
+            write!(s, "Sec-WebSocket-Key2: {}\r\n", key2)?;
+            if let Some(ref protos) = self.protocols {
+                write!(s, "Sec-WebSocket-Protocol: {}\r\n", protos.join(" "))?;
+            }
+            for &(ref name, ref value) in self.headers.iter() {
+                write!(s, "{}: {}\r\n", name, value)?;
+            }
+            s.write_all(b"\r\n")?;
+            s.write_all(&key3)?;
+            s.flush()?;
+        }
+
+        self.set_deadline(deadline)?;
+
+        let (status, headers) = self.read_headers()?;
+        if status != Some(101) {
+            return Err(self.rejected(status, headers));
+        }
+
+        let mut response = [0u8; 16];
+        read_full(&mut self.io(), &mut response)?;
+
+        match legacy::challenge_response(&*key1, &*key2, &key3) {
+            Some(ref expected) if **expected == response[..] => Ok(()),
+            _ => Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "invalid challenge response")))
+        }
+    }
+
+    fn handshake(&mut self) -> WSResult<()> {
+        // Whole handshake, including TCP connection and TLS negotiation, must fit into timeout
+        let deadline = self.handshake_timeout.map(|t| Instant::now() + t);
+
+        self.try_connect(deadline)?;
+        self.upgrade(deadline)
+    }
+
+    // HTTP upgrade over already connected stream
+    fn upgrade(&mut self, deadline: Option<Instant>) -> WSResult<()> {
+        self.set_deadline(deadline)?;
+
+        if self.legacy {
+            self.legacy_handshake(deadline)?;
+        } else {
+            let mut nonce = Nonce::new();
+            self.write_request(&*nonce)?;
+
+            self.set_deadline(deadline)?;
+
+            nonce = nonce.encode();
+            self.read_response(&*nonce)?;
+        }
+
+        self.last_read = self.clock.now();
+        Ok(self.set_stream_timeout(self.read_timeout, None)?)
+    }
+
+    fn connect_endpoint(&mut self) -> WSResult<()> {
+        self.offered_versions = None;
+
+        match self.handshake() {
+            Err(e) => match self.fallback_version() {
+                // Retry once with the version server is willing to speak
+                Some(version) if self.version_fallback => {
+                    self.version = version;
+                    self.handshake()
+                },
+                _ => Err(e)
+            },
+            ok => ok
+        }
+    }
+
+    fn reset(&mut self) {
+        self.active_endpoint = None;
+        self.partial = None;
+        self.idle_pinged = false;
+        self.closing = false;
+        self.close_sent = false;
+        self.drained = None;
+        self.negotiated_protocol = None;
+        self.negotiated_extensions.clear();
+        self.active_codecs.clear();
+        for ext in self.codecs.iter_mut() {
+            ext.reset();
+        }
+        self.sent_progress = 0;
+        self.recv_progress = 0;
+    }
+
+    /// Do handshake over already connected stream, e.g. custom transport,
+    /// there's no protocol version fallback, as it would need a new connection
+    pub fn connect_over(&mut self, stream: S) -> WSResult<()> {
+        self.reset();
+        self.offered_versions = None;
+        self.wake.set_stream(stream.try_clone_tcp().ok());
+        self.stream = Some(BufferedStream::new(stream));
+
+        let deadline = self.handshake_timeout.map(|t| Instant::now() + t);
+        self.upgrade(deadline)
+    }
+
+    /// Connect to the first endpoint which accepts the handshake,
+    /// trying them in order they were added
+    pub fn connect(&mut self) -> WSResult<()> {
+        self.reset();
+
+        let mut result = Err(WSError::InvalidInput("no endpoints to connect to"));
+        for i in 0..self.endpoints.len() {
+            self.endpoint = self.endpoints[i].clone();
+            self.hostname = self.endpoint.address();
+            self.use_ssl = self.endpoint.is_secure();
+
+            result = self.connect_endpoint();
+            if result.is_ok() {
+                self.active_endpoint = Some(i);
+                break;
+            }
+        }
+
+        result
+    }
+
+    /// Handle to interrupt blocked `read_message()` from other thread, once it's requested,
+    /// reads wait for data in short slices to notice wake ups
+    pub fn wake_handle(&mut self) -> WakeHandle {
+        self.wake_enabled = true;
+        self.wake.clone()
+    }
+
+    /// TLS settings used for wss endpoints
+    #[inline] pub fn set_tls_config(&mut self, tls: TlsConfig) {
+        self.tls = tls;
+    }
+
+    /// TCP socket options for new connections
+    #[inline] pub fn set_tcp_options(&mut self, tcp: TcpOptions) {
+        self.tcp = tcp;
+    }
+
+    /// HTTP proxy to tunnel connections through
+    #[inline] pub fn set_proxy(&mut self, proxy: Option<Proxy>) {
+        self.proxy = proxy;
+    }
+
+    /// Subprotocol chosen by server out of offered ones
+    #[inline] pub fn negotiated_protocol(&self) -> Option<&str> {
+        self.negotiated_protocol.as_deref()
+    }
+
+    /// Extensions accepted by server, along with their parameters, as they were returned
+    #[inline] pub fn negotiated_extensions(&self) -> &[String] {
+        &*self.negotiated_extensions
+    }
+
+    /// Offer extension which transforms frames (e.g. compression) in upgrade request,
+    /// it's applied to data frames going both ways if server accepts it
+    pub fn add_extension<E: Extension + 'static>(&mut self, ext: E) {
+        self.codecs.push(Box::new(ext));
+    }
+
+    /// Add fallback endpoint to try if all previous ones fail to connect
+    #[inline] pub fn add_endpoint(&mut self, url: Url) {
+        self.endpoints.push(Endpoint::Url(url));
+    }
+
+    /// Add fallback endpoint given by host, port and path, see `with_target()`
+    #[inline] pub fn add_target(&mut self, host: &str, port: u16, path: &str, secure: bool) {
+        self.endpoints.push(Endpoint::Target { host: host.to_string(), port: port, path: path.to_string(), secure: secure });
+    }
+
+    #[inline] pub fn endpoints(&self) -> &[Endpoint] {
+        &*self.endpoints
+    }
+
+    /// Endpoint socket is (or was last) connected to, or the first one, if it was never connected
+    #[inline] pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// URL of current endpoint, if it's given by URL
+    #[inline] pub fn url(&self) -> Option<&Url> {
+        match self.endpoint {
+            Endpoint::Url(ref url) => Some(url),
+            Endpoint::Target { .. } => None
+        }
+    }
+
+    /// Index of the endpoint current connection was established to
+    #[inline] pub fn active_endpoint(&self) -> Option<usize> {
+        self.active_endpoint
+    }
+
+    /// Retry handshake with a version offered by server in 426 response, if we support any of them
+    #[inline] pub fn set_version_fallback(&mut self, enable: bool) {
+        self.version_fallback = enable;
+    }
+
+    /// Protocol versions listed by server when it rejected our handshake with 426 status
+    #[inline] pub fn offered_versions(&self) -> Option<&[u32]> {
+        self.offered_versions.as_deref()
+    }
+
+    /// Speak legacy hixie-76 (hybi-00) protocol draft instead of RFC6455,
+    /// for peers which know nothing else
+    #[inline] pub fn set_legacy_protocol(&mut self, enable: bool) {
+        self.legacy = enable;
+    }
+
+    /// Clock used for throttling, queued messages time to live, reconnection delays,
+    /// idle timeout and receive timestamps
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.set_clock(clock.clone());
+        }
+        self.outgoing.set_clock(clock.clone());
+        self.last_read = clock.now();
+        self.clock = clock;
+    }
+
+    /// Limit outgoing traffic to given number of bytes and/or messages per second,
+    /// `send_message()` blocks until the message fits into the limits
+    pub fn set_throttle(&mut self, bytes_per_sec: Option<u64>, messages_per_sec: Option<u64>) {
+        self.throttle = if bytes_per_sec.is_some() || messages_per_sec.is_some() {
+            Some(Throttle::with_clock(bytes_per_sec, messages_per_sec, self.clock.clone()))
+        } else {
+            None
+        };
+    }
+
+    /// Reconnect automatically when server closes connection with "service restart" (1012)
+    /// or "try again later" (1013) status, waiting for number of seconds given in close reason,
+    /// or for `delay` if there's no such hint, `None` disables reconnection
+    #[inline] pub fn set_restart_reconnect(&mut self, delay: Option<Duration>) {
+        self.restart_delay = delay;
+    }
+
+    /// Longest delay server may ask to wait before reconnection (5 minutes by default),
+    /// longer hints are cut down to it
+    #[inline] pub fn set_max_restart_delay(&mut self, max: Duration) {
+        self.max_restart_delay = max;
+    }
+
+    /// Limit time `connect()` may take to establish connection to each endpoint,
+    /// covering host name resolution, TCP connection, TLS negotiation and HTTP upgrade
+    #[inline] pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Request path (with query, if any) to send instead of the one taken from URL, as is
+    #[inline] pub fn set_path(&mut self, path: Option<&str>) {
+        self.path = path.map(|p| p.to_string());
+    }
+
+    /// Add header to every upgrade request, replacing the one with the same name
+    /// (case insensitive), e.g. "User-Agent" (set by default), "Accept-Language" or "Origin",
+    /// headers driving the handshake itself (Host, Upgrade, Connection, Sec-WebSocket-*) can't be changed this way,
+    /// neither name nor value may contain line breaks
+    pub fn set_header(&mut self, name: &str, value: &str) -> WSResult<()> {
+        if name.is_empty() || name.bytes().any(|b| b <= b' ' || b == b':' || b >= 0x7f) || value.bytes().any(|b| b == b'\r' || b == b'\n') {
+            return Err(WSError::InvalidInput("invalid header"));
+        }
+        if is_reserved_header(name) {
+            return Err(WSError::InvalidInput("header is set by handshake"));
+        }
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|&(ref n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Value of extra request header, if it's set
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| &**v)
+    }
+
+    /// Call `hook` with raw upgrade request (request line and headers, up to the empty line
+    /// included) just before it's sent, so it can be altered, e.g. to add nonstandard headers;
+    /// legacy handshake is not affected
+    pub fn set_request_hook<F: FnMut(&mut Vec<u8>) + 'static>(&mut self, hook: Option<F>) {
+        self.request_hook = hook.map(|f| Box::new(f) as Box<dyn FnMut(&mut Vec<u8>)>);
+    }
+
+    /// Call `hook` with upgrade response status, headers (names lowercased) and whether
+    /// the response is going to be accepted, hook's result is the final decision,
+    /// so slightly malformed responses can be let through, or good ones rejected
+    pub fn set_response_hook<F: FnMut(Option<u16>, &BTreeMap<String, String>, bool) -> bool + 'static>(&mut self, hook: Option<F>) {
+        self.response_hook = hook.map(|f| Box::new(f) as Box<dyn FnMut(Option<u16>, &BTreeMap<String, String>, bool) -> bool>);
+    }
+
+    /// Close connection (with "going away" status) if nothing is received within `timeout`,
+    /// if `ping` is set, ping the peer first and wait for another `timeout` before closing
+    /// (legacy protocol has no pings, so `ping` is ignored there)
+    #[inline] pub fn set_idle_timeout(&mut self, timeout: Option<Duration>, ping: bool) {
+        self.idle_timeout = timeout;
+        self.idle_ping = ping;
+    }
+
+    /// Make reads fail with timed out error if nothing arrives within `timeout`,
+    /// unlike idle timeout connection is left open, and frame received partially
+    /// is completed by the next read. Timeout is set again on reconnects
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        match self.stream {
+            Some(_) => self.set_stream_timeout(timeout, None),
+            None => Ok(())
+        }
+    }
+
+    #[inline] pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    fn fallback_version(&self) -> Option<u32> {
+        self.offered_versions.as_ref().and_then(|offered| offered.iter().cloned().find(|v| *v != self.version && SUPPORTED_VERSIONS.contains(v)))
+    }
+
+    // Read into `buf` from `*pos` on until it's full, `*pos` is kept up to date, so that
+    // read can be resumed after timeout; `base` bytes of frame of `expected` size are read before `buf`
+    fn read_part(&mut self, buf: &mut [u8], pos: &mut usize, base: usize, expected: usize) -> WSResult<()> {
+        while *pos < buf.len() {
+            match self.io().read(&mut buf[*pos..])? {
+                0 if base + *pos == 0 => return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"))),
+                0 => return Err(WSError::ConnectionResetMidFrame { read: base + *pos, expected: expected }),
+                n => *pos += n
+            }
+        }
+        Ok(())
+    }
+
+    // Read frame header, returns it with payload length and mask
+    fn read_head(&mut self, frame: &mut PartialFrame) -> WSResult<(WSHeader, u64, Option<u32>)> {
+        self.read_part(&mut frame.head[..2], &mut frame.head_read, 0, 2)?;
+        let header = WSHeader::from_bits_truncate(unpack_be(&frame.head[..2]) as u16);
+
+        let wslen = header & WS_LEN;
+        let ext = if wslen == WS_LEN16 { 2 } else if wslen == WS_LEN64 { 8 } else { 0 };
+        let size = 2 + ext + if header.contains(WS_MASK) { 4 } else { 0 };
+        self.read_part(&mut frame.head[..size], &mut frame.head_read, 0, size)?;
+
+        let len = if ext == 0 { wslen.bits() as u64 } else { unpack_be(&frame.head[2..2 + ext]) };
+        // The most significant bit of 64-bit length MUST be 0
+        if len >> 63 != 0 {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "invalid frame length")));
+        }
+
+        let mask = if header.contains(WS_MASK) { Some(unpack_be(&frame.head[2 + ext..size]) as u32) } else { None };
+        Ok((header, len, mask))
+    }
+
+    // Wait for data to arrive in short slices, checking if we're woken up in between,
+    // gives up after idle timeout to let `wait_idle()` deal with idle peer.
+    // Read timeout in effect still applies, and timeouts are restored afterwards
+    fn wait_woken(&mut self) -> WSResult<()> {
+        let (read, write) = self.timeouts;
+        let result = self.poll_woken(read.map(|t| Instant::now() + t), write);
+        self.set_stream_timeout(read, write)?;
+        Ok(result?)
+    }
+
+    fn poll_woken(&mut self, deadline: Option<Instant>, write: Option<Duration>) -> io::Result<()> {
+        let poll = Duration::from_millis(WAKE_POLL_MS);
+        loop {
+            if self.wake.take_woken() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "read is interrupted by wake handle"));
+            }
+            if self.idle_timeout.map(|idle| self.clock.now() >= self.last_read + idle).unwrap_or(false) {
+                return Ok(());
+            }
+
+            let slice = match time_left(deadline) {
+                Ok(Some(left)) => cmp::min(left, poll),
+                Ok(None) => poll,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+            };
+            self.set_stream_timeout(Some(slice), write)?;
+
+            match self.fill() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+                result => return result
+            }
+        }
+    }
+
+    // Wait until peer sends something: if it's silent for idle timeout, ping it, if asked to,
+    // and give it one more idle timeout to answer, then say goodbye and drop connection.
+    // Read timeout in effect still applies, and timeouts are restored afterwards
+    fn wait_idle(&mut self, idle: Duration) -> WSResult<()> {
+        let (read, write) = self.timeouts;
+        let deadline = read.map(|t| Instant::now() + t);
+
+        loop {
+            let idle_left = (self.last_read + idle).checked_duration_since(self.clock.now()).unwrap_or_default();
+            if idle_left == Duration::from_secs(0) {
+                // There are no pings in legacy protocol
+                if self.idle_ping && !self.idle_pinged && !self.legacy {
+                    self.idle_pinged = true;
+                    self.last_read = self.clock.now();
+                    self.send_message(&WSMessage::ping(b"").mask())?;
+                    continue;
+                }
+
+                // Peer is silent for too long, say goodbye and drop connection
+                let _ = self.send_message(&WSMessage::close(WSStatusCode::GoneAway, b"").mask());
+                self.drop_stream();
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::TimedOut, "connection is idle for too long")));
+            }
+
+            let wait = match time_left(deadline) {
+                Ok(Some(left)) => cmp::min(left, idle_left),
+                Ok(None) => idle_left,
+                Err(_) => return Err(WSError::Io(io::Error::new(io::ErrorKind::TimedOut, "read timed out")))
+            };
+
+            self.set_stream_timeout(Some(wait), write)?;
+            let filled = self.fill();
+            self.set_stream_timeout(read, write)?;
+
+            match filled {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+                result => return Ok(result?)
+            }
+        }
+    }
+
+    // Read more data into stream buffer, if it's empty
+    fn fill(&mut self) -> io::Result<()> {
+        match self.stream {
+            Some(ref mut s) => s.fill_buf().map(|_| ()),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
+        }
+    }
+
+    // Wait for the start of the next frame, the rest of frame is expected to follow soon
+    fn wait_header(&mut self, frame: &PartialFrame) -> WSResult<()> {
+        if frame.head_read > 0 || !self.buffered().is_empty() {
+            return Ok(());
+        }
+
+        if self.wake_enabled {
+            self.wait_woken()?;
+        }
+
+        match self.idle_timeout {
+            Some(idle) if self.buffered().is_empty() => self.wait_idle(idle),
+            _ => Ok(())
+        }
+    }
+
+    /// Stop reading from connection: reads fail with would block error without touching
+    /// the socket, so that unread data fills TCP buffers and peer is slowed down by TCP
+    /// flow control, while the application is overloaded. Sending is not affected
+    #[inline] pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Continue reading from where it was paused, nothing is lost meanwhile
+    #[inline] pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    #[inline] pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn check_paused(&self) -> WSResult<()> {
+        if self.paused {
+            Err(WSError::Io(io::Error::new(io::ErrorKind::WouldBlock, "reading is paused")))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn read_message(&mut self) -> WSResult<WSMessage> {
+        self.check_paused()?;
+        loop {
+            let msg = self.receive()?;
+
+            if self.drained.is_some() {
+                return self.drain(msg);
+            }
+
+            let delay = match (self.restart_delay, msg.status) {
+                (Some(delay), Some(WSStatusCode::ServiceRestart)) |
+                (Some(delay), Some(WSStatusCode::TryAgainLater)) =>
+                    String::from_utf8_lossy(&*msg.data).trim().parse::<u64>().map(Duration::from_secs).unwrap_or(delay),
+                _ => return Ok(msg)
+            };
+            let delay = cmp::min(delay, self.max_restart_delay);
+
+            // Complete closing handshake, peer is going away anyway, so ignore errors
+            let _ = self.send_message(&WSMessage::close(msg.status.unwrap(), b"").mask());
+
+            self.clock.sleep(delay);
+            self.connect()?;
+        }
+    }
+
+    /// Read exactly one frame as is, continuation and control frames included,
+    /// without any message level handling (reconnection, half-close draining)
+    #[inline] pub(crate) fn read_frame(&mut self) -> WSResult<WSMessage> {
+        self.check_paused()?;
+        self.receive()
+    }
+
+    /// Write exactly one frame with FIN, RSV bits and opcode taken from its header as is,
+    /// payload is masked with fresh key if mask bit is set
+    #[inline] pub(crate) fn write_frame(&mut self, frame: &WSMessage) -> WSResult<()> {
+        self.send_data(frame.header, frame.status, &*frame.data)
+    }
+
+    /// Read at least one message, blocking if needed, and then all messages
+    /// already received into read buffer, without touching the socket again
+    pub(crate) fn read_available(&mut self) -> WSResult<Vec<WSMessage>> {
+        let mut batch = vec![self.read_message()?];
+        while !self.legacy && self.has_buffered_frame() {
+            batch.push(self.read_message()?);
+        }
+        Ok(batch)
+    }
+
+    fn buffered(&self) -> &[u8] {
+        match self.stream {
+            Some(ref s) => s.buffered(),
+            None => &[]
+        }
+    }
+
+    fn has_buffered_frame(&self) -> bool {
+        let buf = self.buffered();
+        frame_size(buf).map(|size| size <= buf.len() as u64).unwrap_or(false)
+    }
+
+    /// Read next frame, borrowing its payload from internal buffer instead of copying it,
+    /// payload stays valid until the next read
+    pub(crate) fn read_message_ref(&mut self) -> WSResult<WSMessageRef<'_>> {
+        self.check_paused()?;
+        let mut buf = mem::take(&mut self.read_buf);
+        let result = self.receive_into(&mut buf);
+        self.read_buf = buf;
+
+        let (header, status, offset, received) = result?;
+        Ok(WSMessageRef { header: header, data: Cow::Borrowed(&self.read_buf[offset..]), status: status, received: Some(received) })
+    }
+
+    /// Report progress of data messages transfer to `callback` as bytes transferred so far
+    /// and total size, if it's known (i.e. message is not fragmented), counting from
+    /// the first fragment of the message; control frames are not reported
+    pub fn set_progress_callback<F: FnMut(Direction, u64, Option<u64>) + 'static>(&mut self, callback: Option<F>) {
+        self.progress = callback.map(|f| Box::new(f) as Box<dyn FnMut(Direction, u64, Option<u64>)>);
+    }
+
+    // Read payload of `frame`, which comes after `base` bytes of its header
+    fn read_payload(&mut self, frame: &mut PartialFrame, header: WSHeader, base: usize) -> WSResult<()> {
+        let expected = base + frame.data.len();
+        if self.progress.is_none() || header.contains(WS_OPCTRL) {
+            return self.read_part(&mut *frame.data, &mut frame.data_read, base, expected);
+        }
+
+        let total = if header.contains(WS_FIN) && self.recv_progress == frame.reported as u64 { Some(frame.data.len() as u64) } else { None };
+        while frame.reported < frame.data.len() {
+            let end = cmp::min(frame.reported + PROGRESS_CHUNK, frame.data.len());
+            self.read_part(&mut frame.data[..end], &mut frame.data_read, base, expected)?;
+            self.recv_progress += (end - frame.reported) as u64;
+            frame.reported = end;
+
+            let done = self.recv_progress;
+            if let Some(ref mut callback) = self.progress {
+                callback(Direction::Received, done, total);
+            }
+        }
+
+        if header.contains(WS_FIN) {
+            self.recv_progress = 0;
+        }
+        Ok(())
+    }
+
+    fn write_payload(&mut self, data: &[u8], header: WSHeader) -> io::Result<()> {
+        if self.progress.is_none() || header.contains(WS_OPCTRL) {
+            return self.io().write_all(data);
+        }
+
+        let total = if header.contains(WS_FIN) && self.sent_progress == 0 { Some(data.len() as u64) } else { None };
+        for chunk in data.chunks(PROGRESS_CHUNK) {
+            self.io().write_all(chunk)?;
+            self.sent_progress += chunk.len() as u64;
+
+            let done = self.sent_progress;
+            if let Some(ref mut callback) = self.progress {
+                callback(Direction::Sent, done, total);
+            }
+        }
+
+        if header.contains(WS_FIN) {
+            self.sent_progress = 0;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> WSResult<WSMessage> {
+        let mut data = match self.buffers.get() {
+            Some(data) => data,
+            None => return Err(WSError::Io(io::Error::new(io::ErrorKind::OutOfMemory, "no free buffers, received messages must be recycled")))
+        };
+        let (header, status, offset, received) = match self.receive_into(&mut data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.buffers.put(data);
+                return Err(e);
+            }
+        };
+
+        if offset > 0 {
+            data.drain(..offset);
+        }
+
+        Ok(WSMessage { header: header, data: data, status: status, received: Some(received) })
+    }
+
+    // Read next frame with its payload put into `buf`,
+    // returns frame header, status, offset of actual data in `buf` and receive time
+    fn receive_into(&mut self, buf: &mut Vec<u8>) -> WSResult<(WSHeader, Option<WSStatusCode>, usize, WSTimestamps)> {
+        buf.clear();
+
+        if self.legacy {
+            // Wake handles and timeouts apply until message starts, it's read whole from there
+            self.wait_header(&PartialFrame::new())?;
+            let limit = self.max_frame_size;
+            let msg = match legacy::read_message(&mut self.io(), limit) {
+                Ok(msg) => msg,
+                Err(e @ WSError::TooLarge { .. }) => return Err(self.fail(WSStatusCode::TooLargeData, e)),
+                Err(e) => return Err(e)
+            };
+            buf.extend_from_slice(&*msg.data);
+            let now = self.clock.now();
+            self.last_read = now;
+            self.idle_pinged = false;
+            return Ok((msg.header, msg.status, 0, WSTimestamps { first_byte: now, last_byte: now }));
+        }
+
+        // Continue with the frame interrupted by timeout, if any
+        let mut frame = self.partial.take().unwrap_or_else(PartialFrame::new);
+        let result = self.receive_frame(&mut frame, buf);
+        if let Err(WSError::Io(ref e)) = result {
+            if is_resumable(e) && self.stream.is_some() && frame.head_read > 0 {
+                self.partial = Some(frame);
+            }
+        }
+
+        let (mut header, status, offset, received) = result?;
+        if !header.contains(WS_OPCTRL) {
+            self.decode(&mut header, buf)?;
+        }
+        Ok((header, status, offset, received))
+    }
+
+    // Undo negotiated extensions transformations of data frame payload, in reverse order,
+    // frame peer has failed to encode properly fails connection
+    fn decode(&mut self, header: &mut WSHeader, buf: &mut Vec<u8>) -> WSResult<()> {
+        let limit = self.max_frame_size;
+        for &i in self.active_codecs.iter().rev() {
+            match self.codecs[i].decode(header, buf, limit) {
+                Ok(()) => (),
+                Err(e @ WSError::TooLarge { .. }) => return Err(self.fail(WSStatusCode::TooLargeData, e)),
+                Err(e) => return Err(self.fail(WSStatusCode::ProtocolError, e))
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_frame(&mut self, frame: &mut PartialFrame, buf: &mut Vec<u8>) -> WSResult<(WSHeader, Option<WSStatusCode>, usize, WSTimestamps)> {
+        self.wait_header(frame)?;
+        let (header, len, mask) = self.read_head(frame)?;
+        let first_byte = *frame.first_byte.get_or_insert_with(|| self.clock.now());
+
+        if frame.data_read == 0 && frame.data.is_empty() {
+            // Control frames can't be fragmented and carry 125 bytes at most
+            if header.contains(WS_OPCTRL) && (!header.contains(WS_FIN) || len > 125) {
+                return Err(self.fail(WSStatusCode::ProtocolError, WSError::Io(io::Error::new(io::ErrorKind::InvalidData, "invalid control frame"))));
+            }
+
+            // Stream is out of sync now, as the rest of the frame is left unread
+            if let Some(limit) = self.max_frame_size {
+                if len > limit as u64 {
+                    return Err(self.fail(WSStatusCode::TooLargeData, WSError::TooLarge { size: len, limit: limit }));
+                }
+            }
+
+            // Can't fit this into memory on 32-bit platforms
+            if len > usize::MAX as u64 {
+                return Err(self.fail(WSStatusCode::TooLargeData, WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "frame is too large for this platform"))));
+            }
+
+            // Payload goes straight into caller's buffer, it's kept with the frame until it's complete
+            mem::swap(&mut frame.data, buf);
+            frame.data.resize(len as usize, 0);
+        }
+
+        let base = frame.head_read;
+        self.read_payload(frame, header, base)?;
+        mem::swap(&mut frame.data, buf);
+
+        // If we have mask, decrypt data
+        if let Some(m) = mask {
+            mask_in_place(&mut **buf, m);
+        }
 
-        let port = match url.port() {
-            Some(p) => p,
-            None if use_ssl => 443,
-            _ => 80
-        };
+        let received = WSTimestamps { first_byte: first_byte, last_byte: self.clock.now() };
+        self.last_read = self.clock.now();
+        self.idle_pinged = false;
 
-        WebSocket {
-            stream: None,
-            hostname: format!("{}:{}", url.serialize_host().unwrap(), port),
-            url: url,
-            use_ssl: use_ssl,
-            version: version,
-            extensions: extensions.map(|v| v.iter().map(|v| v.to_string()).collect()),
-            protocols: protocols.map(|v| v.iter().map(|v| v.to_string()).collect())
+        // If this is the terminating frame (close command),
+        // first two bytes of data MUST BE u16 status code, if there's any data at all
+        if header & WS_OPCODE != WS_OPTERM || buf.is_empty() {
+            return Ok((header, None, 0, received));
+        }
+        match if buf.len() >= 2 { WSStatusCode::from_u16(unpack_be(&buf[..2]) as u16) } else { None } {
+            Some(status) if status.is_sendable() => Ok((header, Some(status), 2, received)),
+            _ => Err(self.fail(WSStatusCode::ProtocolError, WSError::Io(io::Error::new(io::ErrorKind::InvalidData, "invalid close status code"))))
         }
     }
 
-    #[inline] pub fn new(url: Url) -> WebSocket {
-        WebSocket::with_options(url, 1, None, None)
+    #[inline] pub(crate) fn send_message(&mut self, msg: &WSMessage) -> WSResult<()> {
+        self.send_data(msg.header, msg.status, &*msg.data)
+    }
+
+    /// Send message as is, even if compression is negotiated, e.g. for already compressed data
+    pub(crate) fn send_uncompressed(&mut self, msg: &WSMessage) -> WSResult<()> {
+        self.compress = false;
+        let result = self.send_message(msg);
+        self.compress = true;
+        result
+    }
+
+    #[inline] pub(crate) fn send_shared(&mut self, msg: &WSSharedMessage) -> WSResult<()> {
+        self.send_data(msg.header, msg.status, &**msg.data)
     }
 
-    fn try_connect(&mut self) -> io::Result<()> {
-        self.stream = Some(BufStream::new(try!(NetworkStream::connect(&*self.hostname, self.use_ssl))));
+    /// Send already encoded frame(s) as is, e.g. from `WSMessage::encode_frame()`
+    pub(crate) fn send_raw_frame(&mut self, frame: &[u8]) -> WSResult<()> {
+        if self.closing {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "connection is shutting down")));
+        }
+        if self.legacy {
+            return Err(WSError::InvalidInput("raw frames are not supported by legacy protocol"));
+        }
+
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.wait(frame.len() as u64);
+        }
+
+        self.io().write_all(frame)?;
+        if self.auto_flush {
+            self.flush()?;
+        }
         Ok(())
     }
 
-    fn write_request(&mut self, nonce: &str) -> io::Result<()> {
-        let s = match self.stream { Some(ref mut s) => s, None => return Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None)) };
+    /// Flush every sent message immediately (default), if turned off, messages are accumulated
+    /// in write buffer until it is full or `flush()` is called, so that batch of small messages
+    /// goes out in as few TCP segments as possible
+    #[inline] pub fn set_auto_flush(&mut self, enable: bool) {
+        self.auto_flush = enable;
+    }
 
-        try!(write!(s, "GET {} HTTP/1.1\r\n", self.url.serialize_path().unwrap_or("/".to_string())));
-        try!(write!(s, "Host: {}\r\n", self.url.host().unwrap()));
-        try!(write!(s, "Origin: {}\r\n", self.url.serialize_no_fragment()));
-        try!(write!(s, "Sec-WebSocket-Key: {}\r\n", nonce));
+    /// Use given pool to allocate received messages payloads
+    #[inline] pub fn set_buffer_pool(&mut self, pool: Box<dyn BufferPool>) {
+        self.buffers = pool;
+    }
 
-        try!(s.write_all(b"Upgrade: websocket\r\n"));
-        try!(s.write_all(b"Connection: Upgrade\r\n"));
-        try!(write!(s, "Sec-WebSocket-Version: {}\r\n", self.version));
-        if let Some(ref protos) = self.protocols {
-            try!(write!(s, "Sec-WebSocket-Protocol: {}\r\n", protos.connect(", ")));
+    /// Low memory mode: incoming and outgoing frames are limited to `frame_size` bytes, and their
+    /// payloads go into `count` buffers allocated beforehand. Reads fail with out of memory error
+    /// when all of them are in use, until received messages are `recycle()`d. Larger incoming
+    /// frame fails connection with "too large" (1009) close, as there's no way to skip it
+    /// without reading, both are reported as `WSError::TooLarge`. Message writers send
+    /// fragments of `frame_size` bytes at most
+    pub fn set_fixed_buffers(&mut self, frame_size: usize, count: usize) {
+        self.max_frame_size = Some(frame_size);
+        self.buffers = Box::new(FixedPool::new(frame_size, count));
+        self.read_buf = Vec::with_capacity(frame_size);
+        self.write_buf = Vec::with_capacity(frame_size);
+    }
+
+    /// Give message payload buffer back to the pool when message is no longer needed
+    #[inline] pub fn recycle(&mut self, msg: WSMessage) {
+        self.buffers.put(msg.data);
+    }
+
+    /// Send masked text message straight from borrowed string
+    #[inline] pub(crate) fn send_text(&mut self, text: &str) -> WSResult<()> {
+        self.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, text.as_bytes())
+    }
+
+    /// Send masked binary message straight from borrowed slice
+    #[inline] pub(crate) fn send_binary(&mut self, data: &[u8]) -> WSResult<()> {
+        self.send_data(WS_FIN | WS_MASK | WS_OPBIN, None, data)
+    }
+
+    /// Put message into outgoing queue to be sent by `flush_queue()`, control messages
+    /// go before data ones, which go in order of `priority` (higher first)
+    #[inline] pub fn queue_message(&mut self, msg: WSMessage, priority: u8) -> WSResult<()> {
+        self.enqueue(msg, priority, None)
+    }
+
+    /// Queue message, which is dropped (and reported to expired callback)
+    /// if it's still in the queue when `ttl` passes
+    #[inline] pub fn queue_message_ttl(&mut self, msg: WSMessage, priority: u8, ttl: Duration) -> WSResult<()> {
+        self.enqueue(msg, priority, Some(ttl))
+    }
+
+    fn enqueue(&mut self, msg: WSMessage, priority: u8, ttl: Option<Duration>) -> WSResult<()> {
+        let mut msg = msg;
+        loop {
+            msg = match self.outgoing.push_ttl(msg, priority, ttl) {
+                Ok(()) => return Ok(()),
+                Err(msg) => msg
+            };
+
+            // Make room sending queued frames
+            if self.outgoing.overflow() != Overflow::Block || !self.send_queued()? {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::WouldBlock, "outgoing queue is full")));
+            }
+        }
+    }
+
+    /// Limit number of data messages in outgoing queue (control ones are not limited),
+    /// `overflow` tells what to do with messages queued when it's full
+    #[inline] pub fn set_queue_limit(&mut self, limit: Option<usize>, overflow: Overflow) {
+        self.outgoing.set_limit(limit, overflow);
+    }
+
+    /// Call `callback` with every queued message dropped because its time to live has passed,
+    /// or because the queue has overflowed
+    #[inline] pub fn set_expired_callback<F: FnMut(WSMessage) + 'static>(&mut self, callback: Option<F>) {
+        self.outgoing.set_expired_callback(callback);
+    }
+
+    /// Split queued data messages larger than `size` into fragments, so that
+    /// control frames queued later don't wait for the whole large message to go out
+    #[inline] pub fn set_queue_fragment_size(&mut self, size: Option<usize>) {
+        self.outgoing.set_fragment_size(size);
+    }
+
+    /// Number of frames waiting in outgoing queue
+    #[inline] pub fn queued(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Send one frame from outgoing queue, returns false if queue is empty
+    pub fn send_queued(&mut self) -> WSResult<bool> {
+        match self.outgoing.pop() {
+            Some(frame) => {
+                self.send_message(&frame)?;
+                Ok(true)
+            },
+            None => Ok(false)
         }
-        if let Some(ref exts) = self.extensions {
-            try!(write!(s, "Sec-WebSocket-Extensions: {}\r\n", exts.connect(", ")));
+    }
+
+    /// Send all queued frames
+    pub fn flush_queue(&mut self) -> WSResult<()> {
+        while self.send_queued()? {}
+        Ok(self.flush()?)
+    }
+
+    /// Start streaming message of given type (opcode and optional mask bit in `header`),
+    /// data written to the writer goes out in fragments of `chunk_size` bytes,
+    /// or max frame size, if it's smaller
+    pub(crate) fn message_writer(&mut self, header: WSHeader, chunk_size: usize) -> MessageWriter<'_, S> {
+        let chunk_size = self.chunk_size(chunk_size);
+        MessageWriter {
+            sock: self,
+            header: header & (WS_OPCODE | WS_MASK),
+            chunk_size: chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+            started: false
         }
-        try!(s.write_all(b"\r\n"));
+    }
 
-        s.flush()
+    fn chunk_size(&self, chunk_size: usize) -> usize {
+        cmp::max(cmp::min(chunk_size, self.max_frame_size.unwrap_or(usize::MAX)), 1)
     }
 
-    fn read_response(&mut self, nonce: &str) -> io::Result<()> {
-        let spaces: &[_] = &[' ', '\t', '\r', '\n'];
-        let s = match self.stream { Some(ref mut s) => s, None => return Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None)) };
-        let mut lines = s.lines();
-        let status = match lines.next() {
-            Some(Ok(line)) => line.splitn(2, ' ').nth(1).and_then(|s| s.parse::<u16>().ok()),
-            Some(Err(e)) => return Err(e),
-            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing response status", None))
+    /// Send everything `reader` gives as a single fragmented message, reading it by `chunk_size`
+    /// bytes, so memory use doesn't depend on message size; returns number of bytes sent
+    pub(crate) fn send_stream<R: Read>(&mut self, header: WSHeader, reader: &mut R, chunk_size: usize) -> WSResult<u64> {
+        let mut chunk = vec![0u8; self.chunk_size(chunk_size)];
+        let mut total = 0u64;
+        let mut writer = self.message_writer(header, chunk_size);
+
+        loop {
+            let n = read_upto(reader, &mut *chunk)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&chunk[..n])?;
+            total += n as u64;
+        }
+
+        writer.finish()?;
+        Ok(total)
+    }
+
+    /// Send frame with given header, status and payload without building `WSMessage`,
+    /// if header has mask bit set, payload is masked in internal scratch buffer
+    pub(crate) fn send_data(&mut self, header: WSHeader, status: Option<WSStatusCode>, data: &[u8]) -> WSResult<()> {
+        if self.closing {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "connection is shutting down")));
+        }
+
+        self.write_data(header, status, data)
+    }
+
+    #[inline] fn write_message(&mut self, msg: &WSMessage) -> WSResult<()> {
+        self.write_data(msg.header, msg.status, &*msg.data)
+    }
+
+    fn write_data(&mut self, header: WSHeader, status: Option<WSStatusCode>, data: &[u8]) -> WSResult<()> {
+        let is_close = header & WS_OPCODE == WS_OPTERM;
+        if is_close && self.close_sent {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "close frame is already sent")));
+        }
+
+        if self.legacy {
+            legacy::write_data(&mut self.io(), header, data)?;
+            self.close_sent |= is_close;
+            return Ok(());
+        }
+
+        if is_close {
+            check_close(status, data)?;
+        }
+        let code = status_code(status)?;
+
+        // Negotiated extensions transform data frames, unless they have RSV bits set already
+        let mut encoded = Vec::new();
+        let (header, data) = if !self.active_codecs.is_empty() && !header.contains(WS_OPCTRL) && !header.intersects(WS_RSV) {
+            let mut header = header;
+            encoded.extend_from_slice(data);
+            for &i in self.active_codecs.iter() {
+                self.codecs[i].encode(&mut header, &mut encoded, self.compress)?;
+            }
+            (header, &*encoded)
+        } else {
+            (header, data)
         };
 
-        match status {
-            Some(101) => (),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid response status", None))
+        let mut len = data.len() as u64;
+
+        // If we have status set, the data length is increased by status size
+        if code.is_some() {
+            len += 2;
+        }
+
+        if let Some(limit) = self.max_frame_size {
+            if len > limit as u64 {
+                return Err(WSError::TooLarge { size: len, limit: limit });
+            }
         }
 
-        let headers = lines.map(|r| r.unwrap_or("\r\n".to_string())) .take_while(|l| &**l != "\r\n")
-            .map(|s| s.splitn(1, ':').map(|s| s.trim_matches(spaces).to_string()).collect::<Vec<String>>())
-            .map(|p| (p[0].to_string(), p[1].to_string()))
-            .collect::<BTreeMap<String, String>>();
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.wait(len);
+        }
 
-        try!(s.flush());
+        // If user required masking, generate random mask
+        let mask = if header.contains(WS_MASK) { Some(thread_rng().gen::<u32>()) } else { None };
 
-        let response = headers.get("Sec-WebSocket-Accept");
-        match response {
-            Some(r) if nonce == *r => (),
-            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing Sec-WebSocket-Accept header in response", None))
+        // Encode and send header along with length and mask
+        let frame = FrameHeaderBuilder::from_header(header).len(len).mask(mask);
+        self.io().write_all(&*frame.to_bytes())?;
+
+        // Status code goes first in payload
+        let mut scratch = mem::take(&mut self.write_buf);
+        scratch.clear();
+        if let Some(code) = code {
+            scratch.extend_from_slice(&pack_be_u16(code));
+        }
+
+        // If user required masking, encrypt all data
+        let result = match mask {
+            Some(mask) => {
+                scratch.extend_from_slice(data);
+                mask_in_place(&mut *scratch, mask);
+                self.write_payload(&*scratch, header)
+            },
+            None => match self.io().write_all(&*scratch) {
+                Ok(()) => self.write_payload(data, header),
+                err => err
+            }
+        };
+
+        self.write_buf = scratch;
+        result?;
+        self.close_sent |= is_close;
+
+        if self.auto_flush {
+            self.flush()?;
         }
 
         Ok(())
     }
 
-    pub fn connect(&mut self) -> io::Result<()> {
-        let mut nonce = Nonce::new();
+    /// Gracefully close connection: refuse further sends, flush pending data,
+    /// send close frame (unless it's already sent by `close_send()`) and wait for peer's one,
+    /// dropping connection anyway once `timeout` passes or something goes wrong.
+    /// Connection already closed by both sides is just dropped
+    pub fn shutdown(&mut self, timeout: Duration) -> WSResult<()> {
+        if self.close_sent && self.stream.is_none() {
+            self.closing = true;
+            self.drop_stream();
+            return Ok(());
+        }
+
+        let deadline = Some(Instant::now() + timeout);
 
-        try!(self.try_connect());
-        try!(self.write_request(&*nonce));
+        // Messages queued so far go out before close frame, within the same deadline
+        let flushed = match self.set_deadline(deadline) {
+            Ok(()) => self.flush_queue(),
+            Err(e) => Err(WSError::Io(e))
+        };
+        self.closing = true;
 
-        nonce = nonce.encode();
-        try!(self.read_response(&*nonce));
+        // Idle timeout handling would interfere with deadline, so turn it off for a while
+        let idle_timeout = self.idle_timeout.take();
+        let result = self.close_handshake(deadline);
+        self.idle_timeout = idle_timeout;
 
-        Ok(())
+        self.drop_stream();
+
+        flushed.and(result)
     }
 
-    fn read_header(&mut self) -> io::Result<WSHeader> {
-        let h: u16;
-        try!(self.read(mem::transmute(h)));
-        Ok(WSHeader::from_bits_truncate(h.to_le()))
+    /// Half-close connection: send close frame and refuse further sends, but keep reading
+    /// messages peer has sent before seeing our close, until its close frame arrives,
+    /// then connection is dropped and peer's close message is returned by `read_message()`
+    pub fn close_send(&mut self, status: WSStatusCode, reason: &[u8]) -> WSResult<()> {
+        if self.closing {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "connection is shutting down")));
+        }
+
+        self.flush_queue()?;
+        self.write_message(&WSMessage::close(status, reason).mask())?;
+        self.closing = true;
+        self.drained = Some(0);
+        Ok(())
     }
 
-    fn read_length(&mut self, header: &WSHeader) -> io::Result<u64> {
-        let wslen = *header & WS_LEN;
-        if wslen == WS_LEN16 { self.read_be_u16().map(|v| v as u64) }
-        else if wslen == WS_LEN64 { self.read_be_u64() }
-        else { Ok(wslen.bits() as u64) }
+    /// Max number of messages to read after `close_send()` while waiting for peer's close,
+    /// connection is dropped with error once it's exceeded, `None` means no limit
+    #[inline] pub fn set_close_drain_limit(&mut self, limit: Option<usize>) {
+        self.drain_limit = limit;
     }
 
-    pub fn read_message(&mut self) -> io::Result<WSMessage> {
-        let header = try!(self.read_header());
-        let mut len = try!(self.read_length(&header));
+    // Handle message received in half-closed state
+    fn drain(&mut self, msg: WSMessage) -> WSResult<WSMessage> {
+        let drained = self.drained.unwrap_or(0) + 1;
 
-        let mask = if header.contains(WS_MASK) {
-            Some(try!(self.read_be_u32()))
+        if msg.is_close() {
+            self.drained = None;
+            self.drop_stream();
+            Ok(msg)
+        } else if self.drain_limit.map(|limit| drained > limit).unwrap_or(false) {
+            self.drained = None;
+            self.drop_stream();
+            Err(WSError::Io(io::Error::new(io::ErrorKind::TimedOut, "peer hasn't closed connection in time")))
         } else {
-            None
-        };
+            self.drained = Some(drained);
+            Ok(msg)
+        }
+    }
 
-        // If this is the terminating frame (close command),
-        // first two bytes of data MUST BE u16 status code
-        let mut status = if header.contains(WS_OPTERM) {
-            // compensate length of status code
-            len = len - 2;
-            Some(try!(self.read_be_u16()))
-        } else {
-            None
-        };
+    // Fail connection (RFC 6455, 7.1.7): tell peer why with close frame, if possible,
+    // and drop connection without waiting for reply, as stream can't be read any further
+    fn fail(&mut self, status: WSStatusCode, err: WSError) -> WSError {
+        if !self.close_sent {
+            let _ = self.write_message(&WSMessage::close(status, b"").mask());
+            let _ = self.flush();
+        }
+        self.closing = true;
+        self.drop_stream();
+        err
+    }
+
+    fn drop_stream(&mut self) {
+        if let Some(ref s) = self.stream {
+            let _ = s.get_ref().shutdown(Shutdown::Both);
+        }
+        self.stream = None;
+    }
 
-        let mut data = try!(self.read_exact(len as usize));
+    // Close frame is not sent again after `close_send()`, only peer's close is waited for
+    fn close_handshake(&mut self, deadline: Option<Instant>) -> WSResult<()> {
+        self.set_deadline(deadline)?;
+        self.flush()?;
+        if !self.close_sent {
+            self.write_message(&WSMessage::close(WSStatusCode::NoError, b"").mask())?;
+        }
 
-        // If we have mask, decrypt data
-        if let Some(mut m) = mask {
-            // decrypt status if present
-            if let Some(s) = status {
-                status = Some(s ^ (m & 0xffff) as u16);
-                // compensate the usage of two mask bytes
-                m = m.rotate_right(16);
+        loop {
+            self.set_deadline(deadline)?;
+            if self.receive()?.is_close() {
+                return Ok(());
             }
-            data = WebSocket::mask_data(&*data, m);
         }
+    }
+
+    /// Finish WebSocket session and get underlying stream back, e.g. to switch protocols.
+    /// Write buffer is flushed, but any data already read into buffer and not consumed is lost.
+    pub fn into_inner(self) -> io::Result<S> {
+        match self.stream {
+            Some(s) => s.into_inner(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
+        }
+    }
 
-        Ok(WSMessage { header: header, data: data, status: status.and_then(FromPrimitive::from_u16) })
+    #[inline] fn io(&mut self) -> RawIo<'_, S> {
+        RawIo { stream: &mut self.stream }
     }
 
-    fn mask_data(data: &[u8], mask: u32) -> Vec<u8> {
-        data.iter().enumerate().map(|(i, b)| *b ^ (mask >> ((i % 4) << 3) & 0xff) as u8).collect::<Vec<u8>>()
+    /// Escape hatch to read and write raw bytes of the connection, bypassing framing.
+    /// Reading or writing in the middle of a frame desynchronizes the framing,
+    /// so message level methods are only usable again if raw I/O stops at frame boundary.
+    #[inline] pub(crate) fn raw_io(&mut self) -> RawIo<'_, S> {
+        self.io()
     }
 
-    pub fn send_message(&mut self, msg: &WSMessage) -> io::Result<()> {
-        let mut len = msg.data.len() as u64;
-        let mut hdr = msg.header - WS_LEN;
+    /// Send messages accumulated in write buffer (see `set_auto_flush()`)
+    #[inline] pub fn flush(&mut self) -> io::Result<()> {
+        self.io().flush()
+    }
 
-        // If we have status set, the data length is increased by status size
-        if msg.status.is_some() {
-            len = len + 2;
+    pub(crate) fn iter(&mut self) -> WSMessages<'_, S> {
+        WSMessages { sock: self, error: None }
+    }
+}
+
+/// Writer streaming a single message as fragments, see `Connection::message_writer()`.
+/// Data is held back until more of it comes in, so that the last fragment is not empty,
+/// `finish()` MUST be called to send it, otherwise message is left incomplete
+pub struct MessageWriter<'a, S: 'a = NetworkStream> {
+    sock: &'a mut WebSocket<S>,
+    header: WSHeader,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    started: bool
+}
+
+impl<'a, S: Stream> MessageWriter<'a, S> {
+    // The first fragment carries message opcode, the rest are continuations
+    fn send_fragment(&mut self, len: usize, fin: bool) -> WSResult<()> {
+        let mut header = if self.started { self.header - WS_OPCODE } else { self.header };
+        if fin {
+            header.insert(WS_FIN);
         }
 
-        // Encode and send length along with header
-        if len < WS_LEN16.bits() as u64 {
-            hdr = hdr | WSHeader::from_bits_truncate(len as u16 & WS_LEN.bits());
-            try!(self.write_all(mem::transmute(hdr.bits().to_be())));
+        // Let control frames queued meanwhile go out between fragments
+        while let Some(frame) = self.sock.outgoing.pop_control() {
+            self.sock.send_message(&frame)?;
+        }
 
-        } else if len < u16::MAX as u64 {
-            hdr = hdr | WS_LEN16;
-            try!(self.write_all(mem::transmute(hdr.bits().to_be())));
-            try!(self.write_all(mem::transmute(len as u16)));
+        self.sock.send_data(header, None, &self.buf[..len])?;
+        self.started = true;
+        self.buf.drain(..len);
+        Ok(())
+    }
 
-        } else {
-            hdr = hdr | WS_LEN64;
-            try!(self.write_all(mem::transmute(hdr.bits().to_be())));
-            try!(self.write_all(mem::transmute((len as u64).to_be())));
+    /// Send control frame (ping, pong or close) in between fragments of the message
+    pub fn send_control(&mut self, frame: &WSMessage) -> WSResult<()> {
+        if !frame.is_control() {
+            return Err(WSError::InvalidInput("only control frames can be sent in the middle of a message"));
         }
+        self.sock.send_message(frame)
+    }
 
-        // If user required masking, encrypt all data
-        if hdr.contains(WS_MASK) {
-            // Generate and send random mask
-            let mut mask = thread_rng().gen::<u32>();
-            try!(self.write_all(mem::transmute(mask.to_be())));
+    /// Queue control frame to be sent before the next fragment
+    #[inline] pub fn queue_control(&mut self, frame: WSMessage) {
+        // Control frames are never refused
+        let _ = self.sock.outgoing.push(frame, 0);
+    }
 
-            // Encrypt status code if present
-            if let Some(status) = msg.status {
-                try!(self.write_all(mem::transmute((status.to_u16().unwrap() ^ (mask & 0xffff) as u16).to_be())));
-                // compensate for mask already used for status encryption
-                mask = mask.rotate_right(16);
-            }
+    /// Send what's left as the final fragment
+    pub fn finish(mut self) -> WSResult<()> {
+        let len = self.buf.len();
+        self.send_fragment(len, true)
+    }
+}
 
-            try!(self.write_all(&*WebSocket::mask_data(&*msg.data, mask)));
-        } else {
-            // Send status code if present
-            if let Some(status) = msg.status {
-                try!(self.write_all(mem::transmute(status.to_u16().unwrap().to_be())));
-            }
-            try!(self.write_all(&*msg.data));
+impl<'a, S: Stream> Write for MessageWriter<'a, S> {
+    // Buffer never grows over chunk size: it's sent once it's full and more data comes,
+    // and only what fits into it is taken
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
         }
 
-        self.flush()
+        if self.buf.len() >= self.chunk_size {
+            let len = self.buf.len();
+            self.send_fragment(len, false)?;
+        }
+
+        let n = cmp::min(data.len(), self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
     }
 
-    pub fn iter(&mut self) -> WSMessages {
-        WSMessages { sock: self }
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
     }
 }
 
-impl Read for WebSocket {
+/// Raw byte level access to connection, bypassing framing, see `Connection::raw_io()`
+pub struct RawIo<'a, S: 'a = NetworkStream> {
+    stream: &'a mut Option<BufferedStream<S>>
+}
+
+impl<'a, S: Read + Write> Read for RawIo<'a, S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.stream {
+        match *self.stream {
             Some(ref mut s) => s.read(buf),
-            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None))
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
         }
     }
 }
 
-impl Write for WebSocket {
+impl<'a, S: Read + Write> Write for RawIo<'a, S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.stream {
+        match *self.stream {
             Some(ref mut s) => s.write(buf),
-            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None))
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self.stream {
+        match *self.stream {
             Some(ref mut s) => s.flush(),
-            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None))
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
         }
     }
 }
 
-impl BufRead for WebSocket {
-    fn fill_buf<'a>(&'a mut self) -> io::Result<&'a [u8]> {
-        match self.stream {
+impl<'a, S: Read + Write> BufRead for RawIo<'a, S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match *self.stream {
             Some(ref mut s) => s.fill_buf(),
-            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected", None))
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "client not connected"))
         }
     }
 
     fn consume(&mut self, amt: usize) {
+        if let Some(ref mut s) = *self.stream {
+            s.consume(amt);
+        }
+    }
+}
+
+/// Returns -1 for not connected socket, so registering it with poller fails
+#[cfg(unix)]
+impl AsRawFd for WebSocket {
+    fn as_raw_fd(&self) -> RawFd {
         match self.stream {
-            Some(ref mut s) => s.consume(amt),
-            None => ()
+            Some(ref s) => s.get_ref().as_raw_fd(),
+            None => -1
         }
     }
 }
 
-pub struct WSMessages<'a> {
-    sock: &'a mut WebSocket
+pub struct WSMessages<'a, S: 'a = NetworkStream> {
+    sock: &'a mut WebSocket<S>,
+    error: Option<WSError>
 }
 
-pub struct WSDefragMessages<'a> {
-    underlying: &'a mut WSMessages<'a>,
+pub struct WSDefragMessages<'a, S: 'a = NetworkStream> {
+    underlying: &'a mut WSMessages<'a, S>,
     buffer: WSMessage
 }
 
-impl<'a> WSMessages<'a> {
-    pub fn defrag(&'a mut self) -> WSDefragMessages<'a> {
-        WSDefragMessages{ underlying: self, buffer: WSMessage{ header: WSHeader::empty(), data: Vec::new(), status: None } }
+impl<'a, S: Stream> WSMessages<'a, S> {
+    /// Error which has terminated iteration, if any
+    pub fn take_error(&mut self) -> Option<WSError> {
+        self.error.take()
+    }
+
+    pub fn defrag(&'a mut self) -> WSDefragMessages<'a, S> {
+        WSDefragMessages{ underlying: self, buffer: WSMessage{ header: WSHeader::empty(), data: Vec::new(), status: None, received: None } }
     }
 }
 
-impl<'a> Iterator for WSMessages<'a> {
+impl<'a, S: Stream> Iterator for WSMessages<'a, S> {
     type Item = WSMessage;
     fn next(&mut self) -> Option<WSMessage> {
-        self.sock.read_message().ok()
+        match self.sock.read_message() {
+            Ok(msg) => Some(msg),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
     }
 }
 
-impl<'a> WSDefragMessages<'a> {
+impl<'a, S: Stream> WSDefragMessages<'a, S> {
     fn popbuf(&mut self) -> Option<WSMessage> {
         if self.buffer.data.is_empty() {
             None
         } else {
-            let mut buf = WSMessage{ header: WSHeader::empty(), data: Vec::new(), status: None };
+            let mut buf = WSMessage{ header: WSHeader::empty(), data: Vec::new(), status: None, received: None };
             mem::swap(&mut self.buffer, &mut buf);
             Some(buf)
         }
@@ -304,7 +1992,7 @@ impl<'a> WSDefragMessages<'a> {
     }
 }
 
-impl<'a> Iterator for WSDefragMessages<'a> {
+impl<'a, S: Stream> Iterator for WSDefragMessages<'a, S> {
     type Item = WSMessage;
     fn next(&mut self) -> Option<WSMessage> {
         loop {
@@ -319,7 +2007,8 @@ impl<'a> Iterator for WSDefragMessages<'a> {
                         self.buffer.push(msg);
                     } else if msg.is_last() {
                         self.buffer.push(msg);
-                        return self.popbuf().map(|v| v.last());
+                        // Keep opcode of the first fragment, so we know what we've got
+                        return self.popbuf().map(|mut v| { v.header.insert(WS_FIN); v });
                     }
                 }
             }
@@ -327,3 +2016,394 @@ impl<'a> Iterator for WSDefragMessages<'a> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use url::Url;
+
+    use mock;
+    use super::{WebSocket, Endpoint, MAX_REJECT_BODY};
+    use clock::{Clock, MockClock};
+    use error::WSError;
+    use message::{WSMessage, WSStatusCode, WS_OPBIN};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn file_is_sent_after_buffered_data() {
+        use std::io::{Read, Write, Seek, SeekFrom, BufRead, BufReader};
+        use std::net::TcpListener;
+        use std::fs::{self, OpenOptions};
+        use std::thread;
+        use nonce::compute_accept;
+        use message::WS_FIN;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut key = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if line.to_lowercase().starts_with("sec-websocket-key:") {
+                    key = line[18..].trim().to_string();
+                }
+            }
+            write!(&stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", compute_accept(&*key)).unwrap();
+
+            let mut data = vec![0u8; 4 + 2 + 4 + 1000];
+            reader.read_exact(&mut data).unwrap();
+            data
+        });
+
+        let path = ::std::env::temp_dir().join(format!("bare-websocket-sendfile-{}", ::std::process::id()));
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(&*vec![0x55; 1010]).unwrap();
+        file.seek(SeekFrom::Start(10)).unwrap();
+
+        let mut ws = WebSocket::new(Url::parse(&*format!("ws://{}/", addr)).unwrap());
+        ws.connect().unwrap();
+        ws.set_auto_flush(false);
+        ws.send_message(&WSMessage::text("text")).unwrap();
+        assert_eq!(ws.send_file(WS_OPBIN | WS_FIN, &mut file).unwrap(), 1000);
+        assert_eq!(file.stream_position().unwrap(), 1010);
+        fs::remove_file(&path).unwrap();
+
+        let data = server.join().unwrap();
+        assert_eq!(&data[..6], b"\x81\x04text");
+        assert_eq!(&data[6..10], &[0x82, 126, 0x03, 0xe8]);
+        assert!(data[10..].iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn wake_poll_keeps_read_timeout() {
+        let (mut ws, server) = mock::connect();
+        let _wake = ws.wake_handle();
+        ws.set_read_timeout(Some(Duration::from_millis(30))).unwrap();
+
+        let started = Instant::now();
+        match ws.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+            other => panic!("expected timeout, got {:?}", other)
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(server.read_timeouts().last(), Some(&Some(Duration::from_millis(30))));
+        assert_eq!(server.write_timeouts().last(), Some(&None));
+    }
+    #[test]
+    fn shutdown_with_wake_handle_stops_at_deadline() {
+        let (mut ws, _server) = mock::connect();
+        let _wake = ws.wake_handle();
+
+        let started = Instant::now();
+        assert!(ws.shutdown(Duration::from_millis(30)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn restart_delay_hint_is_limited() {
+        let (mut ws, server) = mock::connect();
+        let clock = MockClock::new();
+        let started = clock.now();
+        ws.set_clock(Arc::new(clock.clone()));
+        ws.set_restart_reconnect(Some(Duration::from_secs(1)));
+        ws.set_max_restart_delay(Duration::from_secs(30));
+
+        let mut close = vec![0x03, 0xf4];
+        close.extend_from_slice(b"99999999999");
+        server.push(&mock::frame(0x88, &*close));
+
+        // Mock transport can't be reconnected by hostname
+        assert!(ws.read_message().is_err());
+        assert_eq!(clock.now() - started, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn rejection_body_is_limited() {
+        let mut response = b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\n100000\r\n".to_vec();
+        response.extend_from_slice(&vec![b'x'; 0x100000]);
+        response.extend_from_slice(b"\r\n0\r\n\r\n");
+
+        match mock::handshake(&*response) {
+            Err(WSError::Handshake(ref e)) => {
+                assert_eq!(e.status, Some(403));
+                assert_eq!(e.body.len(), MAX_REJECT_BODY);
+            },
+            other => panic!("expected rejection, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn huge_chunk_is_not_allocated() {
+        match mock::handshake(b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffff\r\nxx") {
+            Err(WSError::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("expected truncated body, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn borrowed_messages_are_masked() {
+        let (mut ws, server) = mock::connect();
+        ws.send_text("hi").unwrap();
+        ws.send_binary(b"\x01").unwrap();
+
+        let written = server.written();
+        assert_eq!(&written[..2], &[0x81, 0x82]);
+        assert_eq!(&written[8..10], &[0x82, 0x81]);
+    }
+
+    #[test]
+    fn queued_messages_are_sent_before_close() {
+        let (mut ws, server) = mock::connect();
+        ws.queue_message(WSMessage::text("bye").mask(), 0).unwrap();
+        server.push(&*mock::frame(0x88, b"\x03\xe8"));
+
+        ws.shutdown(Duration::from_secs(1)).unwrap();
+        let written = server.written();
+        assert_eq!(&written[..2], &[0x81, 0x83]);
+        assert_eq!(written[9], 0x88);
+    }
+
+    #[test]
+    fn queued_messages_are_sent_before_half_close() {
+        let (mut ws, server) = mock::connect();
+        ws.queue_message(WSMessage::text("bye").mask(), 0).unwrap();
+
+        ws.close_send(WSStatusCode::NoError, b"").unwrap();
+        let written = server.written();
+        assert_eq!(&written[..2], &[0x81, 0x83]);
+        assert_eq!(written[9], 0x88);
+        assert_eq!(ws.queued(), 0);
+    }
+
+    #[test]
+    fn fixed_buffers_are_not_allocated_beyond_count() {
+        let (mut ws, server) = mock::connect();
+        ws.set_fixed_buffers(16, 1);
+        server.push(&*mock::frame(0x81, b"one"));
+        server.push(&*mock::frame(0x81, b"two"));
+
+        let first = ws.read_message().unwrap();
+        match ws.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::OutOfMemory => (),
+            other => panic!("expected out of memory error, got {:?}", other)
+        }
+
+        ws.recycle(first);
+        assert_eq!(ws.read_message().unwrap().as_text().unwrap(), "two");
+    }
+
+    #[test]
+    fn message_writer_buffer_is_limited() {
+        use std::io::Write;
+
+        let (mut ws, server) = mock::connect();
+        ws.set_fixed_buffers(4, 1);
+        {
+            let mut writer = ws.message_writer(WS_OPBIN, 100);
+            writer.write_all(b"0123456789").unwrap();
+            assert!(writer.buf.capacity() <= 4);
+            writer.finish().unwrap();
+        }
+
+        // Fragments of 4, 4 and 2 bytes
+        let written = server.written();
+        assert_eq!(&written[..2], &[0x02, 0x04]);
+        assert_eq!(&written[6..8], &[0x00, 0x04]);
+        assert_eq!(&written[12..14], &[0x80, 0x02]);
+    }
+
+    #[test]
+    fn idle_peer_is_disconnected() {
+        let (mut ws, server) = mock::connect();
+        ws.set_idle_timeout(Some(Duration::from_millis(10)), false);
+
+        match ws.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+            other => panic!("expected timeout, got {:?}", other)
+        }
+        assert!(server.is_shut_down());
+        assert_eq!(&server.written()[..2], &[0x88, 0x82]);
+    }
+
+    #[test]
+    fn legacy_reads_are_woken_and_timed_out() {
+        let (mut ws, server) = mock::connect();
+        ws.legacy = true;
+        let wake = ws.wake_handle();
+        wake.wake();
+        match ws.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::Interrupted => (),
+            other => panic!("expected interrupted read, got {:?}", other)
+        }
+
+        server.push(b"\x00hi\xff");
+        assert_eq!(&*ws.read_message().unwrap().data, b"hi");
+
+        ws.set_idle_timeout(Some(Duration::from_millis(10)), true);
+        match ws.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+            other => panic!("expected timeout, got {:?}", other)
+        }
+        assert!(server.is_shut_down());
+        assert_eq!(server.written(), [0xff, 0x00]);
+    }
+
+    #[test]
+    fn frame_interrupted_by_timeout_is_resumed() {
+        let (mut ws, server) = mock::connect();
+        let frame = mock::frame(0x82, &[7u8; 300]);
+        server.push(&frame[..3]);
+        server.push_timeout();
+        server.push(&frame[3..100]);
+        server.push_timeout();
+        server.push(&frame[100..]);
+
+        for _ in 0..2 {
+            match ws.read_message() {
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+                other => panic!("expected timeout, got {:?}", other)
+            }
+        }
+        let msg = ws.read_message().unwrap();
+        assert_eq!(&*msg.data, &[7u8; 300][..]);
+    }
+
+    #[test]
+    fn read_timeout_is_kept_while_waiting_for_idle_peer() {
+        let (mut ws, server) = mock::connect();
+        ws.set_idle_timeout(Some(Duration::from_secs(10)), false);
+        ws.set_read_timeout(Some(Duration::from_millis(30))).unwrap();
+        assert_eq!(ws.read_timeout(), Some(Duration::from_millis(30)));
+
+        server.push(&mock::frame(0x81, b"hello"));
+        assert_eq!(&*ws.read_message().unwrap().data, b"hello");
+        assert_eq!(server.read_timeouts().last(), Some(&Some(Duration::from_millis(30))));
+    }
+
+    #[test]
+    fn target_is_used_as_given() {
+        let ws = WebSocket::with_target("::1", 8080, "/a%zz?b=[c]", false).unwrap();
+        assert_eq!(ws.url(), None);
+        assert_eq!(ws.endpoint().address(), "[::1]:8080");
+        assert_eq!(ws.endpoint().origin(), "ws://[::1]:8080/a%zz?b=[c]");
+
+        let ws = WebSocket::with_target("Bücher.example", 443, "", true).unwrap();
+        assert_eq!(ws.endpoint().host_header(), "Bücher.example");
+        assert_eq!(ws.endpoint().path(), "/");
+
+        for &(host, path) in &[("", "/"), ("a b", "/"), ("a/b", "/"), ("a\r\n", "/"), ("a", "/x y"), ("a", "/\r\n")] {
+            match WebSocket::with_target(host, 80, path, false) {
+                Err(WSError::InvalidInput(..)) => (),
+                other => panic!("expected {:?} {:?} to be rejected, got {:?}", host, path, other.map(|_| ()))
+            }
+        }
+
+        let (stream, server) = mock::pair();
+        let endpoint = Endpoint::Target { host: "[fe80::1]".to_string(), port: 8000, path: "/a%zz".to_string(), secure: false };
+        let mut ws = WebSocket::with_endpoint(endpoint, 13, None, None);
+        ws.connect_over(stream).unwrap();
+        let request = server.request();
+        assert!(request.starts_with("GET /a%zz HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nHost: [fe80::1]:8000\r\n"));
+    }
+
+    #[test]
+    fn reserved_and_malformed_headers_are_rejected() {
+        let mut ws = WebSocket::new(Url::parse("ws://localhost/").unwrap());
+        ws.set_header("Accept-Language", "en").unwrap();
+        assert_eq!(ws.header("accept-language"), Some("en"));
+
+        for &(name, value) in &[("Host", "evil"), ("sec-websocket-key", "x"), ("Connection", "close"),
+                                ("X-Foo", "a\r\nHost: evil"), ("X Foo", "a"), ("", "a")] {
+            match ws.set_header(name, value) {
+                Err(WSError::InvalidInput(..)) => (),
+                other => panic!("expected {:?} to be rejected, got {:?}", name, other)
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_close_and_control_frames_fail_connection() {
+        let frames = [mock::frame(0x88, b"\x03"), mock::frame(0x88, b"\x13\x88"), mock::frame(0x88, b"\x03\xee"),
+                      mock::frame(0x88, b"\x07\xd0"), mock::frame(0x09, b""), mock::frame(0x89, &[0; 126])];
+        for frame in frames.iter() {
+            let (mut ws, server) = mock::connect();
+            server.push(&**frame);
+
+            match ws.read_message() {
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => (),
+                other => panic!("expected protocol error for {:?}, got {:?}", &frame[..2], other)
+            }
+            let written = server.written();
+            assert_eq!(written[0], 0x88);
+            assert_eq!([written[6] ^ written[2], written[7] ^ written[3]], [0x03, 0xea]);
+            assert!(server.is_shut_down());
+        }
+    }
+
+    #[test]
+    fn close_without_status_is_accepted() {
+        let (mut ws, server) = mock::connect();
+        server.push(&*mock::frame(0x88, b""));
+        server.push(&*mock::frame(0x88, b"\x0f\xa0bye"));
+
+        assert!(ws.read_message().unwrap().status.is_none());
+        let msg = ws.read_message().unwrap();
+        assert_eq!(msg.status.and_then(|s| s.to_u16()), Some(4000));
+        assert_eq!(&*msg.data, b"bye");
+    }
+
+    #[test]
+    fn idle_time_is_measured_by_clock() {
+        let (mut ws, server) = mock::connect();
+        let clock = MockClock::new();
+        ws.set_clock(Arc::new(clock.clone()));
+        ws.set_idle_timeout(Some(Duration::from_millis(10)), false);
+
+        // Real time passing doesn't count
+        ::std::thread::sleep(Duration::from_millis(20));
+        server.push(&*mock::frame(0x81, b"hi"));
+        assert_eq!(&*ws.read_message().unwrap().data, b"hi");
+
+        clock.advance(Duration::from_millis(10));
+        match ws.read_message() {
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+            other => panic!("expected idle timeout, got {:?}", other)
+        }
+        assert!(server.is_shut_down());
+    }
+
+    #[test]
+    fn shutdown_after_half_close_sends_single_close() {
+        let (mut ws, server) = mock::connect();
+        ws.close_send(WSStatusCode::NoError, b"").unwrap();
+        server.push(&*mock::frame(0x81, b"late"));
+        server.push(&*mock::frame(0x88, b"\x03\xe8"));
+
+        ws.shutdown(Duration::from_secs(1)).unwrap();
+        let written = server.written();
+        assert_eq!(written.len(), 8);
+        assert_eq!(written[0], 0x88);
+        assert!(server.is_shut_down());
+    }
+
+    #[test]
+    fn shutdown_after_peer_close_is_drained() {
+        let (mut ws, server) = mock::connect();
+        ws.close_send(WSStatusCode::NoError, b"").unwrap();
+        server.push(&*mock::frame(0x88, b"\x03\xe8"));
+
+        assert!(ws.read_message().unwrap().is_close());
+        ws.shutdown(Duration::from_secs(1)).unwrap();
+        assert_eq!(server.written().len(), 8);
+    }
+}
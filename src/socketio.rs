@@ -0,0 +1,254 @@
+//! engine.io and basic socket.io client protocol.
+//!
+//! Only websocket transport of engine.io is supported (there's no HTTP client in this crate
+//! to start with long-polling and upgrade), which all engine.io servers accept directly
+//! when connected to with `transport=websocket` query, see `engine_url()`.
+//!
+//! socket.io v3 and later servers (engine.io protocol 4) only deliver events after
+//! client joins a namespace with CONNECT packet, e.g. `send("0")` for the main one.
+
+use std::io;
+use std::time::Duration;
+use url::{Url, ParseError};
+use rustc_serialize::json::Json;
+
+use socket::WebSocket;
+use message::{WSMessage, Defragmenter, WS_FIN, WS_OPTEXT, WS_MASK};
+use error::{WSError, WSResult};
+
+/// URL of engine.io websocket endpoint at given server (e.g. "ws://localhost:3000"),
+/// `version` is engine.io protocol version: 4 for socket.io v3 and later, 3 for v2
+pub fn engine_url(server: &str, version: u32) -> Result<Url, ParseError> {
+    Url::parse(&*format!("{}/socket.io/?EIO={}&transport=websocket", server.trim_end_matches('/'), version))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnginePacket {
+    Open(String),
+    Close,
+    Ping(String),
+    Pong(String),
+    Message(String),
+    Upgrade,
+    Noop
+}
+
+impl EnginePacket {
+    pub fn parse(data: &str) -> Option<EnginePacket> {
+        let mut chars = data.chars();
+        let kind = chars.next();
+        let payload = chars.as_str().to_string();
+        match kind {
+            Some('0') => Some(EnginePacket::Open(payload)),
+            Some('1') => Some(EnginePacket::Close),
+            Some('2') => Some(EnginePacket::Ping(payload)),
+            Some('3') => Some(EnginePacket::Pong(payload)),
+            Some('4') => Some(EnginePacket::Message(payload)),
+            Some('5') => Some(EnginePacket::Upgrade),
+            Some('6') => Some(EnginePacket::Noop),
+            _ => None
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match *self {
+            EnginePacket::Open(ref data) => format!("0{}", data),
+            EnginePacket::Close => "1".to_string(),
+            EnginePacket::Ping(ref data) => format!("2{}", data),
+            EnginePacket::Pong(ref data) => format!("3{}", data),
+            EnginePacket::Message(ref data) => format!("4{}", data),
+            EnginePacket::Upgrade => "5".to_string(),
+            EnginePacket::Noop => "6".to_string()
+        }
+    }
+}
+
+/// socket.io event, carried in engine.io message packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketIoEvent {
+    pub namespace: Option<String>,
+    pub name: String,
+    pub args: Vec<Json>,
+    pub ack: Option<u64>
+}
+
+impl SocketIoEvent {
+    pub fn new(name: &str, args: Vec<Json>) -> SocketIoEvent {
+        SocketIoEvent { namespace: None, name: name.to_string(), args: args, ack: None }
+    }
+
+    /// Encode as socket.io EVENT packet: `2[/nsp,][ack]["name",args...]`
+    pub fn encode(&self) -> String {
+        let mut array = vec![Json::String(self.name.clone())];
+        array.extend_from_slice(&*self.args);
+
+        let mut packet = "2".to_string();
+        if let Some(ref nsp) = self.namespace {
+            packet.push_str(&**nsp);
+            packet.push(',');
+        }
+        if let Some(ack) = self.ack {
+            packet.push_str(&*ack.to_string());
+        }
+        packet.push_str(&*Json::Array(array).to_string());
+        packet
+    }
+
+    /// Parse socket.io EVENT packet, other packet types are not events
+    pub fn parse(packet: &str) -> Option<SocketIoEvent> {
+        if !packet.starts_with("2") {
+            return None;
+        }
+        let mut rest = &packet[1..];
+
+        let namespace = if rest.starts_with("/") {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let nsp = rest[..end].to_string();
+            rest = if end < rest.len() { &rest[end + 1..] } else { "" };
+            Some(nsp)
+        } else {
+            None
+        };
+
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let ack = rest[..digits].parse::<u64>().ok();
+        rest = &rest[digits..];
+
+        match rest.parse::<Json>() {
+            Ok(Json::Array(mut array)) => match if array.is_empty() { None } else { Some(array.remove(0)) } {
+                Some(Json::String(name)) => Some(SocketIoEvent { namespace: namespace, name: name, args: array, ack: ack }),
+                _ => None
+            },
+            _ => None
+        }
+    }
+}
+
+/// socket.io client over connected engine.io websocket
+pub struct SocketIoClient {
+    ws: WebSocket,
+    sid: Option<String>,
+    ping_interval: Option<Duration>,
+    defrag: Defragmenter
+}
+
+impl SocketIoClient {
+    pub fn new(ws: WebSocket) -> SocketIoClient {
+        SocketIoClient { ws: ws, sid: None, ping_interval: None, defrag: Defragmenter::new() }
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    /// engine.io session id, known after `open()`
+    #[inline] pub fn sid(&self) -> Option<&str> {
+        self.sid.as_deref()
+    }
+
+    /// Interval to call `ping()` with, as requested by server in open packet
+    #[inline] pub fn ping_interval(&self) -> Option<Duration> {
+        self.ping_interval
+    }
+
+    fn send_packet(&mut self, packet: &EnginePacket) -> WSResult<()> {
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, packet.encode().as_bytes())
+    }
+
+    fn recv_packet(&mut self) -> WSResult<EnginePacket> {
+        loop {
+            let msg = match self.defrag.push(self.ws.read_message()?)? {
+                Some(msg) => msg,
+                None => continue
+            };
+            if msg.is_ping() {
+                self.ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+                continue;
+            }
+            if msg.is_close() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+            }
+            if !msg.is_text() {
+                continue;
+            }
+            if let Some(packet) = EnginePacket::parse(msg.as_text()?) {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Wait for engine.io open packet, returns its handshake data
+    pub fn open(&mut self) -> WSResult<Json> {
+        loop {
+            if let EnginePacket::Open(data) = self.recv_packet()? {
+                let handshake = data.parse::<Json>().map_err(|_| WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "invalid engine.io handshake")))?;
+                self.sid = handshake.find("sid").and_then(|v| v.as_string()).map(|v| v.to_string());
+                self.ping_interval = handshake.find("pingInterval").and_then(|v| v.as_u64()).map(Duration::from_millis);
+                return Ok(handshake);
+            }
+        }
+    }
+
+    /// Send engine.io ping, should be called every `ping_interval()` with engine.io protocol 3,
+    /// with protocol 4 server pings client instead, and `recv()` answers them
+    pub fn ping(&mut self) -> WSResult<()> {
+        self.send_packet(&EnginePacket::Ping(String::new()))
+    }
+
+    /// Send raw socket.io packet
+    pub fn send(&mut self, packet: &str) -> WSResult<()> {
+        self.send_packet(&EnginePacket::Message(packet.to_string()))
+    }
+
+    pub fn emit(&mut self, event: &str, args: Vec<Json>) -> WSResult<()> {
+        let packet = SocketIoEvent::new(event, args).encode();
+        self.send(&*packet)
+    }
+
+    /// Receive next socket.io event, answering engine.io pings on the way
+    pub fn recv(&mut self) -> WSResult<SocketIoEvent> {
+        loop {
+            match self.recv_packet()? {
+                EnginePacket::Ping(data) => self.send_packet(&EnginePacket::Pong(data))?,
+                EnginePacket::Close => return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "engine.io session closed"))),
+                EnginePacket::Message(packet) => if let Some(event) = SocketIoEvent::parse(&*packet) {
+                    return Ok(event);
+                },
+                _ => ()
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> WSResult<()> {
+        self.send_packet(&EnginePacket::Close)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{engine_url, EnginePacket};
+
+    #[test]
+    fn engine_url_has_protocol_version() {
+        assert_eq!(engine_url("ws://localhost:3000/", 4).unwrap().as_str(), "ws://localhost:3000/socket.io/?EIO=4&transport=websocket");
+    }
+
+    #[test]
+    fn engine_packets_are_parsed() {
+        assert_eq!(EnginePacket::parse("2probe"), Some(EnginePacket::Ping("probe".to_string())));
+        assert_eq!(EnginePacket::parse("4héllo"), Some(EnginePacket::Message("héllo".to_string())));
+        assert_eq!(EnginePacket::parse("6"), Some(EnginePacket::Noop));
+        assert_eq!(EnginePacket::parse(""), None);
+    }
+
+    #[test]
+    fn multibyte_packet_type_does_not_panic() {
+        assert_eq!(EnginePacket::parse("é"), None);
+        assert_eq!(EnginePacket::parse("éa"), None);
+    }
+}
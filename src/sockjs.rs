@@ -0,0 +1,166 @@
+//! SockJS client protocol over its websocket transport.
+//!
+//! The `/info` request is not done, as it's only needed to choose between transports,
+//! and only websocket one is supported here (there's no HTTP client in this crate
+//! for streaming and polling ones). Connect WebSocket to `session_url()` and wrap into `SockJsClient`.
+
+use std::io;
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+use url::{Url, ParseError};
+use rustc_serialize::json::Json;
+
+use socket::WebSocket;
+use message::{WSMessage, Defragmenter, WS_FIN, WS_OPTEXT, WS_MASK};
+use error::{WSError, WSResult};
+
+/// URL of websocket transport for a new session at given SockJS endpoint
+/// (e.g. "ws://localhost:8080/echo"), server and session ids are random
+pub fn session_url(endpoint: &str) -> Result<Url, ParseError> {
+    let mut rng = rand::thread_rng();
+    let server_id = rng.gen_range(0u32..1000);
+    let session_id = rng.sample_iter(&Alphanumeric).take(16).map(char::from).collect::<String>();
+    Url::parse(&*format!("{}/{:03}/{}/websocket", endpoint.trim_end_matches('/'), server_id, session_id))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SockJsFrame {
+    Open,
+    Heartbeat,
+    Messages(Vec<String>),
+    Close(u16, String)
+}
+
+impl SockJsFrame {
+    pub fn parse(data: &str) -> Option<SockJsFrame> {
+        match data.chars().next() {
+            Some('o') => Some(SockJsFrame::Open),
+            Some('h') => Some(SockJsFrame::Heartbeat),
+            Some('a') => match data[1..].parse::<Json>() {
+                Ok(Json::Array(items)) => Some(SockJsFrame::Messages(
+                    items.into_iter().filter_map(|v| match v { Json::String(s) => Some(s), _ => None }).collect())),
+                _ => None
+            },
+            Some('c') => match data[1..].parse::<Json>() {
+                Ok(Json::Array(ref items)) => Some(SockJsFrame::Close(
+                    items.first().and_then(|v| v.as_u64()).unwrap_or(0) as u16,
+                    items.get(1).and_then(|v| v.as_string()).unwrap_or("").to_string())),
+                _ => None
+            },
+            _ => None
+        }
+    }
+}
+
+/// SockJS session over connected WebSocket
+pub struct SockJsClient {
+    ws: WebSocket,
+    pending: Vec<String>,
+    opened: bool,
+    defrag: Defragmenter
+}
+
+impl SockJsClient {
+    pub fn new(ws: WebSocket) -> SockJsClient {
+        SockJsClient { ws: ws, pending: Vec::new(), opened: false, defrag: Defragmenter::new() }
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    fn recv_frame(&mut self) -> WSResult<SockJsFrame> {
+        loop {
+            let msg = match self.defrag.push(self.ws.read_message()?)? {
+                Some(msg) => msg,
+                None => continue
+            };
+
+            if msg.is_ping() {
+                self.ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+            } else if msg.is_close() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+            } else if msg.is_text() {
+                match SockJsFrame::parse(msg.as_text()?) {
+                    Some(frame) => return Ok(frame),
+                    None => return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "invalid SockJS frame")))
+                }
+            }
+        }
+    }
+
+    /// Wait for session open frame, must be called before sending anything
+    pub fn open(&mut self) -> WSResult<()> {
+        while !self.opened {
+            match self.recv_frame()? {
+                SockJsFrame::Open => self.opened = true,
+                SockJsFrame::Close(code, reason) => return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionRefused, format!("SockJS session rejected: {} {}", code, reason)))),
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    /// Send several messages in one frame
+    pub fn send_all(&mut self, messages: &[&str]) -> WSResult<()> {
+        let data = Json::Array(messages.iter().map(|m| Json::String(m.to_string())).collect()).to_string();
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, data.as_bytes())
+    }
+
+    #[inline] pub fn send(&mut self, message: &str) -> WSResult<()> {
+        self.send_all(&[message])
+    }
+
+    /// Receive next message, skipping heartbeats
+    pub fn recv(&mut self) -> WSResult<String> {
+        loop {
+            if !self.pending.is_empty() {
+                return Ok(self.pending.remove(0));
+            }
+
+            match self.recv_frame()? {
+                SockJsFrame::Messages(messages) => self.pending = messages,
+                SockJsFrame::Close(code, reason) => return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, format!("SockJS session closed: {} {}", code, reason)))),
+                SockJsFrame::Open => self.opened = true,
+                SockJsFrame::Heartbeat => ()
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{SockJsFrame, session_url};
+
+    #[test]
+    fn frames_are_parsed() {
+        assert_eq!(SockJsFrame::parse("o"), Some(SockJsFrame::Open));
+        assert_eq!(SockJsFrame::parse("h"), Some(SockJsFrame::Heartbeat));
+        assert_eq!(SockJsFrame::parse("a[\"hi\",\"there\"]"),
+                   Some(SockJsFrame::Messages(vec!["hi".to_string(), "there".to_string()])));
+        assert_eq!(SockJsFrame::parse("c[3000,\"Go away!\"]"), Some(SockJsFrame::Close(3000, "Go away!".to_string())));
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        assert_eq!(SockJsFrame::parse(""), None);
+        assert_eq!(SockJsFrame::parse("x"), None);
+        assert_eq!(SockJsFrame::parse("a{\"hi\"}"), None);
+        assert_eq!(SockJsFrame::parse("c[3000"), None);
+    }
+
+    #[test]
+    fn session_url_points_to_websocket_transport() {
+        let url = session_url("ws://localhost:8080/echo/").unwrap();
+        let segments = url.path_segments().unwrap().collect::<Vec<_>>();
+        assert_eq!(segments.len(), 4);
+        assert_eq!((segments[0], segments[3]), ("echo", "websocket"));
+        assert_eq!(segments[1].len(), 3);
+        assert_eq!(segments[2].len(), 16);
+    }
+}
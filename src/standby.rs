@@ -0,0 +1,160 @@
+//! Warm standby: second connection to backup endpoint is kept handshaken and idle,
+//! and takes over instantly when primary connection fails, hiding reconnection latency.
+//!
+//! Failed connection is reconnected in background of `keepalive()` calls
+//! and becomes the new standby.
+
+use std::time::Duration;
+use std::mem;
+use std::io;
+
+use socket::WebSocket;
+use message::{WSMessage, WSStatusCode};
+use error::{WSError, WSResult};
+
+// How long keepalive waits for standby's messages (pongs mostly) to show up
+const STANDBY_POLL_MS: u64 = 1;
+
+pub struct Standby {
+    primary: WebSocket,
+    standby: WebSocket,
+    standby_up: bool
+}
+
+impl Standby {
+    /// Both sockets are connected with `start()`
+    pub fn new(primary: WebSocket, standby: WebSocket) -> Standby {
+        Standby { primary: primary, standby: standby, standby_up: false }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &WebSocket {
+        &self.primary
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.primary
+    }
+
+    /// Standby socket, which is connected if `is_standby_up()`
+    #[inline] pub fn standby(&self) -> &WebSocket {
+        &self.standby
+    }
+
+    #[inline] pub fn is_standby_up(&self) -> bool {
+        self.standby_up
+    }
+
+    #[inline] pub fn into_inner(self) -> (WebSocket, WebSocket) {
+        (self.primary, self.standby)
+    }
+
+    /// Connect primary socket, falling back to standby if it fails, then connect standby,
+    /// error is returned only if there's no connection at all
+    pub fn start(&mut self) -> WSResult<()> {
+        match self.primary.connect() {
+            Ok(()) => {
+                self.standby_up = self.standby.connect().is_ok();
+                Ok(())
+            },
+            Err(e) => {
+                self.standby.connect().map_err(|_| e)?;
+                mem::swap(&mut self.primary, &mut self.standby);
+                Ok(())
+            }
+        }
+    }
+
+    /// Promote standby connection to primary one, failed primary becomes standby
+    /// and is reconnected by `keepalive()`
+    pub fn failover(&mut self) -> WSResult<()> {
+        if !self.standby_up {
+            return Err(WSError::Io(io::Error::new(io::ErrorKind::NotConnected, "standby connection is down")));
+        }
+
+        mem::swap(&mut self.primary, &mut self.standby);
+        self.standby_up = false;
+        Ok(())
+    }
+
+    /// Ping standby connection and answer its pings, reconnecting it if it's down,
+    /// should be called regularly (e.g. every few seconds) while primary connection is in use
+    pub fn keepalive(&mut self) -> WSResult<()> {
+        if !self.standby_up {
+            self.standby.connect()?;
+            self.standby_up = true;
+        }
+
+        let result = self.ping_standby();
+        if result.is_err() {
+            self.standby_up = false;
+        }
+        result
+    }
+
+    // Standby socket's read timeout is restored afterwards, whatever the outcome
+    fn ping_standby(&mut self) -> WSResult<()> {
+        let read_timeout = self.standby.read_timeout();
+        let result = self.poll_standby();
+        let restored = self.standby.set_read_timeout(read_timeout);
+        if result.is_ok() {
+            restored?;
+        }
+        result
+    }
+
+    fn poll_standby(&mut self) -> WSResult<()> {
+        let ws = &mut self.standby;
+        ws.send_message(&WSMessage::ping(b"").mask())?;
+
+        ws.set_read_timeout(Some(Duration::from_millis(STANDBY_POLL_MS)))?;
+        loop {
+            match ws.read_message() {
+                Ok(msg) => {
+                    if msg.is_ping() {
+                        ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+                    } else if msg.is_close() {
+                        let _ = ws.send_message(&WSMessage::close(msg.status.unwrap_or(WSStatusCode::NoError), b"").mask());
+                        return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+                    }
+                    // Standby is not subscribed to anything, so everything else is dropped
+                },
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Send message, failing over to standby and sending it again if primary connection has failed
+    pub fn send_message(&mut self, msg: &WSMessage) -> WSResult<()> {
+        match self.primary.send_message(msg) {
+            Err(WSError::Io(_)) => {
+                self.failover()?;
+                self.primary.send_message(msg)
+            },
+            result => result
+        }
+    }
+
+    /// Read next message, failing over to standby if primary connection fails
+    /// or is closed by server (close frame is answered first)
+    pub fn read_message(&mut self) -> WSResult<WSMessage> {
+        loop {
+            match self.primary.read_message() {
+                Ok(msg) => {
+                    if msg.is_close() {
+                        let _ = self.primary.send_message(&WSMessage::close(msg.status.unwrap_or(WSStatusCode::NoError), b"").mask());
+                        self.failover()?;
+                    } else {
+                        return Ok(msg);
+                    }
+                },
+                // Read timeout set by user is not a failure
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+                    return Err(WSError::Io(io::Error::new(e.kind(), "read timed out"))),
+                Err(WSError::Io(_)) | Err(WSError::ConnectionResetMidFrame { .. }) => self.failover()?,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! STOMP 1.2 over WebSocket, as exposed by most message brokers (ActiveMQ, RabbitMQ etc.)
+//!
+//! Offer `v12.stomp` subprotocol when creating WebSocket, connect it
+//! and wrap into `StompClient`.
+
+use std::io;
+use std::str;
+
+use socket::WebSocket;
+use message::{WSMessage, Defragmenter, WS_FIN, WS_OPTEXT, WS_OPBIN, WS_MASK};
+use error::{WSError, WSResult};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StompFrame {
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl StompFrame {
+    pub fn new(command: &str) -> StompFrame {
+        StompFrame { command: command.to_string(), headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> StompFrame {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: &[u8]) -> StompFrame {
+        self.body = body.to_vec();
+        self
+    }
+
+    /// Header value, if header is repeated, the first one wins
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| &**n == name).map(|&(_, ref v)| &**v)
+    }
+
+    // CONNECT and CONNECTED frames headers are never escaped for compatibility with STOMP 1.0
+    fn escaped(&self) -> bool {
+        &*self.command != "CONNECT" && &*self.command != "CONNECTED"
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let escaped = self.escaped();
+        let mut data = Vec::with_capacity(self.body.len() + 64);
+
+        data.extend_from_slice(self.command.as_bytes());
+        data.push(b'\n');
+
+        for &(ref name, ref value) in self.headers.iter() {
+            if escaped {
+                data.extend_from_slice(escape(&**name).as_bytes());
+                data.push(b':');
+                data.extend_from_slice(escape(&**value).as_bytes());
+            } else {
+                data.extend_from_slice(name.as_bytes());
+                data.push(b':');
+                data.extend_from_slice(value.as_bytes());
+            }
+            data.push(b'\n');
+        }
+
+        if !self.body.is_empty() && self.get("content-length").is_none() {
+            data.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+
+        data.push(b'\n');
+        data.extend_from_slice(&*self.body);
+        data.push(0);
+        data
+    }
+
+    /// Parse the first frame in data, heart-beats (bare EOLs) are skipped
+    pub fn parse(data: &[u8]) -> Option<StompFrame> {
+        let start = data.iter().position(|&b| b != b'\n' && b != b'\r')?;
+        let data = &data[start..];
+
+        let head_end = (0..data.len()).find(|&i| data[i..].starts_with(b"\n\n") || data[i..].starts_with(b"\r\n\r\n"))?;
+        let body_start = head_end + if data[head_end] == b'\r' { 4 } else { 2 };
+
+        let head = match str::from_utf8(&data[..head_end]) {
+            Ok(head) => head,
+            Err(_) => return None
+        };
+
+        let mut lines = head.lines().map(|l| l.trim_end_matches('\r'));
+        let mut frame = StompFrame::new(lines.next()?);
+        let escaped = frame.escaped();
+
+        for line in lines {
+            let mut parts = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if escaped {
+                    frame.headers.push((unescape(name), unescape(value)));
+                } else {
+                    frame.headers.push((name.to_string(), value.to_string()));
+                }
+            }
+        }
+
+        let rest = &data[body_start..];
+        let body_len = match frame.get("content-length").and_then(|v| v.parse::<usize>().ok()) {
+            Some(len) if len <= rest.len() => len,
+            _ => rest.iter().position(|&b| b == 0).unwrap_or(rest.len())
+        };
+        frame.body = rest[..body_len].to_vec();
+
+        Some(frame)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("\r", "\\r").replace("\n", "\\n").replace(":", "\\c")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('r') => result.push('\r'),
+                Some('n') => result.push('\n'),
+                Some('c') => result.push(':'),
+                Some(c) => result.push(c),
+                None => ()
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn protocol_error(desc: &'static str, frame: &StompFrame) -> WSError {
+    match frame.get("message") {
+        Some(message) => WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", desc, message))),
+        None => WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, desc))
+    }
+}
+
+/// STOMP client over connected WebSocket
+pub struct StompClient {
+    ws: WebSocket,
+    next_id: u64,
+    defrag: Defragmenter
+}
+
+impl StompClient {
+    pub fn new(ws: WebSocket) -> StompClient {
+        StompClient { ws: ws, next_id: 0, defrag: Defragmenter::new() }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &WebSocket {
+        &self.ws
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        self.next_id.to_string()
+    }
+
+    /// Open STOMP session, `heartbeat` is (outgoing, incoming) intervals in milliseconds,
+    /// returns server's CONNECTED frame
+    pub fn connect(&mut self, host: &str, login: Option<(&str, &str)>, heartbeat: (u32, u32)) -> WSResult<StompFrame> {
+        let mut frame = StompFrame::new("CONNECT")
+            .header("accept-version", "1.2")
+            .header("host", host)
+            .header("heart-beat", &*format!("{},{}", heartbeat.0, heartbeat.1));
+
+        if let Some((login, passcode)) = login {
+            frame = frame.header("login", login).header("passcode", passcode);
+        }
+
+        self.send_frame(&frame)?;
+
+        let reply = self.recv()?;
+        if &*reply.command == "CONNECTED" {
+            Ok(reply)
+        } else {
+            Err(protocol_error("STOMP connection rejected", &reply))
+        }
+    }
+
+    pub fn send_frame(&mut self, frame: &StompFrame) -> WSResult<()> {
+        let data = frame.encode();
+        let opcode = if str::from_utf8(&*data).is_ok() { WS_OPTEXT } else { WS_OPBIN };
+        self.ws.send_data(WS_FIN | WS_MASK | opcode, None, &*data)
+    }
+
+    pub fn send(&mut self, destination: &str, content_type: Option<&str>, body: &[u8]) -> WSResult<()> {
+        let mut frame = StompFrame::new("SEND").header("destination", destination);
+        if let Some(content_type) = content_type {
+            frame = frame.header("content-type", content_type);
+        }
+        self.send_frame(&frame.body(body))
+    }
+
+    /// Subscribe to destination with given ack mode ("auto", "client" or "client-individual"),
+    /// returns subscription id
+    pub fn subscribe(&mut self, destination: &str, ack: &str) -> WSResult<String> {
+        let id = self.next_id();
+        self.send_frame(&StompFrame::new("SUBSCRIBE")
+                             .header("id", &*id)
+                             .header("destination", destination)
+                             .header("ack", ack))?;
+        Ok(id)
+    }
+
+    pub fn unsubscribe(&mut self, id: &str) -> WSResult<()> {
+        self.send_frame(&StompFrame::new("UNSUBSCRIBE").header("id", id))
+    }
+
+    /// Acknowledge MESSAGE frame by its "ack" header value
+    pub fn ack(&mut self, id: &str) -> WSResult<()> {
+        self.send_frame(&StompFrame::new("ACK").header("id", id))
+    }
+
+    pub fn nack(&mut self, id: &str) -> WSResult<()> {
+        self.send_frame(&StompFrame::new("NACK").header("id", id))
+    }
+
+    /// Send heart-beat, should be called at least as often as negotiated in `connect()`
+    pub fn heartbeat(&mut self) -> WSResult<()> {
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, b"\n")
+    }
+
+    /// Receive next STOMP frame, skipping heart-beats and answering WebSocket pings
+    pub fn recv(&mut self) -> WSResult<StompFrame> {
+        loop {
+            let msg = match self.defrag.push(self.ws.read_message()?)? {
+                Some(msg) => msg,
+                None => continue
+            };
+
+            if msg.is_ping() {
+                self.ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+            } else if msg.is_close() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+            } else if !msg.is_control() {
+                if let Some(frame) = StompFrame::parse(&*msg.data) {
+                    return Ok(frame);
+                }
+            }
+        }
+    }
+
+    /// Close STOMP session gracefully, waiting for server to confirm it
+    pub fn disconnect(&mut self) -> WSResult<()> {
+        let receipt = self.next_id();
+        self.send_frame(&StompFrame::new("DISCONNECT").header("receipt", &*receipt))?;
+
+        loop {
+            let frame = self.recv()?;
+            match &*frame.command {
+                "RECEIPT" if frame.get("receipt-id") == Some(&*receipt) => return Ok(()),
+                "ERROR" => return Err(protocol_error("STOMP error on disconnect", &frame)),
+                _ => ()
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::StompFrame;
+
+    #[test]
+    fn frame_survives_encoding() {
+        let frame = StompFrame::new("SEND").header("destination", "/queue/a:b").header("note", "line\nbreak").body(b"hi\0there");
+        let data = frame.encode();
+        assert!(data.starts_with(b"SEND\ndestination:/queue/a\\cb\nnote:line\\nbreak\ncontent-length:8\n\n"));
+        assert_eq!(data.last(), Some(&0));
+
+        let parsed = StompFrame::parse(&*data).unwrap();
+        assert_eq!(parsed.get("destination"), Some("/queue/a:b"));
+        assert_eq!(parsed.get("note"), Some("line\nbreak"));
+        assert_eq!(&*parsed.body, b"hi\0there");
+    }
+
+    #[test]
+    fn heartbeats_and_crlf_are_skipped() {
+        let frame = StompFrame::parse(b"\n\r\nMESSAGE\r\nsubscription:0\r\nsubscription:1\r\n\r\nbody\0").unwrap();
+        assert_eq!(&*frame.command, "MESSAGE");
+        assert_eq!(frame.get("subscription"), Some("0"));
+        assert_eq!(&*frame.body, b"body");
+
+        assert_eq!(StompFrame::parse(b"\n\n"), None);
+        assert_eq!(StompFrame::parse(b"MESSAGE\nid:1"), None);
+    }
+
+    #[test]
+    fn connect_headers_are_not_escaped() {
+        let frame = StompFrame::new("CONNECT").header("passcode", "a:b\\c");
+        assert_eq!(&*frame.encode(), &b"CONNECT\npasscode:a:b\\c\n\n\0"[..]);
+
+        let parsed = StompFrame::parse(b"CONNECTED\nserver:x\\c\n\n\0").unwrap();
+        assert_eq!(parsed.get("server"), Some("x\\c"));
+    }
+}
@@ -1,6 +1,14 @@
-use openssl::ssl::{SslMethod, SslStream, SslContext};
-use std::net::TcpStream;
-use std::io::{Write, Read, self};
+use openssl::ssl::{Ssl, SslStream};
+use std::net::{TcpStream, SocketAddr, IpAddr, ToSocketAddrs, Shutdown};
+use std::time::{Duration, Instant};
+use std::io::{Write, Read, BufRead, self};
+use std::sync::mpsc;
+use std::thread;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+
+use tls::TlsConfig;
+use proxy::Proxy;
+use socket2::{Socket, Domain, Type, Protocol};
 
 pub enum NetworkStream {
     Tcp(TcpStream),
@@ -8,15 +16,419 @@ pub enum NetworkStream {
 }
 
 impl NetworkStream {
-    pub fn connect(hostname: &str, use_ssl: bool) -> io::Result<NetworkStream> {
-        let sock = try!(TcpStream::connect(hostname));
-
+    #[inline] pub fn connect(hostname: &str, use_ssl: bool) -> io::Result<NetworkStream> {
         if use_ssl {
-            let ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|_| io::Error::new(io::ErrorKind::Other, "ssl context creation error", None)));
-            Ok(NetworkStream::Ssl(try!(SslStream::new(&ctx, sock).map_err(|_| io::Error::new(io::ErrorKind::Other, "ssl connection error", None)))))
+            NetworkStream::connect_timeout(hostname, Some(&TlsConfig::new()), None)
         } else {
-            Ok(NetworkStream::Tcp(sock))
+            NetworkStream::connect_timeout(hostname, None, None)
+        }
+    }
+
+    /// Connect with host name resolution, TCP connection and TLS negotiation
+    /// all limited by `timeout`, TLS is used if its config is given
+    #[inline] pub fn connect_timeout(hostname: &str, tls: Option<&TlsConfig>, timeout: Option<Duration>) -> io::Result<NetworkStream> {
+        NetworkStream::connect_with(hostname, None, &TcpOptions::default(), tls, timeout)
+    }
+
+    /// Connect like `connect_timeout()`, with given TCP socket options,
+    /// through tunnel opened by HTTP proxy, if it's given
+    pub fn connect_with(hostname: &str, proxy: Option<&Proxy>, tcp: &TcpOptions, tls: Option<&TlsConfig>, timeout: Option<Duration>) -> io::Result<NetworkStream> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let sock = match proxy {
+            Some(proxy) => proxy.open(hostname, || connect_tcp(proxy.address(), tcp, deadline))?,
+            None => connect_tcp(hostname, tcp, deadline)?
+        };
+        NetworkStream::secure(sock, hostname, tls)
+    }
+
+    // Negotiate TLS with `hostname` over connected socket, if its config is given
+    fn secure(sock: TcpStream, hostname: &str, tls: Option<&TlsConfig>) -> io::Result<NetworkStream> {
+        match tls {
+            Some(tls) => {
+                let ctx = tls.context()?;
+                let mut ssl = Ssl::new(&ctx).map_err(|_| io::Error::other("ssl creation error"))?;
+
+                // Servers hosting several domains need SNI to pick certificate,
+                // it's not used for IP addresses
+                let host = host_only(hostname);
+                let ip = host.parse::<IpAddr>().ok();
+                if ip.is_none() {
+                    ssl.set_hostname(host).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"))?;
+                }
+
+                // Certificate must be issued for the host we connect to
+                if tls.verifies_peer() {
+                    let expected = match ip {
+                        Some(ip) => ssl.param_mut().set_ip(ip),
+                        None => ssl.param_mut().set_host(host)
+                    };
+                    expected.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"))?;
+                }
+
+                // Skip full handshake if we've talked to this host before
+                if let Some(session) = tls.session_cache.as_ref().and_then(|cache| cache.get(hostname)) {
+                    // Safe: cached sessions are created with the context shared through the cache
+                    unsafe { ssl.set_session(&session) }.map_err(|_| io::Error::other("ssl session setup error"))?;
+                }
+
+                let stream = ssl.connect(sock).map_err(|_| io::Error::other("ssl connection error"))?;
+
+                if let Some(ref cache) = tls.session_cache {
+                    match stream.ssl().session() {
+                        Some(session) => cache.put(hostname, session.to_owned()),
+                        None => cache.remove(hostname)
+                    }
+                }
+
+                Ok(NetworkStream::Ssl(stream))
+            },
+            None => Ok(NetworkStream::Tcp(sock))
+        }
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match *self {
+            NetworkStream::Tcp(ref s) => s,
+            NetworkStream::Ssl(ref s) => s.get_ref()
+        }
+    }
+
+    #[inline] pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    #[inline] pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    /// Kernel statistics of TCP connection, available on Linux only
+    #[cfg(target_os = "linux")]
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        let mut info: libc::tcp_info = unsafe { ::std::mem::zeroed() };
+        let mut len = ::std::mem::size_of_val(&info) as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(self.tcp().as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut _ as *mut libc::c_void, &mut len)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(TcpInfo {
+            rtt: Duration::from_micros(info.tcpi_rtt as u64),
+            rtt_var: Duration::from_micros(info.tcpi_rttvar as u64),
+            retransmits: info.tcpi_total_retrans,
+            lost: info.tcpi_lost,
+            unacked: info.tcpi_unacked,
+            cwnd: info.tcpi_snd_cwnd,
+            mss: info.tcpi_snd_mss
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "TCP statistics are not available on this platform"))
+    }
+}
+
+/// Transport WebSocket can run over. Only `Read + Write` is required from custom ones
+/// (test doubles, tunnels, memory pipes), the rest is optional: timeouts are refused,
+/// so idle, handshake and read timeouts can't be used with such transports
+pub trait Stream: Read + Write + Sized {
+    /// Open stream to `hostname` ("host:port"), with TLS if its config is given,
+    /// custom transports, which are not connected by hostname, are passed to `WebSocket::connect_over()` instead
+    fn connect(_hostname: &str, _tls: Option<&TlsConfig>, _timeout: Option<Duration>) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "stream can't be connected by hostname"))
+    }
+
+    /// Open stream to `hostname` like `connect()`, with TCP socket options and through HTTP proxy,
+    /// if it's given. Transports which don't support these only connect with default options directly
+    fn connect_with(hostname: &str, proxy: Option<&Proxy>, tcp: &TcpOptions, tls: Option<&TlsConfig>, timeout: Option<Duration>) -> io::Result<Self> {
+        match proxy {
+            None if *tcp == TcpOptions::default() => Self::connect(hostname, tls, timeout),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "stream doesn't support TCP options")),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "stream can't be connected through proxy"))
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "stream doesn't support timeouts"))
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            None => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "stream doesn't support timeouts"))
+        }
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Underlying TCP socket handle, to shut it down from other thread
+    fn try_clone_tcp(&self) -> io::Result<TcpStream> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "stream is not a TCP socket"))
+    }
+}
+
+impl Stream for NetworkStream {
+    #[inline] fn connect(hostname: &str, tls: Option<&TlsConfig>, timeout: Option<Duration>) -> io::Result<NetworkStream> {
+        NetworkStream::connect_timeout(hostname, tls, timeout)
+    }
+
+    #[inline] fn connect_with(hostname: &str, proxy: Option<&Proxy>, tcp: &TcpOptions, tls: Option<&TlsConfig>, timeout: Option<Duration>) -> io::Result<NetworkStream> {
+        NetworkStream::connect_with(hostname, proxy, tcp, tls, timeout)
+    }
+
+    #[inline] fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    #[inline] fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(timeout)
+    }
+
+    #[inline] fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tcp().shutdown(how)
+    }
+
+    #[inline] fn try_clone_tcp(&self) -> io::Result<TcpStream> {
+        self.tcp().try_clone()
+    }
+}
+
+// Host part of "host:port", without brackets around IPv6 address
+fn host_only(hostname: &str) -> &str {
+    let host = match hostname.rfind(':') {
+        Some(pos) if !hostname[pos..].contains(']') => &hostname[..pos],
+        _ => hostname
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// What nonblocking stream waits for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interest {
+    Read,
+    Write
+}
+
+/// Transport for coroutine schedulers (green threads): wraps nonblocking stream and
+/// calls `wait` whenever it would block, to yield to scheduler until stream is ready,
+/// then repeats the operation, so that blocking-looking `WebSocket` API doesn't block OS thread.
+/// It's connected by scheduler and given to `WebSocket::connect_over()`. Timeouts are
+/// passed to the wrapped stream, so they should be implemented by `wait`, if needed
+pub struct YieldingStream<S, F> {
+    inner: S,
+    wait: F
+}
+
+impl<S, F> YieldingStream<S, F> where S: Read + Write, F: FnMut(&S, Interest) -> io::Result<()> {
+    pub fn new(inner: S, wait: F) -> YieldingStream<S, F> {
+        YieldingStream { inner: inner, wait: wait }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    #[inline] pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn retry<T, O>(&mut self, interest: Interest, mut op: O) -> io::Result<T> where O: FnMut(&mut S) -> io::Result<T> {
+        loop {
+            match op(&mut self.inner) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (self.wait)(&self.inner, interest)?,
+                result => return result
+            }
+        }
+    }
+}
+
+impl<S, F> Read for YieldingStream<S, F> where S: Read + Write, F: FnMut(&S, Interest) -> io::Result<()> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry(Interest::Read, |s| s.read(buf))
+    }
+}
+
+impl<S, F> Write for YieldingStream<S, F> where S: Read + Write, F: FnMut(&S, Interest) -> io::Result<()> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(Interest::Write, |s| s.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(Interest::Write, |s| s.flush())
+    }
+}
+
+impl<S, F> Stream for YieldingStream<S, F> where S: Stream, F: FnMut(&S, Interest) -> io::Result<()> {
+    #[inline] fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    #[inline] fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    #[inline] fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    #[inline] fn try_clone_tcp(&self) -> io::Result<TcpStream> {
+        self.inner.try_clone_tcp()
+    }
+}
+
+// Connect to "host:port" before deadline, socket timeouts are set to time left
+fn connect_tcp(hostname: &str, tcp: &TcpOptions, deadline: Option<Instant>) -> io::Result<TcpStream> {
+    let sock = if deadline.is_none() && *tcp == TcpOptions::default() {
+        TcpStream::connect(hostname)?
+    } else {
+        let mut result = Err(io::Error::new(io::ErrorKind::InvalidInput, "could not resolve host"));
+        for addr in resolve(hostname, deadline)? {
+            result = tcp.connect(&addr, time_left(deadline)?);
+            if result.is_ok() {
+                break;
+            }
         }
+        result?
+    };
+
+    let left = time_left(deadline)?;
+    sock.set_read_timeout(left)?;
+    sock.set_write_timeout(left)?;
+    Ok(sock)
+}
+
+/// Kernel TCP statistics, to tell network trouble from application slowness
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TcpInfo {
+    /// Smoothed round trip time
+    pub rtt: Duration,
+    /// Round trip time variance
+    pub rtt_var: Duration,
+    /// Segments retransmitted over connection lifetime
+    pub retransmits: u32,
+    /// Segments considered lost right now
+    pub lost: u32,
+    /// Segments sent, but not acknowledged yet
+    pub unacked: u32,
+    /// Congestion window, in segments
+    pub cwnd: u32,
+    /// Sender maximum segment size, in bytes
+    pub mss: u32
+}
+
+/// TCP socket options, which are set before connection is made
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TcpOptions {
+    fast_open: bool,
+    tos: Option<u8>
+}
+
+impl TcpOptions {
+    #[inline] pub fn new() -> TcpOptions {
+        TcpOptions::default()
+    }
+
+    /// Use TCP Fast Open, so that the first data sent (TLS or upgrade request) goes with SYN
+    /// once server has given us its cookie. It's supported on Linux only, ignored elsewhere
+    pub fn fast_open(mut self, enable: bool) -> TcpOptions {
+        self.fast_open = enable;
+        self
+    }
+
+    /// Mark outgoing packets with type of service (traffic class for IPv6) byte for QoS,
+    /// DSCP goes in its upper 6 bits, e.g. `tos(46 << 2)` for expedited forwarding.
+    /// It's supported on Unix only, connection fails elsewhere
+    pub fn tos(mut self, tos: u8) -> TcpOptions {
+        self.tos = Some(tos);
+        self
+    }
+
+    fn connect(&self, addr: &SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let sock = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        // Old kernels don't know about TFO, connection is made as usual there
+        #[cfg(target_os = "linux")]
+        {
+            if self.fast_open {
+                let _ = setsockopt(&sock, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1);
+            }
+        }
+
+        if let Some(tos) = self.tos {
+            set_tos(&sock, addr, tos)?;
+        }
+
+        match timeout {
+            Some(timeout) => sock.connect_timeout(&(*addr).into(), timeout)?,
+            None => sock.connect(&(*addr).into())?
+        }
+        Ok(sock.into())
+    }
+}
+
+#[cfg(unix)]
+fn setsockopt(sock: &Socket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), level, name, &value as *const _ as *const libc::c_void, ::std::mem::size_of_val(&value) as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_tos(sock: &Socket, addr: &SocketAddr, tos: u8) -> io::Result<()> {
+    match *addr {
+        SocketAddr::V4(_) => setsockopt(sock, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int),
+        SocketAddr::V6(_) => setsockopt(sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int)
+    }
+}
+
+#[cfg(not(unix))]
+fn set_tos(_sock: &Socket, _addr: &SocketAddr, _tos: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "type of service can't be set on this platform"))
+}
+
+/// Resolve "host:port" before deadline. System resolver can't be interrupted,
+/// so it's run in a helper thread, which is left to finish on its own on timeout
+fn resolve(hostname: &str, deadline: Option<Instant>) -> io::Result<Vec<SocketAddr>> {
+    let timeout = match time_left(deadline)? {
+        Some(timeout) => timeout,
+        None => return Ok(hostname.to_socket_addrs()?.collect())
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let host = hostname.to_string();
+    thread::spawn(move || {
+        let _ = tx.send(host.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()));
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "host name resolution timed out"))
+    }
+}
+
+/// Time left till deadline, or timed out error if it has already passed
+pub fn time_left(deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now < deadline {
+                Ok(Some(deadline - now))
+            } else {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "deadline has passed"))
+            }
+        },
+        None => Ok(None)
     }
 }
 
@@ -45,4 +457,207 @@ impl Write for NetworkStream {
     }
 }
 
+#[cfg(unix)]
+impl AsRawFd for NetworkStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tcp().as_raw_fd()
+    }
+}
+
+const BUF_SIZE: usize = 8 * 1024;
+
+/// Buffered reader and writer over a stream, unlike `std::io::BufStream`
+/// it lets to peek into data already read into buffer
+pub struct BufferedStream<S> {
+    inner: S,
+    rbuf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+    wbuf: Vec<u8>
+}
+
+impl<S: Read + Write> BufferedStream<S> {
+    pub fn new(inner: S) -> BufferedStream<S> {
+        BufferedStream {
+            inner: inner,
+            rbuf: vec![0u8; BUF_SIZE],
+            pos: 0,
+            cap: 0,
+            wbuf: Vec::with_capacity(BUF_SIZE)
+        }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Data already read from stream, but not consumed yet
+    #[inline] pub fn buffered(&self) -> &[u8] {
+        &self.rbuf[self.pos..self.cap]
+    }
+
+    /// Flush write buffer and get underlying stream back,
+    /// data left in read buffer is lost
+    pub fn into_inner(mut self) -> io::Result<S> {
+        self.flush_buf()?;
+        Ok(self.inner)
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        if !self.wbuf.is_empty() {
+            self.inner.write_all(&*self.wbuf)?;
+            self.wbuf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for BufferedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Don't bother copying large reads through buffer
+        if self.pos == self.cap && buf.len() >= self.rbuf.len() {
+            return self.inner.read(buf);
+        }
+
+        let n = {
+            let available = self.fill_buf()?;
+            let n = ::std::cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> BufRead for BufferedStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.cap {
+            self.cap = self.inner.read(&mut *self.rbuf)?;
+            self.pos = 0;
+        }
+        Ok(&self.rbuf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = ::std::cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+impl<S: Read + Write> Write for BufferedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.wbuf.len() + buf.len() > BUF_SIZE {
+            self.flush_buf()?;
+        }
+
+        if buf.len() >= BUF_SIZE {
+            self.inner.write(buf)
+        } else {
+            self.wbuf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
 
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use url::Url;
+
+    use mock;
+    use socket::WebSocket;
+    use super::{host_only, Interest, YieldingStream, NetworkStream, TcpOptions};
+
+    #[test]
+    fn fast_open_connection_carries_data() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut data = [0u8; 5];
+            stream.read_exact(&mut data).unwrap();
+            stream.write_all(&data).unwrap();
+        });
+
+        let tcp = TcpOptions::new().fast_open(true);
+        let mut stream = NetworkStream::connect_with(&*addr.to_string(), None, &tcp, None, Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello");
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tos_is_set_before_connection() {
+        use std::net::TcpListener;
+        use std::os::unix::io::AsRawFd;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpOptions::new().tos(46 << 2);
+        let stream = NetworkStream::connect_with(&*listener.local_addr().unwrap().to_string(), None, &tcp, None, None).unwrap();
+
+        let mut tos: libc::c_int = 0;
+        let mut len = ::std::mem::size_of_val(&tos) as libc::socklen_t;
+        let result = unsafe { libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS, &mut tos as *mut _ as *mut libc::c_void, &mut len) };
+        assert_eq!(result, 0);
+        assert_eq!(tos, 46 << 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tcp_info_is_reported() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = NetworkStream::connect_timeout(&*listener.local_addr().unwrap().to_string(), None, None).unwrap();
+        let info = stream.tcp_info().unwrap();
+        assert!(info.cwnd > 0);
+        assert!(info.mss > 0);
+        assert_eq!(info.retransmits, 0);
+    }
+
+    #[test]
+    fn host_is_stripped_of_port() {
+        assert_eq!(host_only("example.com:443"), "example.com");
+        assert_eq!(host_only("127.0.0.1:8080"), "127.0.0.1");
+        assert_eq!(host_only("[::1]:443"), "::1");
+        assert_eq!(host_only("example.com"), "example.com");
+    }
+
+    #[test]
+    fn yielding_stream_waits_instead_of_failing() {
+        let (stream, server) = mock::pair();
+        let yields = Cell::new(0);
+        let stream = YieldingStream::new(stream, |_, interest| {
+            assert_eq!(interest, Interest::Read);
+            yields.set(yields.get() + 1);
+            Ok(())
+        });
+
+        let mut ws = WebSocket::with_transport(Url::parse("ws://localhost/").unwrap(), 13, None, None);
+        ws.connect_over(stream).unwrap();
+
+        server.push_timeout();
+        server.push_timeout();
+        server.push(&*mock::frame(0x81, b"hi"));
+        assert_eq!(ws.read_message().unwrap().as_text().unwrap(), "hi");
+        assert_eq!(yields.get(), 2);
+    }
+}
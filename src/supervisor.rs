@@ -0,0 +1,212 @@
+//! Connection supervisor: keeps connection up, reconnecting when it fails,
+//! and replays declared subscriptions on every reconnection before resuming traffic.
+//!
+//! Messages sent while connection is down may be buffered and sent once it's back,
+//! see `set_offline_buffering()`.
+
+use std::io;
+use std::time::Duration;
+use std::sync::Arc;
+
+use socket::WebSocket;
+use message::{WSMessage, WSHeader, WSStatusCode};
+use error::{WSError, WSResult};
+use clock::{Clock, SystemClock};
+
+// Timeouts, wake ups and invalid arguments leave connection intact,
+// so they are passed to the caller instead of reconnecting
+fn is_failure(e: &io::Error) -> bool {
+    !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::InvalidInput)
+}
+
+struct Subscription {
+    key: String,
+    header: WSHeader,
+    data: Vec<u8>
+}
+
+#[allow(clippy::type_complexity)]
+pub struct Supervisor {
+    ws: WebSocket,
+    subscriptions: Vec<Subscription>,
+    retry_delay: Duration,
+    max_retries: Option<u32>,
+    clock: Arc<dyn Clock>,
+    offline_buffering: bool,
+    on_reconnect: Option<Box<dyn FnMut(&mut WebSocket) -> WSResult<()>>>
+}
+
+impl Supervisor {
+    /// Supervise socket, which is connected with `start()`
+    pub fn new(ws: WebSocket) -> Supervisor {
+        Supervisor {
+            ws: ws,
+            subscriptions: Vec::new(),
+            retry_delay: Duration::from_secs(1),
+            max_retries: None,
+            clock: Arc::new(SystemClock),
+            offline_buffering: false,
+            on_reconnect: None
+        }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &WebSocket {
+        &self.ws
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    /// Delay between reconnection attempts (1 second by default) and max number of retries,
+    /// `None` (default) retries forever
+    pub fn set_retry(&mut self, delay: Duration, max_retries: Option<u32>) {
+        self.retry_delay = delay;
+        self.max_retries = max_retries;
+    }
+
+    /// Clock to wait between reconnection attempts with
+    #[inline] pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Keep messages which can't be sent because reconnection has failed (see `set_retry()`)
+    /// in socket's outgoing queue instead of failing, and send them in order once connection
+    /// is re-established, after subscriptions are replayed. Size of the buffer and what to do
+    /// when it's full is set with `WebSocket::set_queue_limit()`
+    #[inline] pub fn set_offline_buffering(&mut self, enable: bool) {
+        self.offline_buffering = enable;
+    }
+
+    /// Number of messages waiting for connection to be re-established
+    #[inline] pub fn buffered(&self) -> usize {
+        self.ws.queued()
+    }
+
+    /// Hook to run after every reconnection before subscriptions are replayed, e.g. to authenticate
+    pub fn set_reconnect_hook<F: FnMut(&mut WebSocket) -> WSResult<()> + 'static>(&mut self, hook: F) {
+        self.on_reconnect = Some(Box::new(hook));
+    }
+
+    /// Connect for the first time
+    #[inline] pub fn start(&mut self) -> WSResult<()> {
+        self.reconnect()
+    }
+
+    /// Reconnect (retrying as configured), run reconnect hook and replay subscriptions
+    pub fn reconnect(&mut self) -> WSResult<()> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.ws.connect() {
+                Ok(()) => self.resume(),
+                err => err
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    attempt += 1;
+                    if self.max_retries.map(|max| attempt > max).unwrap_or(false) {
+                        return Err(e);
+                    }
+                    self.clock.sleep(self.retry_delay);
+                }
+            }
+        }
+    }
+
+    fn resume(&mut self) -> WSResult<()> {
+        if let Some(ref mut hook) = self.on_reconnect {
+            hook(&mut self.ws)?;
+        }
+
+        for sub in self.subscriptions.iter() {
+            self.ws.send_data(sub.header, None, &*sub.data)?;
+        }
+        self.ws.flush_queue()
+    }
+
+    // Put message aside till connection is re-established
+    fn buffer(&mut self, msg: &WSMessage) -> WSResult<()> {
+        let copy = WSMessage { header: msg.header, data: msg.data.clone(), status: msg.status, received: None };
+        self.ws.queue_message(copy, 0)
+    }
+
+    /// Send subscription message and remember it under `key` to replay on reconnection,
+    /// subscription with the same key is replaced
+    pub fn subscribe(&mut self, key: &str, msg: WSMessage) -> WSResult<()> {
+        self.subscriptions.retain(|s| &*s.key != key);
+        self.subscriptions.push(Subscription { key: key.to_string(), header: msg.header, data: msg.data });
+
+        let result = {
+            let sub = self.subscriptions.last().unwrap();
+            self.ws.send_data(sub.header, None, &*sub.data)
+        };
+
+        // It will go out on reconnection anyway
+        match result {
+            Err(_) => self.reconnect(),
+            ok => ok
+        }
+    }
+
+    /// Forget subscription, sending `msg` (e.g. unsubscribe request) if given
+    pub fn unsubscribe(&mut self, key: &str, msg: Option<WSMessage>) -> WSResult<()> {
+        self.subscriptions.retain(|s| &*s.key != key);
+        match msg {
+            Some(msg) => self.send_message(&msg),
+            None => Ok(())
+        }
+    }
+
+    /// Keys of active subscriptions, in order they are replayed
+    pub fn subscriptions(&self) -> Vec<&str> {
+        self.subscriptions.iter().map(|s| &*s.key).collect()
+    }
+
+    /// Send message, reconnecting and sending it again if connection has failed,
+    /// if reconnection fails too, message is buffered, if offline buffering is on
+    pub fn send_message(&mut self, msg: &WSMessage) -> WSResult<()> {
+        // Don't let the message overtake ones buffered before it
+        if self.offline_buffering && self.ws.queued() > 0 {
+            return self.buffer(msg);
+        }
+
+        match self.ws.send_message(msg) {
+            Err(WSError::Io(e)) if !is_failure(&e) => Err(WSError::Io(e)),
+            Err(WSError::Io(_)) => match self.reconnect() {
+                Ok(()) => self.ws.send_message(msg),
+                Err(_) if self.offline_buffering => self.buffer(msg),
+                Err(e) => Err(e)
+            },
+            result => result
+        }
+    }
+
+    /// Read next message, reconnecting transparently if connection fails or is closed by server
+    /// (close frame is answered first), pings are answered on the way. Read timeouts and
+    /// wake ups are reported as is
+    pub fn read_message(&mut self) -> WSResult<WSMessage> {
+        loop {
+            match self.ws.read_message() {
+                Ok(msg) => {
+                    if msg.is_ping() {
+                        let _ = self.ws.send_message(&WSMessage::pong(&*msg.data).mask());
+                    } else if msg.is_close() {
+                        let _ = self.ws.send_message(&WSMessage::close(msg.status.unwrap_or(WSStatusCode::NoError), b"").mask());
+                        self.reconnect()?;
+                    } else {
+                        return Ok(msg);
+                    }
+                },
+                Err(WSError::Io(e)) if !is_failure(&e) => return Err(WSError::Io(e)),
+                Err(WSError::Io(_)) | Err(WSError::ConnectionResetMidFrame { .. }) => self.reconnect()?,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Load testing: many connections to one URL, each sending payloads at a given rate
+//! and measuring time till server's reply (so server is expected to answer every message,
+//! like echo servers do), with stats aggregated over the whole swarm.
+//!
+//! All connections are driven by one shared loop in the calling thread: like `Selector` does,
+//! they are polled in turn with short read timeouts, and only while they wait for replies.
+//! Replies are timestamped when their connection is polled, so with many connections
+//! waiting at once latency includes up to a poll interval per waiting connection.
+
+use std::io;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use socket::WebSocket;
+use message::{WSMessage, WSStatusCode, WS_FIN, WS_OPBIN, WS_MASK};
+use error::WSError;
+
+// How long to wait for a reply on one connection before checking the next one
+const POLL_INTERVAL_MS: u64 = 1;
+
+/// Aggregated swarm results
+#[derive(Debug, Clone, Default)]
+pub struct SwarmStats {
+    pub connected: usize,
+    pub connect_errors: usize,
+    pub sent: u64,
+    pub received: u64,
+    pub errors: u64,
+    /// Replies which haven't come in time, their connections are dropped
+    pub timeouts: u64,
+    pub min_latency: Option<Duration>,
+    pub max_latency: Option<Duration>,
+    total_latency: Duration
+}
+
+impl SwarmStats {
+    /// Average time from sending message till reply
+    pub fn avg_latency(&self) -> Option<Duration> {
+        if self.received == 0 {
+            None
+        } else {
+            Some(self.total_latency / self.received as u32)
+        }
+    }
+
+    fn add_latency(&mut self, latency: Duration) {
+        self.received += 1;
+        self.total_latency += latency;
+        self.min_latency = Some(self.min_latency.map_or(latency, |v| cmp::min(v, latency)));
+        self.max_latency = Some(self.max_latency.map_or(latency, |v| cmp::max(v, latency)));
+    }
+}
+
+pub struct Swarm {
+    url: Url,
+    connections: usize,
+    rate: u32,
+    payload_size: usize,
+    duration: Duration,
+    reply_timeout: Duration
+}
+
+// State of a single connection in the shared loop
+struct Client {
+    ws: WebSocket,
+    finish: Instant,
+    next_send: Instant,
+    sent_at: Option<Instant>
+}
+
+impl Swarm {
+    /// Swarm of `connections` clients, by default each one sends one 64 bytes message per second for 10 seconds,
+    /// waiting for each reply for 5 seconds at most
+    pub fn new(url: Url, connections: usize) -> Swarm {
+        Swarm { url: url, connections: connections, rate: 1, payload_size: 64, duration: Duration::from_secs(10), reply_timeout: Duration::from_secs(5) }
+    }
+
+    /// Messages per second sent by each connection
+    #[inline] pub fn rate(mut self, rate: u32) -> Swarm {
+        self.rate = cmp::max(rate, 1);
+        self
+    }
+
+    #[inline] pub fn payload_size(mut self, size: usize) -> Swarm {
+        self.payload_size = size;
+        self
+    }
+
+    /// How long each connection keeps sending
+    #[inline] pub fn duration(mut self, duration: Duration) -> Swarm {
+        self.duration = duration;
+        self
+    }
+
+    /// How long each connection may take to connect, and to get reply to each message,
+    /// connection is dropped once it's exceeded
+    #[inline] pub fn reply_timeout(mut self, timeout: Duration) -> Swarm {
+        self.reply_timeout = timeout;
+        self
+    }
+
+    /// Run the swarm and wait for all connections to finish
+    pub fn run(&self) -> SwarmStats {
+        let mut stats = SwarmStats::default();
+        let interval = Duration::from_secs(1) / self.rate;
+        let payload = vec![0x55u8; self.payload_size];
+
+        let mut clients = Vec::with_capacity(self.connections);
+        for _ in 0..self.connections {
+            match self.connect() {
+                Ok(ws) => {
+                    let now = Instant::now();
+                    stats.connected += 1;
+                    clients.push(Some(Client { ws: ws, finish: now + self.duration, next_send: now, sent_at: None }));
+                },
+                Err(_) => stats.connect_errors += 1
+            }
+        }
+
+        while clients.iter().any(|c| c.is_some()) {
+            let mut polled = false;
+            let mut wake_at: Option<Instant> = None;
+
+            for slot in clients.iter_mut() {
+                let done = match *slot {
+                    Some(ref mut client) => {
+                        let done = self.step(client, &mut stats, &*payload, interval);
+                        polled = polled || client.sent_at.is_some();
+                        wake_at = Some(wake_at.map_or(client.next_send, |t| cmp::min(t, client.next_send)));
+                        done
+                    },
+                    None => continue
+                };
+
+                if done {
+                    if let Some(mut client) = slot.take() {
+                        let _ = client.ws.close_send(WSStatusCode::NoError, b"");
+                    }
+                }
+            }
+
+            // Nobody waits for replies, so sleep till the next message is due
+            if !polled {
+                if let Some(left) = wake_at.and_then(|t| t.checked_duration_since(Instant::now())) {
+                    thread::sleep(cmp::min(left, interval));
+                }
+            }
+        }
+
+        stats
+    }
+
+    fn connect(&self) -> Result<WebSocket, WSError> {
+        let mut ws = WebSocket::new(self.url.clone());
+        ws.set_handshake_timeout(Some(self.reply_timeout));
+        ws.connect()?;
+        ws.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+        Ok(ws)
+    }
+
+    // Scripted behavior of a single connection: send message when it's due, poll for reply
+    // if it's awaited, returns whether connection is done
+    fn step(&self, client: &mut Client, stats: &mut SwarmStats, payload: &[u8], interval: Duration) -> bool {
+        let now = Instant::now();
+        match client.sent_at {
+            None if now >= client.finish => return true,
+            None if now >= client.next_send => {
+                if client.ws.send_data(WS_FIN | WS_MASK | WS_OPBIN, None, payload).is_err() {
+                    stats.errors += 1;
+                    return true;
+                }
+                stats.sent += 1;
+                client.sent_at = Some(now);
+                client.next_send = now + interval;
+            },
+            None => return false,
+            Some(sent_at) if now.duration_since(sent_at) >= self.reply_timeout => {
+                stats.timeouts += 1;
+                return true;
+            },
+            Some(_) => ()
+        }
+
+        // Wait for reply shortly, answering pings meanwhile
+        match client.ws.read_message() {
+            Ok(ref msg) if msg.is_ping() => { let _ = client.ws.send_message(&WSMessage::pong(&*msg.data).mask()); },
+            Ok(ref msg) if msg.is_close() => return true,
+            Ok(ref msg) if msg.is_control() => (),
+            Ok(_) => if let Some(sent_at) = client.sent_at.take() {
+                stats.add_latency(sent_at.elapsed());
+            },
+            Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(_) => {
+                stats.errors += 1;
+                return true;
+            }
+        }
+
+        false
+    }
+}
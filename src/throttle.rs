@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::cmp;
+
+use clock::{Clock, SystemClock};
+
+/// Outgoing traffic rate limiter, limits bytes and/or messages sent per second.
+///
+/// Every message is given its share of time (its size divided by bytes limit, or one over
+/// messages limit, whichever is longer), and next message waits until the shares of all
+/// previous ones have passed. So a single message larger than the bytes limit still goes out,
+/// but following ones are delayed accordingly. Time the sender was idle is not saved up for bursts.
+pub struct Throttle {
+    clock: Arc<dyn Clock>,
+    bytes_per_sec: Option<u64>,
+    messages_per_sec: Option<u64>,
+    // When traffic sent so far is paid off at the allowed rate
+    next: Instant
+}
+
+// Time to send `amount` of something at `per_sec` rate, saturating instead of overflowing
+fn share(amount: u64, per_sec: u64) -> Duration {
+    let nanos = amount as u128 * 1_000_000_000 / cmp::max(per_sec, 1) as u128;
+    Duration::from_nanos(cmp::min(nanos, u64::MAX as u128) as u64)
+}
+
+impl Throttle {
+    #[inline] pub fn new(bytes_per_sec: Option<u64>, messages_per_sec: Option<u64>) -> Throttle {
+        Throttle::with_clock(bytes_per_sec, messages_per_sec, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(bytes_per_sec: Option<u64>, messages_per_sec: Option<u64>, clock: Arc<dyn Clock>) -> Throttle {
+        Throttle {
+            next: clock.now(),
+            clock: clock,
+            bytes_per_sec: bytes_per_sec,
+            messages_per_sec: messages_per_sec
+        }
+    }
+
+    /// Switch to another clock, forgetting traffic sent so far
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.next = clock.now();
+        self.clock = clock;
+    }
+
+    /// Block until a message of `len` bytes can be sent and account it
+    pub fn wait(&mut self, len: u64) {
+        let now = self.clock.now();
+        if self.next > now {
+            self.clock.sleep(self.next - now);
+        } else {
+            self.next = now;
+        }
+
+        let cost = cmp::max(
+            self.bytes_per_sec.map(|bps| share(len, bps)).unwrap_or_default(),
+            self.messages_per_sec.map(|mps| share(1, mps)).unwrap_or_default());
+        self.next = self.next.checked_add(cost).unwrap_or(self.next);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use clock::{Clock, MockClock};
+    use super::Throttle;
+
+    // Time it takes to let `count` messages of `len` bytes through, until the next one may go
+    fn elapsed(throttle: &mut Throttle, clock: &MockClock, count: usize, len: u64) -> Duration {
+        let started = clock.now();
+        for _ in 0..count {
+            throttle.wait(len);
+        }
+        throttle.wait(0);
+        clock.now() - started
+    }
+
+    #[test]
+    fn long_run_rate_matches_bytes_limit() {
+        let clock = MockClock::new();
+        let mut throttle = Throttle::with_clock(Some(1000), None, Arc::new(clock.clone()));
+        assert_eq!(elapsed(&mut throttle, &clock, 10, 600), Duration::from_secs(6));
+
+        let clock = MockClock::new();
+        let mut throttle = Throttle::with_clock(Some(1000), None, Arc::new(clock.clone()));
+        assert_eq!(elapsed(&mut throttle, &clock, 4, 2500), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn long_run_rate_matches_messages_limit() {
+        let clock = MockClock::new();
+        let mut throttle = Throttle::with_clock(Some(1_000_000), Some(4), Arc::new(clock.clone()));
+        assert_eq!(elapsed(&mut throttle, &clock, 10, 100), Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn idle_time_is_not_saved_up() {
+        let clock = MockClock::new();
+        let mut throttle = Throttle::with_clock(Some(1000), None, Arc::new(clock.clone()));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(elapsed(&mut throttle, &clock, 2, 1000), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn huge_messages_dont_overflow() {
+        let clock = MockClock::new();
+        let mut throttle = Throttle::with_clock(Some(1), None, Arc::new(clock.clone()));
+        throttle.wait(u64::MAX);
+        throttle.wait(u64::MAX);
+    }
+}
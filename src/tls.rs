@@ -0,0 +1,350 @@
+use std::io;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use openssl::ssl::{SslMethod, SslContext, SslContextBuilder, SslOptions, SslSession, SslVerifyMode, SslVersion};
+use openssl::x509::X509StoreContextRef;
+use openssl::hash::MessageDigest;
+use openssl::error::ErrorStack;
+
+/// Peer certificate verification hook, called for each certificate in chain
+/// with OpenSSL's own verification result, returns whether to accept the certificate
+pub type VerifyCallback = Arc<dyn Fn(bool, &mut X509StoreContextRef) -> bool + Send + Sync>;
+
+// Modern AEAD ciphers with forward secrecy only
+static SECURE_CIPHERS: &str = "ECDHE+AESGCM:DHE+AESGCM:!aNULL:!eNULL:!MD5:!RC4:!DSS";
+
+// Pre-shared key suites, preferably with forward secrecy
+static PSK_CIPHERS: &str = "ECDHEPSK:DHEPSK:PSK:!eNULL";
+
+// Put NUL-terminated PSK identity and the key into OpenSSL's buffers,
+// returns key length, or nothing if they don't fit
+fn write_psk(identity: &[u8], key: &[u8], identity_buf: &mut [u8], key_buf: &mut [u8]) -> Option<usize> {
+    if identity.len() >= identity_buf.len() || key.len() > key_buf.len() {
+        return None;
+    }
+
+    identity_buf[..identity.len()].copy_from_slice(identity);
+    identity_buf[identity.len()] = 0;
+    key_buf[..key.len()].copy_from_slice(key);
+    Some(key.len())
+}
+
+/// TLS settings for wss connections
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// Protocol method, `SslMethod::tls()` negotiates any version both sides support,
+    /// use `options` to disable some of them
+    pub method: SslMethod,
+    /// OpenSSL cipher list string, library defaults are used if not set
+    pub cipher_list: Option<String>,
+    /// Additional OpenSSL options, e.g. to disable TLS compression
+    pub options: SslOptions,
+    /// Lowest protocol version to negotiate, OpenSSL's default if not set
+    pub min_version: Option<SslVersion>,
+    /// Verify peer certificate chain against system trusted certificates
+    pub verify_peer: bool,
+    /// Protocols to offer via ALPN (e.g. "http/1.1"), nothing is offered if empty
+    pub alpn_protocols: Vec<String>,
+    /// Cache of sessions to resume on reconnects, sessions are not resumed if not set.
+    /// When it's set, SSL context is built once and kept in the cache
+    pub session_cache: Option<SessionCache>,
+    /// Custom peer certificate verification, OpenSSL's result is used if not set
+    pub verify: Option<VerifyCallback>,
+    /// Pre-shared key identity and key to authenticate with instead of certificates
+    pub psk: Option<(Vec<u8>, Vec<u8>)>
+}
+
+impl TlsConfig {
+    /// Negotiate any protocol version, except for broken SSLv2 and SSLv3
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            method: SslMethod::tls(),
+            cipher_list: None,
+            options: SslOptions::NO_SSLV2 | SslOptions::NO_SSLV3,
+            min_version: None,
+            verify_peer: false,
+            alpn_protocols: Vec::new(),
+            session_cache: None,
+            verify: None,
+            psk: None
+        }
+    }
+
+    /// Require TLSv1.2 or later with strong cipher suites, no TLS compression
+    /// and verified peer certificate.
+    ///
+    /// Insecure legacy renegotiation is never allowed by OpenSSL unless explicitly enabled.
+    pub fn secure() -> TlsConfig {
+        TlsConfig {
+            method: SslMethod::tls(),
+            cipher_list: Some(SECURE_CIPHERS.to_string()),
+            options: SslOptions::NO_COMPRESSION,
+            min_version: Some(SslVersion::TLS1_2),
+            verify_peer: true,
+            alpn_protocols: Vec::new(),
+            session_cache: None,
+            verify: None,
+            psk: None
+        }
+    }
+
+    /// Offer given protocols via ALPN during TLS handshake
+    pub fn alpn(mut self, protocols: &[&str]) -> TlsConfig {
+        self.alpn_protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Resume TLS sessions on reconnects to the same host, skipping full handshake.
+    /// Configure everything else first: SSL context is built once on first connection
+    pub fn resume_sessions(mut self) -> TlsConfig {
+        self.session_cache = Some(SessionCache::new());
+        self
+    }
+
+    /// Verify peer certificates with given callback
+    pub fn verify_with<F>(mut self, callback: F) -> TlsConfig where F: Fn(bool, &mut X509StoreContextRef) -> bool + Send + Sync + 'static {
+        self.verify = Some(Arc::new(callback));
+        self
+    }
+
+    /// Accept certificates OpenSSL trusts for the host, or server certificate
+    /// with given SHA-256 fingerprint, e.g. known self-signed certificate
+    pub fn pin_fingerprint(self, fingerprint: Vec<u8>) -> TlsConfig {
+        self.verify_with(move |ok, ctx| ok || (ctx.error_depth() == 0 && ctx.current_cert()
+                         .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+                         .map(|fp| *fp == *fingerprint)
+                         .unwrap_or(false)))
+    }
+
+    /// Authenticate with pre-shared key, PSK cipher suites are offered if cipher list is not set
+    pub fn psk(mut self, identity: &str, key: &[u8]) -> TlsConfig {
+        self.psk = Some((identity.as_bytes().to_vec(), key.to_vec()));
+        self
+    }
+
+    /// Whether peer certificate is verified, along with host name it's issued for
+    #[inline] pub fn verifies_peer(&self) -> bool {
+        self.verify_peer || self.verify.is_some()
+    }
+
+    /// SSL context for new connections, it's shared by all connections
+    /// if sessions are resumed, as sessions only work with the context they came from
+    pub fn context(&self) -> io::Result<SslContext> {
+        match self.session_cache {
+            Some(ref cache) => cache.context(|| self.build_context()),
+            None => self.build_context()
+        }
+    }
+
+    fn build_context(&self) -> io::Result<SslContext> {
+        let mut ctx = SslContextBuilder::new(self.method).map_err(|_| io::Error::other("ssl context creation error"))?;
+
+        let ciphers = match self.cipher_list {
+            Some(ref ciphers) => Some(&**ciphers),
+            None if self.psk.is_some() => Some(PSK_CIPHERS),
+            None => None
+        };
+        if let Some(ciphers) = ciphers {
+            ctx.set_cipher_list(ciphers).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cipher list"))?;
+        }
+
+        if let Some((ref identity, ref key)) = self.psk {
+            if identity.contains(&0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid PSK identity"));
+            }
+            let (identity, key) = (identity.clone(), key.clone());
+            ctx.set_psk_client_callback(move |_, _, identity_buf, key_buf| {
+                write_psk(&*identity, &*key, identity_buf, key_buf).ok_or_else(ErrorStack::get)
+            });
+        }
+
+        ctx.set_options(self.options);
+
+        if self.min_version.is_some() {
+            ctx.set_min_proto_version(self.min_version).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid protocol version"))?;
+        }
+
+        if self.verifies_peer() {
+            ctx.set_default_verify_paths().map_err(|_| io::Error::other("can't load trusted certificates"))?;
+        }
+
+        match self.verify {
+            Some(ref verify) => {
+                let verify = verify.clone();
+                ctx.set_verify_callback(SslVerifyMode::PEER, move |ok, store| (*verify)(ok, store));
+            },
+            None if self.verify_peer => ctx.set_verify(SslVerifyMode::PEER),
+            None => ()
+        }
+
+        if !self.alpn_protocols.is_empty() {
+            // Wire format: each protocol name is prefixed with its length
+            let mut protos = Vec::new();
+            for proto in self.alpn_protocols.iter() {
+                if proto.is_empty() || proto.len() > 255 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid ALPN protocol name"));
+                }
+                protos.push(proto.len() as u8);
+                protos.extend_from_slice(proto.as_bytes());
+            }
+            ctx.set_alpn_protos(&*protos).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid ALPN protocols"))?;
+        }
+
+        Ok(ctx.build())
+    }
+}
+
+impl Default for TlsConfig {
+    #[inline] fn default() -> TlsConfig {
+        TlsConfig::new()
+    }
+}
+
+/// TLS sessions by host along with SSL context they were created with,
+/// clones share the same cache
+#[derive(Clone)]
+pub struct SessionCache {
+    sessions: Arc<Mutex<HashMap<String, SslSession>>>,
+    context: Arc<Mutex<Option<SslContext>>>
+}
+
+impl SessionCache {
+    pub fn new() -> SessionCache {
+        SessionCache {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            context: Arc::new(Mutex::new(None))
+        }
+    }
+
+    // Context cached sessions belong to, built on first use
+    fn context<F>(&self, build: F) -> io::Result<SslContext> where F: FnOnce() -> io::Result<SslContext> {
+        let mut context = self.context.lock().unwrap();
+        match *context {
+            Some(ref ctx) => Ok(ctx.clone()),
+            None => {
+                let ctx = build()?;
+                *context = Some(ctx.clone());
+                Ok(ctx)
+            }
+        }
+    }
+
+    pub fn get(&self, host: &str) -> Option<SslSession> {
+        self.sessions.lock().unwrap().get(host).cloned()
+    }
+
+    pub fn put(&self, host: &str, session: SslSession) {
+        self.sessions.lock().unwrap().insert(host.to_string(), session);
+    }
+
+    pub fn remove(&self, host: &str) {
+        self.sessions.lock().unwrap().remove(host);
+    }
+
+    pub fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+impl Default for SessionCache {
+    #[inline] fn default() -> SessionCache {
+        SessionCache::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::thread;
+    use std::net::{TcpListener, TcpStream};
+    use openssl::ssl::{Ssl, SslMethod, SslContextBuilder, SslVersion};
+    use openssl::ssl::SslContextRef;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use super::{TlsConfig, write_psk};
+
+    // Self-signed certificate and its private key
+    fn self_signed() -> (X509, PKey<::openssl::pkey::Private>) {
+        let key = PKey::from_ec_key(EcKey::generate(&*EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&*name).unwrap();
+        cert.set_issuer_name(&*name).unwrap();
+        cert.set_pubkey(&*key).unwrap();
+        cert.set_not_before(&*Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&*Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&*key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    #[test]
+    fn secure_config_negotiates_tls13() {
+        let tls = TlsConfig::secure();
+        assert_eq!(tls.min_version, Some(SslVersion::TLS1_2));
+        assert!(tls.verify_peer);
+
+        let (cert, key) = self_signed();
+        let fingerprint = cert.digest(MessageDigest::sha256()).unwrap().to_vec();
+        let mut server = SslContextBuilder::new(SslMethod::tls()).unwrap();
+        server.set_certificate(&*cert).unwrap();
+        server.set_private_key(&*key).unwrap();
+        let server = server.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            Ssl::new(&*server).unwrap().accept(stream).map(|_| ()).map_err(|e| e.to_string())
+        });
+
+        let ctx = tls.pin_fingerprint(fingerprint).context().unwrap();
+        let stream = Ssl::new(&*ctx).unwrap().connect(TcpStream::connect(addr).unwrap()).unwrap();
+        assert_eq!(stream.ssl().version2(), Some(SslVersion::TLS1_3));
+        accepted.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn context_is_shared_when_resuming_sessions() {
+        let tls = TlsConfig::new().resume_sessions();
+        let (first, second) = (tls.context().unwrap(), tls.context().unwrap());
+        assert!(ptr::eq::<SslContextRef>(&*first, &*second));
+
+        let tls = TlsConfig::new();
+        let (first, second) = (tls.context().unwrap(), tls.context().unwrap());
+        assert!(!ptr::eq::<SslContextRef>(&*first, &*second));
+    }
+
+    #[test]
+    fn pinned_certificate_is_verified() {
+        assert!(!TlsConfig::new().verifies_peer());
+        let tls = TlsConfig::new().pin_fingerprint(vec![0; 32]);
+        assert!(tls.verifies_peer());
+        assert!(tls.context().is_ok());
+    }
+
+    #[test]
+    fn psk_identity_is_nul_terminated() {
+        let (mut identity, mut key) = ([0xffu8; 8], [0u8; 4]);
+        assert_eq!(write_psk(b"device", b"\x01\x02", &mut identity, &mut key), Some(2));
+        assert_eq!(&identity[..7], b"device\0");
+        assert_eq!(&key[..2], b"\x01\x02");
+
+        assert_eq!(write_psk(b"too long!", b"", &mut identity, &mut key), None);
+        assert_eq!(write_psk(b"", b"too long", &mut identity, &mut key), None);
+    }
+
+    #[test]
+    fn psk_config_builds_context() {
+        assert!(TlsConfig::new().psk("device", b"secret").context().is_ok());
+        assert!(TlsConfig::new().psk("dev\0ice", b"secret").context().is_err());
+    }
+}
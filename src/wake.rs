@@ -0,0 +1,53 @@
+//! Interrupting a thread blocked reading from WebSocket.
+
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+struct WakeState {
+    woken: AtomicBool,
+    stream: Mutex<Option<TcpStream>>
+}
+
+/// Handle to wake up a thread blocked in `Connection::read_message()` from another thread,
+/// get one with `WebSocket::wake_handle()`
+#[derive(Clone)]
+pub struct WakeHandle {
+    state: Arc<WakeState>
+}
+
+impl WakeHandle {
+    pub fn new() -> WakeHandle {
+        WakeHandle { state: Arc::new(WakeState { woken: AtomicBool::new(false), stream: Mutex::new(None) }) }
+    }
+
+    /// Make current (or next) read return interrupted error promptly,
+    /// connection stays usable, so reading can be resumed afterwards
+    pub fn wake(&self) {
+        self.state.woken.store(true, Ordering::SeqCst);
+    }
+
+    /// Wake up reader and shut connection down for good, unblocking reads and writes immediately
+    pub fn shutdown(&self) {
+        self.wake();
+        if let Some(ref stream) = *self.state.stream.lock().unwrap() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Check and reset wake flag
+    pub fn take_woken(&self) -> bool {
+        self.state.woken.swap(false, Ordering::SeqCst)
+    }
+
+    /// Remember connection to shut down, it's set by `WebSocket` on every (re)connection
+    pub fn set_stream(&self, stream: Option<TcpStream>) {
+        *self.state.stream.lock().unwrap() = stream;
+    }
+}
+
+impl Default for WakeHandle {
+    #[inline] fn default() -> WakeHandle {
+        WakeHandle::new()
+    }
+}
@@ -0,0 +1,249 @@
+//! WAMP v2 basic profile client (JSON serialization), as spoken by Crossbar and alike routers.
+//!
+//! Offer `wamp.2.json` subprotocol when creating WebSocket, connect it,
+//! wrap into `WampClient` and `join()` a realm.
+
+use std::io;
+use std::collections::{BTreeMap, VecDeque};
+use rustc_serialize::json::{Json, Object, Array};
+
+use socket::WebSocket;
+use message::{WSMessage, Defragmenter, WS_FIN, WS_OPTEXT, WS_MASK};
+use error::{WSError, WSResult};
+
+pub const HELLO: u64 = 1;
+pub const WELCOME: u64 = 2;
+pub const ABORT: u64 = 3;
+pub const GOODBYE: u64 = 6;
+pub const ERROR: u64 = 8;
+pub const PUBLISH: u64 = 16;
+pub const PUBLISHED: u64 = 17;
+pub const SUBSCRIBE: u64 = 32;
+pub const SUBSCRIBED: u64 = 33;
+pub const UNSUBSCRIBE: u64 = 34;
+pub const UNSUBSCRIBED: u64 = 35;
+pub const EVENT: u64 = 36;
+pub const CALL: u64 = 48;
+pub const RESULT: u64 = 50;
+
+/// Event delivered to subscription
+#[derive(Debug, Clone, PartialEq)]
+pub struct WampEvent {
+    pub subscription: u64,
+    pub publication: u64,
+    pub details: Object,
+    pub args: Array,
+    pub kwargs: Object
+}
+
+fn protocol_error(desc: &'static str, detail: Option<String>) -> WSError {
+    match detail {
+        Some(detail) => WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", desc, detail))),
+        None => WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, desc))
+    }
+}
+
+// Pick error URI from ABORT/GOODBYE/ERROR message to report it
+fn reason(msg: &Array) -> Option<String> {
+    msg.iter().filter_map(|v| v.as_string()).next().map(|v| v.to_string())
+}
+
+fn payload(msg: &Array, at: usize) -> (Array, Object) {
+    (msg.get(at).and_then(|v| v.as_array()).cloned().unwrap_or_else(Vec::new),
+     msg.get(at + 1).and_then(|v| v.as_object()).cloned().unwrap_or_else(BTreeMap::new))
+}
+
+/// WAMP client session over connected WebSocket
+pub struct WampClient {
+    ws: WebSocket,
+    session: Option<u64>,
+    next_request: u64,
+    // Events received while waiting for replies to requests
+    events: VecDeque<WampEvent>,
+    defrag: Defragmenter
+}
+
+impl WampClient {
+    pub fn new(ws: WebSocket) -> WampClient {
+        WampClient { ws: ws, session: None, next_request: 0, events: VecDeque::new(), defrag: Defragmenter::new() }
+    }
+
+    #[inline] pub fn get_ref(&self) -> &WebSocket {
+        &self.ws
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    /// Session id assigned by router in WELCOME
+    #[inline] pub fn session(&self) -> Option<u64> {
+        self.session
+    }
+
+    fn next_request(&mut self) -> u64 {
+        self.next_request += 1;
+        self.next_request
+    }
+
+    pub fn send_raw(&mut self, msg: Array) -> WSResult<()> {
+        let data = Json::Array(msg).to_string();
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, data.as_bytes())
+    }
+
+    /// Receive next WAMP message as JSON array, answering WebSocket pings
+    pub fn recv_raw(&mut self) -> WSResult<Array> {
+        loop {
+            let msg = match self.defrag.push(self.ws.read_message()?)? {
+                Some(msg) => msg,
+                None => continue
+            };
+
+            if msg.is_ping() {
+                self.ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+                continue;
+            } else if msg.is_close() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+            } else if msg.is_control() {
+                continue;
+            }
+
+            match msg.as_text()?.parse::<Json>() {
+                Ok(Json::Array(array)) if array.first().and_then(|v| v.as_u64()).is_some() => return Ok(array),
+                _ => return Err(protocol_error("invalid WAMP message", None))
+            }
+        }
+    }
+
+    /// Wait for reply of given type to request, queueing events received meanwhile
+    fn wait_reply(&mut self, kind: u64, request: u64) -> WSResult<Array> {
+        loop {
+            let msg = self.recv_raw()?;
+            let code = msg[0].as_u64().unwrap();
+
+            if code == EVENT {
+                self.queue_event(&msg);
+            } else if code == kind && msg.get(1).and_then(|v| v.as_u64()) == Some(request) {
+                return Ok(msg);
+            } else if code == ERROR && msg.get(2).and_then(|v| v.as_u64()) == Some(request) {
+                return Err(protocol_error("WAMP request failed", msg.get(4).and_then(|v| v.as_string()).map(|v| v.to_string())));
+            } else if code == GOODBYE || code == ABORT {
+                self.session = None;
+                return Err(protocol_error("WAMP session closed", reason(&msg)));
+            }
+        }
+    }
+
+    fn queue_event(&mut self, msg: &Array) {
+        if let (Some(sub), Some(publ)) = (msg.get(1).and_then(|v| v.as_u64()), msg.get(2).and_then(|v| v.as_u64())) {
+            let (args, kwargs) = payload(msg, 4);
+            self.events.push_back(WampEvent {
+                subscription: sub,
+                publication: publ,
+                details: msg.get(3).and_then(|v| v.as_object()).cloned().unwrap_or_else(BTreeMap::new),
+                args: args,
+                kwargs: kwargs
+            });
+        }
+    }
+
+    /// Join realm announcing subscriber, publisher and caller roles, returns session id
+    pub fn join(&mut self, realm: &str) -> WSResult<u64> {
+        let mut roles = BTreeMap::new();
+        for role in ["subscriber", "publisher", "caller"].iter() {
+            roles.insert(role.to_string(), Json::Object(BTreeMap::new()));
+        }
+        let mut details = BTreeMap::new();
+        details.insert("roles".to_string(), Json::Object(roles));
+
+        self.send_raw(vec![Json::U64(HELLO), Json::String(realm.to_string()), Json::Object(details)])?;
+
+        let msg = self.recv_raw()?;
+        match (msg[0].as_u64().unwrap(), msg.get(1).and_then(|v| v.as_u64())) {
+            (WELCOME, Some(session)) => {
+                self.session = Some(session);
+                Ok(session)
+            },
+            (ABORT, _) => Err(protocol_error("WAMP session rejected", reason(&msg))),
+            _ => Err(protocol_error("unexpected WAMP message", None))
+        }
+    }
+
+    /// Leave realm, waiting for router to confirm it
+    pub fn leave(&mut self, reason: &str) -> WSResult<()> {
+        self.send_raw(vec![Json::U64(GOODBYE), Json::Object(BTreeMap::new()), Json::String(reason.to_string())])?;
+
+        loop {
+            let msg = self.recv_raw()?;
+            if msg[0].as_u64() == Some(GOODBYE) {
+                self.session = None;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Subscribe to topic, returns subscription id events will be delivered to
+    pub fn subscribe(&mut self, topic: &str) -> WSResult<u64> {
+        let request = self.next_request();
+        self.send_raw(vec![Json::U64(SUBSCRIBE), Json::U64(request), Json::Object(BTreeMap::new()), Json::String(topic.to_string())])?;
+
+        let reply = self.wait_reply(SUBSCRIBED, request)?;
+        reply.get(2).and_then(|v| v.as_u64()).ok_or_else(|| protocol_error("invalid WAMP SUBSCRIBED message", None))
+    }
+
+    pub fn unsubscribe(&mut self, subscription: u64) -> WSResult<()> {
+        let request = self.next_request();
+        self.send_raw(vec![Json::U64(UNSUBSCRIBE), Json::U64(request), Json::U64(subscription)])?;
+        self.wait_reply(UNSUBSCRIBED, request).map(|_| ())
+    }
+
+    /// Publish event to topic, if `acknowledge` is set, waits for router to confirm publication
+    pub fn publish(&mut self, topic: &str, args: Array, kwargs: Object, acknowledge: bool) -> WSResult<()> {
+        let request = self.next_request();
+        let mut options = BTreeMap::new();
+        if acknowledge {
+            options.insert("acknowledge".to_string(), Json::Boolean(true));
+        }
+
+        self.send_raw(vec![Json::U64(PUBLISH), Json::U64(request), Json::Object(options),
+                                Json::String(topic.to_string()), Json::Array(args), Json::Object(kwargs)])?;
+
+        if acknowledge {
+            self.wait_reply(PUBLISHED, request)?;
+        }
+        Ok(())
+    }
+
+    /// Call remote procedure and wait for its result
+    pub fn call(&mut self, procedure: &str, args: Array, kwargs: Object) -> WSResult<(Array, Object)> {
+        let request = self.next_request();
+        self.send_raw(vec![Json::U64(CALL), Json::U64(request), Json::Object(BTreeMap::new()),
+                                Json::String(procedure.to_string()), Json::Array(args), Json::Object(kwargs)])?;
+
+        let reply = self.wait_reply(RESULT, request)?;
+        Ok(payload(&reply, 3))
+    }
+
+    /// Receive next event for any of subscriptions
+    pub fn recv_event(&mut self) -> WSResult<WampEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            let msg = self.recv_raw()?;
+            match msg[0].as_u64().unwrap() {
+                EVENT => self.queue_event(&msg),
+                GOODBYE | ABORT => {
+                    self.session = None;
+                    return Err(protocol_error("WAMP session closed", reason(&msg)));
+                },
+                _ => ()
+            }
+        }
+    }
+}
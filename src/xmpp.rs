@@ -0,0 +1,159 @@
+//! XMPP over WebSocket (RFC 7395) framing.
+//!
+//! Offer `xmpp` subprotocol when creating WebSocket, connect it and wrap into `XmppFraming`.
+//! Each message is exactly one complete XML element: either framing `<open/>`/`<close/>`
+//! or a stanza, so XML parsing is left to XMPP client, only framing elements are recognized here.
+
+use std::io;
+
+use socket::WebSocket;
+use message::{WSMessage, Defragmenter, WS_FIN, WS_OPTEXT, WS_MASK};
+use error::{WSError, WSResult};
+
+pub const FRAMING_NS: &str = "urn:ietf:params:xml:ns:xmpp-framing";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmppFrame {
+    /// Stream opening element, as sent by server
+    Open(String),
+    /// Stream closing element, with `see-other-uri` value if server redirects client
+    Close(Option<String>),
+    Stanza(String)
+}
+
+impl XmppFrame {
+    pub fn parse(data: &str) -> XmppFrame {
+        let data = data.trim();
+        if is_element(data, "open") {
+            XmppFrame::Open(data.to_string())
+        } else if is_element(data, "close") {
+            XmppFrame::Close(attribute(data, "see-other-uri"))
+        } else {
+            XmppFrame::Stanza(data.to_string())
+        }
+    }
+}
+
+fn is_element(data: &str, name: &str) -> bool {
+    data.starts_with("<") && data[1..].starts_with(name) &&
+        data[1 + name.len()..].chars().next().map(|c| c.is_whitespace() || c == '/' || c == '>').unwrap_or(false)
+}
+
+/// Value of attribute of the first element in data, entities are not decoded
+pub fn attribute(data: &str, name: &str) -> Option<String> {
+    let head = &data[..data.find('>').unwrap_or(data.len())];
+    for quote in ["\"", "'"].iter() {
+        let pattern = format!(" {}={}", name, quote);
+        if let Some(start) = head.find(&*pattern).map(|p| p + pattern.len()) {
+            if let Some(len) = head[start..].find(*quote) {
+                return Some(head[start..start + len].to_string());
+            }
+        }
+    }
+    None
+}
+
+fn closed(see_other: Option<String>) -> WSError {
+    match see_other {
+        Some(host) => WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, format!("XMPP stream closed, see other host: {}", host))),
+        None => WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "XMPP stream closed"))
+    }
+}
+
+pub struct XmppFraming {
+    ws: WebSocket,
+    stream_id: Option<String>,
+    defrag: Defragmenter
+}
+
+impl XmppFraming {
+    pub fn new(ws: WebSocket) -> XmppFraming {
+        XmppFraming { ws: ws, stream_id: None, defrag: Defragmenter::new() }
+    }
+
+    #[inline] pub fn get_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+
+    #[inline] pub fn into_inner(self) -> WebSocket {
+        self.ws
+    }
+
+    /// Stream id from server's `<open/>`
+    #[inline] pub fn stream_id(&self) -> Option<&str> {
+        self.stream_id.as_deref()
+    }
+
+    fn send_text(&mut self, text: &str) -> WSResult<()> {
+        self.ws.send_data(WS_FIN | WS_MASK | WS_OPTEXT, None, text.as_bytes())
+    }
+
+    fn recv_frame(&mut self) -> WSResult<XmppFrame> {
+        loop {
+            let msg = match self.defrag.push(self.ws.read_message()?)? {
+                Some(msg) => msg,
+                None => continue
+            };
+
+            if msg.is_ping() {
+                self.ws.send_message(&WSMessage::pong(&*msg.data).mask())?;
+            } else if msg.is_close() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")));
+            } else if msg.is_text() {
+                return Ok(XmppFrame::parse(msg.as_text()?));
+            } else if !msg.is_control() {
+                return Err(WSError::Io(io::Error::new(io::ErrorKind::InvalidInput, "binary frames are not allowed for XMPP")));
+            }
+        }
+    }
+
+    /// Open (or restart, e.g. after SASL) XMPP stream to domain, returns server's `<open/>` element
+    pub fn open(&mut self, domain: &str) -> WSResult<String> {
+        self.send_text(&*format!("<open xmlns=\"{}\" to=\"{}\" version=\"1.0\"/>", FRAMING_NS, domain))?;
+
+        loop {
+            match self.recv_frame()? {
+                XmppFrame::Open(element) => {
+                    self.stream_id = attribute(&*element, "id");
+                    return Ok(element);
+                },
+                XmppFrame::Close(see_other) => return Err(closed(see_other)),
+                XmppFrame::Stanza(_) => ()
+            }
+        }
+    }
+
+    /// Send one complete stanza (or stream level element, like `<auth/>`)
+    pub fn send_stanza(&mut self, stanza: &str) -> WSResult<()> {
+        self.send_text(stanza)
+    }
+
+    /// Receive next stanza, stream close is reported as connection aborted error,
+    /// with `see-other-uri` as detail if server asks to reconnect elsewhere
+    pub fn recv_stanza(&mut self) -> WSResult<String> {
+        loop {
+            match self.recv_frame()? {
+                XmppFrame::Stanza(stanza) => return Ok(stanza),
+                XmppFrame::Close(see_other) => {
+                    let _ = self.send_text(&*format!("<close xmlns=\"{}\"/>", FRAMING_NS));
+                    return Err(closed(see_other));
+                },
+                XmppFrame::Open(_) => ()
+            }
+        }
+    }
+
+    /// Close XMPP stream, waiting for server to close it too
+    pub fn close(&mut self) -> WSResult<()> {
+        self.send_text(&*format!("<close xmlns=\"{}\"/>", FRAMING_NS))?;
+
+        loop {
+            match self.recv_frame() {
+                Ok(XmppFrame::Close(_)) => return Ok(()),
+                Ok(_) => (),
+                Err(WSError::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionAborted => return Ok(()),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}